@@ -1,16 +1,101 @@
 use crate::{metadata, policy};
-use failure::Fallible;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Maximum sane rollout duration (30 days), longer ones are clamped.
+pub const MAX_ROLLOUT_DURATION_MINUTES: i64 = 30 * 24 * 60;
+
+/// Media type of the upstream Cincinnati graph protocol.
+pub const CINCINNATI_MEDIA_TYPE: &str = "application/vnd.redhat.cincinnati.v1+json";
+
+/// Error in assembling or validating a graph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GraphError {
+    /// The release index has no releases at all.
+    EmptyReleases,
+    /// Updates metadata is at an unsupported schema version.
+    SchemaMismatch { expected: String, found: String },
+    /// A release is missing a required metadata key.
+    MissingMetadata { version: String, key: String },
+    /// The graph has a cycle, as a list of `(from, to)` version edges.
+    CyclicGraph(Vec<(String, String)>),
+    /// An edge references a node index which does not exist.
+    InvalidEdge(u64, u64),
+    /// An edge does not go from an older to a newer release, as `(from, to)` versions.
+    MisorderedEdge(String, String),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::EmptyReleases => write!(f, "empty release index"),
+            GraphError::SchemaMismatch { expected, found } => write!(
+                f,
+                "unsupported updates metadata schema version '{}' (expected '{}')",
+                found, expected
+            ),
+            GraphError::MissingMetadata { version, key } => write!(
+                f,
+                "release '{}' is missing required metadata key '{}'",
+                version, key
+            ),
+            GraphError::CyclicGraph(edges) => {
+                let edges: Vec<String> = edges
+                    .iter()
+                    .map(|(from, to)| format!("{} -> {}", from, to))
+                    .collect();
+                write!(f, "cycle in update graph: {}", edges.join(", "))
+            }
+            GraphError::InvalidEdge(from, to) => {
+                write!(f, "edge ({}, {}) references an unknown node", from, to)
+            }
+            GraphError::MisorderedEdge(from, to) => {
+                write!(
+                    f,
+                    "edge not from older to newer release: {} -> {}",
+                    from, to
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
 
 /// Single release entry in the Cincinnati update-graph.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CincinnatiPayload {
     pub version: String,
+    /// Release metadata, serialized with sorted keys for stable output.
+    #[serde(serialize_with = "serialize_sorted")]
     pub metadata: HashMap<String, String>,
     pub payload: String,
 }
 
+/// Serialize a map with sorted keys.
+fn serialize_sorted<S: serde::Serializer>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+
+    let sorted: BTreeMap<&String, &String> = map.iter().collect();
+    sorted.serialize(serializer)
+}
+
+impl CincinnatiPayload {
+    /// Return the age index of this release, if valid.
+    pub fn age_index(&self) -> Option<u64> {
+        self.metadata.get(metadata::AGE_INDEX)?.parse().ok()
+    }
+
+    /// Set the age index of this release.
+    pub fn set_age_index(&mut self, age_index: u64) {
+        self.metadata
+            .insert(metadata::AGE_INDEX.to_string(), age_index.to_string());
+    }
+}
+
 /// Cincinnati update-graph, a DAG with releases (nodes) and update paths (edges).
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Graph {
@@ -18,13 +103,484 @@ pub struct Graph {
     pub edges: Vec<(u64, u64)>,
 }
 
+/// Borrowed view of a graph in the upstream Cincinnati wire format.
+///
+/// This is the shape expected by standard Cincinnati clients: metadata keys
+/// are sorted and an (empty) list of conditional edges is always present.
+#[derive(Debug, Serialize)]
+pub struct CincinnatiWireGraph<'a> {
+    nodes: Vec<CincinnatiWireNode<'a>>,
+    edges: &'a [(u64, u64)],
+    #[serde(rename = "conditionalEdges")]
+    conditional_edges: [(); 0],
+}
+
+#[derive(Debug, Serialize)]
+struct CincinnatiWireNode<'a> {
+    version: &'a str,
+    payload: &'a str,
+    metadata: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> From<&'a Graph> for CincinnatiWireGraph<'a> {
+    fn from(graph: &'a Graph) -> Self {
+        let nodes = graph
+            .nodes
+            .iter()
+            .map(|n| CincinnatiWireNode {
+                version: &n.version,
+                payload: &n.payload,
+                metadata: n
+                    .metadata
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect(),
+            })
+            .collect();
+        Self {
+            nodes,
+            edges: &graph.edges,
+            conditional_edges: [],
+        }
+    }
+}
+
+/// Borrowed view of a graph, with a preference for each edge.
+///
+/// Edges are unchanged, with their priorities in a parallel list: among edges
+/// from the same node, the one with the lowest priority (starting at 0) is
+/// the preferred next hop.
+#[derive(Debug, Serialize)]
+pub struct WeightedGraph<'a> {
+    nodes: &'a [CincinnatiPayload],
+    edges: &'a [(u64, u64)],
+    edge_priorities: Vec<u64>,
+}
+
+impl<'a> WeightedGraph<'a> {
+    /// Build a view of a graph, with edge priorities computed beforehand.
+    ///
+    /// This allows computing priorities before metadata keys are moved to a
+    /// custom namespace.
+    pub fn with_priorities(graph: &'a Graph, edge_priorities: Vec<u64>) -> Self {
+        assert_eq!(graph.edges.len(), edge_priorities.len());
+        Self {
+            nodes: &graph.nodes,
+            edges: &graph.edges,
+            edge_priorities,
+        }
+    }
+}
+
+impl<'a> From<&'a Graph> for WeightedGraph<'a> {
+    fn from(graph: &'a Graph) -> Self {
+        Self::with_priorities(graph, graph.edge_priorities())
+    }
+}
+
+/// Changes between two graphs, with edges expressed as `(from, to)` version pairs.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphDiff {
+    pub added_nodes: Vec<String>,
+    pub removed_nodes: Vec<String>,
+    pub added_edges: Vec<(String, String)>,
+    pub removed_edges: Vec<(String, String)>,
+}
+
+impl GraphDiff {
+    /// Whether there are no changes at all.
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+    }
+}
+
 impl Graph {
+    /// Compute changes from a previous graph to this one.
+    pub fn diff(&self, previous: &Graph) -> GraphDiff {
+        let current_nodes: HashSet<&str> = self.nodes.iter().map(|n| n.version.as_str()).collect();
+        let previous_nodes: HashSet<&str> =
+            previous.nodes.iter().map(|n| n.version.as_str()).collect();
+        let current_edges = self.version_edges();
+        let previous_edges = previous.version_edges();
+
+        GraphDiff {
+            added_nodes: self
+                .nodes
+                .iter()
+                .filter(|n| !previous_nodes.contains(n.version.as_str()))
+                .map(|n| n.version.clone())
+                .collect(),
+            removed_nodes: previous
+                .nodes
+                .iter()
+                .filter(|n| !current_nodes.contains(n.version.as_str()))
+                .map(|n| n.version.clone())
+                .collect(),
+            added_edges: current_edges
+                .iter()
+                .filter(|e| !previous_edges.contains(e))
+                .cloned()
+                .collect(),
+            removed_edges: previous_edges
+                .iter()
+                .filter(|e| !current_edges.contains(e))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Move metadata keys from the default namespace to a custom one.
+    ///
+    /// E.g. with namespace `com.example.os`, the `org.fedoraproject.coreos.scheme`
+    /// key becomes `com.example.os.scheme`. Other keys are kept as is.
+    pub fn rename_key_namespace(&mut self, namespace: &str) {
+        let default_prefix = format!("{}.", metadata::KEY_NAMESPACE);
+        for node in &mut self.nodes {
+            node.metadata = node
+                .metadata
+                .drain()
+                .map(|(key, value)| match key.strip_prefix(&default_prefix) {
+                    Some(name) => (format!("{}.{}", namespace, name), value),
+                    None => (key, value),
+                })
+                .collect();
+        }
+    }
+
+    /// Merge extra per-version metadata into matching nodes.
+    ///
+    /// Keys already set on a node (e.g. injected from upstream metadata) are
+    /// only replaced if `overwrite` is set. Unknown versions are ignored.
+    pub fn merge_node_metadata(
+        &mut self,
+        extra: &BTreeMap<String, BTreeMap<String, String>>,
+        overwrite: bool,
+    ) {
+        if extra.is_empty() {
+            return;
+        }
+        for node in &mut self.nodes {
+            let entries = match extra.get(&node.version) {
+                Some(e) => e,
+                None => continue,
+            };
+            for (key, value) in entries {
+                if overwrite || !node.metadata.contains_key(key) {
+                    node.metadata.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    /// Rank edges from each node, returning a priority for each edge (0 being
+    /// the preferred one).
+    ///
+    /// Targets which are not dead-ends are preferred, then newer ones.
+    pub fn edge_priorities(&self) -> Vec<u64> {
+        let is_deadend = |index: u64| {
+            self.nodes
+                .get(index as usize)
+                .and_then(|n| n.metadata.get(metadata::DEADEND))
+                .map(|v| v == "true")
+                .unwrap_or(false)
+        };
+
+        let mut by_source: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (index, (from, _to)) in self.edges.iter().enumerate() {
+            by_source.entry(*from).or_default().push(index);
+        }
+        let mut priorities = vec![0; self.edges.len()];
+        for mut edges in by_source.into_values() {
+            // Nodes are in age order.
+            edges.sort_by_key(|&e| {
+                let to = self.edges[e].1;
+                (is_deadend(to), std::cmp::Reverse(to))
+            });
+            for (priority, edge) in edges.into_iter().enumerate() {
+                priorities[edge] = priority as u64;
+            }
+        }
+        priorities
+    }
+
+    /// Ensure that every node carries all the given metadata keys.
+    pub fn check_required_metadata(&self, keys: &[String]) -> Result<(), GraphError> {
+        for node in &self.nodes {
+            if let Some(missing) = keys.iter().find(|k| !node.metadata.contains_key(*k)) {
+                return Err(GraphError::MissingMetadata {
+                    version: node.version.clone(),
+                    key: missing.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensure that all edges reference existing nodes.
+    pub fn check_edges(&self) -> Result<(), GraphError> {
+        let len = self.nodes.len() as u64;
+        match self
+            .edges
+            .iter()
+            .find(|&&(from, to)| from >= len || to >= len)
+        {
+            Some(&(from, to)) => Err(GraphError::InvalidEdge(from, to)),
+            None => Ok(()),
+        }
+    }
+
+    /// Ensure that all edges go from older to newer releases.
+    ///
+    /// Nodes are ordered by age, so this holds by construction; a violation
+    /// means a bug in edge computation.
+    pub fn check_edge_order(&self) -> Result<(), GraphError> {
+        self.check_edges()?;
+        match self.edges.iter().find(|(from, to)| from >= to) {
+            Some(&(from, to)) => Err(GraphError::MisorderedEdge(
+                self.nodes[from as usize].version.clone(),
+                self.nodes[to as usize].version.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Count edges by their source in edge computation, as `(rollouts, barriers)`.
+    ///
+    /// Edges targeting a rollout are attributed to rollouts, all others to barriers.
+    pub fn edge_sources(&self) -> (usize, usize) {
+        let rollouts = self
+            .edges
+            .iter()
+            .filter(|&&(_, to)| {
+                self.nodes
+                    .get(to as usize)
+                    .map(|n| n.metadata.contains_key(metadata::ROLLOUT))
+                    .unwrap_or(false)
+            })
+            .count();
+        (rollouts, self.edges.len() - rollouts)
+    }
+
+    /// Ensure that the graph is acyclic, as required for an update graph.
+    ///
+    /// On failure, the error lists the edges of a detected cycle.
+    pub fn check_acyclic(&self) -> Result<(), GraphError> {
+        let cycle = match self.find_cycle() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let edges = cycle
+            .iter()
+            .map(|&(from, to)| {
+                (
+                    self.nodes[from as usize].version.clone(),
+                    self.nodes[to as usize].version.clone(),
+                )
+            })
+            .collect();
+        Err(GraphError::CyclicGraph(edges))
+    }
+
+    /// Check all graph invariants, returning every violation found.
+    ///
+    /// This covers the same invariants as the individual checks (required
+    /// metadata keys, edges in range and from older to newer releases, no
+    /// cycles), without stopping at the first violation.
+    pub fn violations(&self, required_keys: &[String]) -> Vec<GraphError> {
+        let mut violations = vec![];
+        for node in &self.nodes {
+            for key in required_keys {
+                if !node.metadata.contains_key(key) {
+                    violations.push(GraphError::MissingMetadata {
+                        version: node.version.clone(),
+                        key: key.clone(),
+                    });
+                }
+            }
+        }
+
+        let len = self.nodes.len() as u64;
+        for &(from, to) in &self.edges {
+            if from >= len || to >= len {
+                violations.push(GraphError::InvalidEdge(from, to));
+            } else if from >= to {
+                violations.push(GraphError::MisorderedEdge(
+                    self.nodes[from as usize].version.clone(),
+                    self.nodes[to as usize].version.clone(),
+                ));
+            }
+        }
+
+        if let Err(e) = self.check_acyclic() {
+            violations.push(e);
+        }
+        violations
+    }
+
+    /// Find a cycle in the graph, returning its edges.
+    fn find_cycle(&self) -> Option<Vec<(u64, u64)>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            New,
+            Visiting,
+            Done,
+        }
+
+        let len = self.nodes.len();
+        let mut successors = vec![vec![]; len];
+        for &(from, to) in &self.edges {
+            if (from as usize) < len && (to as usize) < len {
+                successors[from as usize].push(to as usize);
+            }
+        }
+
+        // Iterative DFS, with the current path on an explicit stack.
+        let mut state = vec![State::New; len];
+        for root in 0..len {
+            if state[root] != State::New {
+                continue;
+            }
+            let mut stack = vec![(root, 0usize)];
+            state[root] = State::Visiting;
+            while let Some((node, next)) = stack.last_mut() {
+                let node = *node;
+                if let Some(&succ) = successors[node].get(*next) {
+                    *next += 1;
+                    match state[succ] {
+                        State::New => {
+                            state[succ] = State::Visiting;
+                            stack.push((succ, 0));
+                        }
+                        State::Visiting => {
+                            let start = stack.iter().position(|&(n, _)| n == succ)?;
+                            let mut path: Vec<u64> =
+                                stack[start..].iter().map(|&(n, _)| n as u64).collect();
+                            path.push(succ as u64);
+                            return Some(path.windows(2).map(|w| (w[0], w[1])).collect());
+                        }
+                        State::Done => {}
+                    }
+                } else {
+                    state[node] = State::Done;
+                    stack.pop();
+                }
+            }
+        }
+        None
+    }
+
+    /// Remove nodes at the given indices, dropping their edges and remapping the others.
+    pub fn remove_nodes(&mut self, removed: &HashSet<usize>) {
+        if removed.is_empty() {
+            return;
+        }
+
+        // Old index -> new index, for retained nodes only.
+        let mut remap = HashMap::with_capacity(self.nodes.len());
+        let nodes = std::mem::take(&mut self.nodes);
+        for (index, node) in nodes.into_iter().enumerate() {
+            if removed.contains(&index) {
+                continue;
+            }
+            remap.insert(index as u64, self.nodes.len() as u64);
+            self.nodes.push(node);
+        }
+
+        self.edges = self
+            .edges
+            .iter()
+            .filter_map(|(from, to)| Some((*remap.get(from)?, *remap.get(to)?)))
+            .collect();
+    }
+
+    /// Translate edges from node indices to version pairs, ignoring out-of-range ones.
+    fn version_edges(&self) -> Vec<(String, String)> {
+        self.edges
+            .iter()
+            .filter_map(|&(from, to)| {
+                let from = self.nodes.get(from as usize)?;
+                let to = self.nodes.get(to as usize)?;
+                Some((from.version.clone(), to.version.clone()))
+            })
+            .collect()
+    }
+
     /// Assemble a graph from release-index and updates metadata.
     pub fn from_metadata(
         releases: Vec<metadata::Release>,
         updates: metadata::UpdatesJSON,
         scope: GraphScope,
-    ) -> Fallible<Self> {
+    ) -> Result<Self, GraphError> {
+        Self::from_metadata_with_overlay(releases, updates, vec![], scope)
+    }
+
+    /// Assemble a graph from release-index and updates metadata, plus overlay releases.
+    ///
+    /// Overlay releases are appended as the newest ones (skipping already known
+    /// versions) and treated as fully rolled out, so that they are reachable
+    /// in the resulting graph.
+    pub fn from_metadata_with_overlay(
+        releases: Vec<metadata::Release>,
+        updates: metadata::UpdatesJSON,
+        overlay: Vec<metadata::Release>,
+        scope: GraphScope,
+    ) -> Result<Self, GraphError> {
+        let blocked = HashSet::new();
+        let graph = Self::from_metadata_with_deadends(releases, updates, overlay, &blocked, scope)?;
+        Ok(policy::filter_deadends(graph))
+    }
+
+    /// Assemble a graph like `from_metadata_with_overlay`, but keeping
+    /// edges out of dead-end releases.
+    ///
+    /// Releases with `blocked` versions are left out before computing edges,
+    /// so that barriers and rollouts are re-derived without them. They still
+    /// count for the age index of newer releases.
+    ///
+    /// Such a graph must not be served to clients, it is only meant for auditing.
+    pub fn from_metadata_with_deadends(
+        releases: Vec<metadata::Release>,
+        updates: metadata::UpdatesJSON,
+        overlay: Vec<metadata::Release>,
+        blocked: &HashSet<String>,
+        scope: GraphScope,
+    ) -> Result<Self, GraphError> {
+        if releases.is_empty() {
+            return Err(GraphError::EmptyReleases);
+        }
+        if updates.schema_version() != metadata::UPDATES_SCHEMA_VERSION {
+            return Err(GraphError::SchemaMismatch {
+                expected: metadata::UPDATES_SCHEMA_VERSION.to_string(),
+                found: updates.schema_version().to_string(),
+            });
+        }
+
+        let mut releases = releases;
+        let mut updates = updates;
+        let known: HashSet<String> = releases.iter().map(|r| r.version.clone()).collect();
+        for release in overlay {
+            if known.contains(&release.version) {
+                continue;
+            }
+            updates.releases.push(metadata::ReleaseUpdate {
+                version: release.version.clone(),
+                metadata: metadata::UpdateMetadata {
+                    barrier: None,
+                    deadend: None,
+                    rollout: Some(metadata::UpdateRollout {
+                        start_epoch: None,
+                        start_percentage: Some(1.0),
+                        duration_minutes: None,
+                        malformed_fields: vec![],
+                    }),
+                },
+            });
+            releases.push(release);
+        }
+
         let nodes: Vec<CincinnatiPayload> = releases
             .into_iter()
             .enumerate()
@@ -32,10 +588,12 @@ impl Graph {
                 let mut current = CincinnatiPayload {
                     version: entry.version,
                     payload: "".to_string(),
-                    metadata: maplit::hashmap! {
-                        metadata::AGE_INDEX.to_string() => age_index.to_string(),
-                    },
+                    metadata: HashMap::new(),
                 };
+                current.set_age_index(age_index as u64);
+                if blocked.contains(&current.version) {
+                    return None;
+                }
                 let mut has_basearch = false;
                 if scope.oci {
                     if let Some(oci_images) = entry.oci_images {
@@ -45,6 +603,15 @@ impl Graph {
                             {
                                 continue;
                             }
+                            if !is_valid_digest_ref(&oci_image.digest_ref) {
+                                log::warn!(
+                                    "skipping malformed OCI digest reference for {}/{}: '{}'",
+                                    current.version,
+                                    oci_image.architecture,
+                                    oci_image.digest_ref
+                                );
+                                continue;
+                            }
                             has_basearch = true;
                             current.payload = oci_image.digest_ref;
                             current
@@ -88,16 +655,11 @@ impl Graph {
 
         // Compute the update graph.
         let edges = Self::compute_edges(&nodes)?;
-        let graph = Graph { nodes, edges };
-
-        // Filter deadends.
-        let final_graph = policy::filter_deadends(graph);
-
-        Ok(final_graph)
+        Ok(Graph { nodes, edges })
     }
 
     /// Compute edges based on graph metadata.
-    fn compute_edges(nodes: &[CincinnatiPayload]) -> Fallible<Vec<(u64, u64)>> {
+    fn compute_edges(nodes: &[CincinnatiPayload]) -> Result<Vec<(u64, u64)>, GraphError> {
         use std::collections::BTreeSet;
         use std::ops::Bound;
 
@@ -205,24 +767,95 @@ impl Graph {
                 release
                     .metadata
                     .insert(metadata::ROLLOUT.to_string(), true.to_string());
+                // Partial parameters could make the rollout progress unexpectedly,
+                // so hold it at its start instead.
+                if !rollout.malformed_fields.is_empty() {
+                    log::warn!(
+                        "release '{}': malformed rollout fields ({}), pausing rollout",
+                        release.version,
+                        rollout.malformed_fields.join(", ")
+                    );
+                    continue;
+                }
                 if let Some(val) = rollout.start_epoch {
                     release
                         .metadata
                         .insert(metadata::START_EPOCH.to_string(), val.to_string());
                 }
                 if let Some(val) = rollout.start_percentage {
-                    release
-                        .metadata
-                        .insert(metadata::START_VALUE.to_string(), val.to_string());
+                    if let Some(val) = Self::sanitize_start_value(&release.version, val) {
+                        release
+                            .metadata
+                            .insert(metadata::START_VALUE.to_string(), val.to_string());
+                    }
                 }
-                if let Some(minutes) = &rollout.duration_minutes {
-                    release
-                        .metadata
-                        .insert(metadata::DURATION.to_string(), minutes.to_string());
+                if let Some(minutes) = rollout.duration_minutes {
+                    if let Some(minutes) = Self::sanitize_duration(&release.version, minutes) {
+                        release
+                            .metadata
+                            .insert(metadata::DURATION.to_string(), minutes.to_string());
+                    }
                 }
             }
         }
     }
+
+    /// Clamp a rollout start value into `[0.0, 1.0]`, skipping non-numbers.
+    fn sanitize_start_value(version: &str, val: f64) -> Option<f64> {
+        if val.is_nan() {
+            log::warn!(
+                "release '{}': skipping invalid rollout start value",
+                version
+            );
+            return None;
+        }
+        let clamped = val.clamp(0.0, 1.0);
+        if clamped != val {
+            log::warn!(
+                "release '{}': clamping rollout start value {} to {}",
+                version,
+                val,
+                clamped
+            );
+        }
+        Some(clamped)
+    }
+
+    /// Clamp a rollout duration to `MAX_ROLLOUT_DURATION_MINUTES`, skipping negative ones.
+    fn sanitize_duration(version: &str, minutes: i64) -> Option<i64> {
+        if minutes < 0 {
+            log::warn!(
+                "release '{}': skipping negative rollout duration {}",
+                version,
+                minutes
+            );
+            return None;
+        }
+        if minutes > MAX_ROLLOUT_DURATION_MINUTES {
+            log::warn!(
+                "release '{}': clamping rollout duration {} to {} minutes",
+                version,
+                minutes,
+                MAX_ROLLOUT_DURATION_MINUTES
+            );
+            return Some(MAX_ROLLOUT_DURATION_MINUTES);
+        }
+        Some(minutes)
+    }
+}
+
+/// Check whether an OCI reference is in the `repository@sha256:<hex>` form.
+fn is_valid_digest_ref(digest_ref: &str) -> bool {
+    let (repo, digest) = match digest_ref.rsplit_once("@sha256:") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    !repo.is_empty()
+        && !repo.contains(char::is_whitespace)
+        && digest.len() == 64
+        && digest
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
 }
 
 /// The scope of a cached graph, i.e. the specific stream and basearch that it is valid for.
@@ -232,3 +865,660 @@ pub struct GraphScope {
     pub stream: String,
     pub oci: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(version: &str) -> CincinnatiPayload {
+        CincinnatiPayload {
+            version: version.to_string(),
+            metadata: HashMap::new(),
+            payload: format!("payload-{}", version),
+        }
+    }
+
+    /// Build nodes with barrier and/or rollout markers, by index.
+    fn marked_nodes(
+        count: usize,
+        barriers: &[usize],
+        rollouts: &[usize],
+    ) -> Vec<CincinnatiPayload> {
+        (0..count)
+            .map(|index| {
+                let mut n = node(&index.to_string());
+                if barriers.contains(&index) {
+                    n.metadata
+                        .insert(metadata::BARRIER.to_string(), "true".to_string());
+                }
+                if rollouts.contains(&index) {
+                    n.metadata
+                        .insert(metadata::ROLLOUT.to_string(), "true".to_string());
+                }
+                n
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_in_progress_barrier_edges() {
+        // Node 2 is an in-progress barrier: it gets rollout edges only, but the
+        // barrier cursor still advances to it, so no edges skip over it.
+        let nodes = marked_nodes(5, &[2, 4], &[2]);
+        let edges = Graph::compute_edges(&nodes).unwrap();
+        assert_eq!(edges, vec![(0, 2), (1, 2), (2, 4), (3, 4)]);
+
+        // An in-progress barrier as the newest node, after a regular barrier.
+        let nodes = marked_nodes(4, &[1, 3], &[3]);
+        let edges = Graph::compute_edges(&nodes).unwrap();
+        assert_eq!(edges, vec![(1, 3), (2, 3), (0, 1)]);
+
+        // Later rollouts only reach back to the in-progress barrier.
+        let nodes = marked_nodes(3, &[1], &[1, 2]);
+        let edges = Graph::compute_edges(&nodes).unwrap();
+        assert_eq!(edges, vec![(1, 2), (0, 1)]);
+    }
+
+    #[test]
+    fn test_rename_key_namespace() {
+        let mut n = node("1");
+        n.metadata
+            .insert(metadata::SCHEME.to_string(), "checksum".to_string());
+        n.metadata.insert("custom".to_string(), "x".to_string());
+        let mut graph = Graph {
+            nodes: vec![n],
+            edges: vec![],
+        };
+
+        graph.rename_key_namespace("com.example.os");
+        let metadata = &graph.nodes[0].metadata;
+        assert_eq!(metadata["com.example.os.scheme"], "checksum");
+        assert_eq!(metadata["custom"], "x");
+        assert_eq!(metadata.len(), 2);
+    }
+
+    #[test]
+    fn test_graph_diff() {
+        let previous = Graph {
+            nodes: vec![node("1"), node("2"), node("3")],
+            edges: vec![(0, 2), (1, 2)],
+        };
+        let current = Graph {
+            nodes: vec![node("2"), node("3"), node("4")],
+            edges: vec![(0, 1), (1, 2)],
+        };
+
+        let diff = current.diff(&previous);
+        assert_eq!(diff.added_nodes, vec!["4".to_string()]);
+        assert_eq!(diff.removed_nodes, vec!["1".to_string()]);
+        assert_eq!(diff.added_edges, vec![("3".to_string(), "4".to_string())]);
+        assert_eq!(diff.removed_edges, vec![("1".to_string(), "3".to_string())]);
+
+        assert!(current.diff(&current).is_empty());
+    }
+
+    #[test]
+    fn test_sorted_metadata() {
+        let keys = ["d", "b", "e", "a", "c", "f", "h", "g"];
+        let mut first = node("1");
+        for key in &keys {
+            first.metadata.insert(key.to_string(), "x".to_string());
+        }
+        // Same content, different insertion order (and hashing state).
+        let mut second = node("1");
+        for key in keys.iter().rev() {
+            second.metadata.insert(key.to_string(), "x".to_string());
+        }
+
+        let first = serde_json::to_string_pretty(&first).unwrap();
+        let second = serde_json::to_string_pretty(&second).unwrap();
+        assert_eq!(first, second);
+        let positions: Vec<usize> = ["\"a\"", "\"b\"", "\"h\""]
+            .iter()
+            .map(|k| first.find(k).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_cincinnati_wire_graph() {
+        let mut first = node("1");
+        first.metadata.insert("b".to_string(), "2".to_string());
+        first.metadata.insert("a".to_string(), "1".to_string());
+        let graph = Graph {
+            nodes: vec![first, node("2")],
+            edges: vec![(0, 1)],
+        };
+
+        let wire = serde_json::to_string(&CincinnatiWireGraph::from(&graph)).unwrap();
+        assert_eq!(
+            wire,
+            r#"{"nodes":[{"version":"1","payload":"payload-1","metadata":{"a":"1","b":"2"}},{"version":"2","payload":"payload-2","metadata":{}}],"edges":[[0,1]],"conditionalEdges":[]}"#
+        );
+    }
+
+    #[test]
+    fn test_edge_priorities() {
+        let mut deadend = node("3");
+        deadend
+            .metadata
+            .insert(metadata::DEADEND.to_string(), "true".to_string());
+        let graph = Graph {
+            nodes: vec![node("1"), node("2"), deadend, node("4")],
+            edges: vec![(0, 1), (0, 2), (0, 3), (1, 2), (2, 3)],
+        };
+        assert_eq!(graph.edge_priorities(), vec![1, 2, 0, 0, 0]);
+
+        let weighted = serde_json::to_value(WeightedGraph::from(&graph)).unwrap();
+        assert_eq!(
+            weighted["edges"],
+            serde_json::json!([[0, 1], [0, 2], [0, 3], [1, 2], [2, 3]])
+        );
+        assert_eq!(
+            weighted["edge_priorities"],
+            serde_json::json!([1, 2, 0, 0, 0])
+        );
+        assert_eq!(weighted["nodes"][2]["version"], "3");
+    }
+
+    #[test]
+    fn test_merge_node_metadata() {
+        let mut injected = node("1");
+        injected
+            .metadata
+            .insert(metadata::DEADEND.to_string(), "false".to_string());
+        let graph = Graph {
+            nodes: vec![injected, node("2")],
+            edges: vec![(0, 1)],
+        };
+        let extra = maplit::btreemap! {
+            "1".to_string() => maplit::btreemap! {
+                "com.example.advisory".to_string() => "https://example.com/1".to_string(),
+                metadata::DEADEND.to_string() => "true".to_string(),
+            },
+            "3".to_string() => maplit::btreemap! {
+                "com.example.advisory".to_string() => "https://example.com/3".to_string(),
+            },
+        };
+
+        let mut merged = graph.clone();
+        merged.merge_node_metadata(&extra, false);
+        assert_eq!(
+            merged.nodes[0].metadata["com.example.advisory"],
+            "https://example.com/1"
+        );
+        assert_eq!(merged.nodes[0].metadata[metadata::DEADEND], "false");
+        assert_eq!(merged.nodes[1].metadata, graph.nodes[1].metadata);
+        assert_eq!(merged.edges, graph.edges);
+
+        let mut merged = graph;
+        merged.merge_node_metadata(&extra, true);
+        assert_eq!(merged.nodes[0].metadata[metadata::DEADEND], "true");
+    }
+
+    fn release(version: &str) -> metadata::Release {
+        metadata::Release {
+            commits: vec![metadata::ReleaseCommit {
+                architecture: "x86_64".to_string(),
+                checksum: format!("checksum-{}", version),
+            }],
+            oci_images: None,
+            version: version.to_string(),
+            metadata: "".to_string(),
+        }
+    }
+
+    fn scope() -> GraphScope {
+        GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        }
+    }
+
+    #[test]
+    fn test_from_metadata_with_overlay() {
+        let updates = metadata::UpdatesJSON {
+            stream: "stable".to_string(),
+            schema_version: None,
+            releases: vec![],
+        };
+        let releases = vec![release("1"), release("2")];
+        let overlay = vec![release("2"), release("3")];
+
+        let graph = Graph::from_metadata_with_overlay(releases, updates, overlay, scope()).unwrap();
+        let versions: Vec<&str> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["1", "2", "3"]);
+        assert_eq!(graph.edges, vec![(0, 2), (1, 2)]);
+    }
+
+    #[test]
+    fn test_from_metadata_with_deadends() {
+        let mut updates = rollout_update("2", Some(1.0), None);
+        updates.releases.push(metadata::ReleaseUpdate {
+            version: "1".to_string(),
+            metadata: metadata::UpdateMetadata {
+                barrier: None,
+                deadend: Some(metadata::UpdateDeadend {
+                    reason: "https://example.com/deadend".to_string(),
+                }),
+                rollout: None,
+            },
+        });
+        let releases = vec![release("1"), release("2")];
+
+        let blocked = HashSet::new();
+        let full = Graph::from_metadata_with_deadends(
+            releases.clone(),
+            updates.clone(),
+            vec![],
+            &blocked,
+            scope(),
+        )
+        .unwrap();
+        assert_eq!(full.nodes[0].metadata[metadata::DEADEND], "true");
+        assert_eq!(full.edges, vec![(0, 1)]);
+
+        let graph = Graph::from_metadata(releases, updates, scope()).unwrap();
+        assert_eq!(graph.nodes.len(), full.nodes.len());
+        assert!(graph.edges.is_empty());
+    }
+
+    #[test]
+    fn test_from_metadata_blocked() {
+        let mut updates = rollout_update("3", Some(1.0), None);
+        updates.releases.push(metadata::ReleaseUpdate {
+            version: "2".to_string(),
+            metadata: metadata::UpdateMetadata {
+                barrier: Some(metadata::UpdateBarrier {
+                    reason: "https://example.com/barrier".to_string(),
+                }),
+                deadend: None,
+                rollout: None,
+            },
+        });
+        let releases = vec![release("1"), release("2"), release("3")];
+        let graph = |blocked: &[&str]| {
+            let blocked = blocked.iter().map(|v| v.to_string()).collect();
+            let graph = Graph::from_metadata_with_deadends(
+                releases.clone(),
+                updates.clone(),
+                vec![],
+                &blocked,
+                scope(),
+            );
+            graph.unwrap()
+        };
+
+        let full = graph(&[]);
+        assert_eq!(full.edges, vec![(1, 2), (0, 1)]);
+
+        // Blocking the barrier does not strand older releases.
+        let unblocked = graph(&["2"]);
+        let versions: Vec<_> = unblocked.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["1", "3"]);
+        assert_eq!(unblocked.edges, vec![(0, 1)]);
+        assert_eq!(unblocked.nodes[1].age_index(), Some(2));
+
+        // Neither does blocking the only rollout target.
+        let rollback = graph(&["3"]);
+        assert_eq!(rollback.nodes.len(), 2);
+        assert_eq!(rollback.edges, vec![(0, 1)]);
+    }
+
+    fn rollout_update(
+        version: &str,
+        start_percentage: Option<f64>,
+        duration_minutes: Option<i64>,
+    ) -> metadata::UpdatesJSON {
+        metadata::UpdatesJSON {
+            stream: "stable".to_string(),
+            schema_version: None,
+            releases: vec![metadata::ReleaseUpdate {
+                version: version.to_string(),
+                metadata: metadata::UpdateMetadata {
+                    barrier: None,
+                    deadend: None,
+                    rollout: Some(metadata::UpdateRollout {
+                        start_epoch: Some(1_600_000_000),
+                        start_percentage,
+                        duration_minutes,
+                        malformed_fields: vec![],
+                    }),
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn test_rollout_negative_duration() {
+        let updates = rollout_update("1", Some(0.5), Some(-10));
+        let mut release = node("1");
+        Graph::inject_throttling_params(&updates, &mut release);
+        assert_eq!(release.metadata[metadata::ROLLOUT], "true");
+        assert_eq!(release.metadata[metadata::START_VALUE], "0.5");
+        assert!(!release.metadata.contains_key(metadata::DURATION));
+
+        let updates = rollout_update("1", None, Some(MAX_ROLLOUT_DURATION_MINUTES + 1));
+        let mut release = node("1");
+        Graph::inject_throttling_params(&updates, &mut release);
+        assert_eq!(
+            release.metadata[metadata::DURATION],
+            MAX_ROLLOUT_DURATION_MINUTES.to_string()
+        );
+    }
+
+    #[test]
+    fn test_rollout_start_value_bounds() {
+        let updates = rollout_update("1", Some(150.0), Some(60));
+        let mut release = node("1");
+        Graph::inject_throttling_params(&updates, &mut release);
+        assert_eq!(release.metadata[metadata::START_VALUE], "1");
+        assert_eq!(release.metadata[metadata::DURATION], "60");
+
+        let updates = rollout_update("1", Some(-0.5), None);
+        let mut release = node("1");
+        Graph::inject_throttling_params(&updates, &mut release);
+        assert_eq!(release.metadata[metadata::START_VALUE], "0");
+
+        let updates = rollout_update("1", Some(f64::NAN), None);
+        let mut release = node("1");
+        Graph::inject_throttling_params(&updates, &mut release);
+        assert!(!release.metadata.contains_key(metadata::START_VALUE));
+    }
+
+    #[test]
+    fn test_rollout_malformed() {
+        let updates: metadata::UpdatesJSON = serde_json::from_str(
+            r#"{
+                "stream": "stable",
+                "releases": [{
+                    "version": "1",
+                    "metadata": {
+                        "rollout": {
+                            "start_epoch": "soon",
+                            "start_percentage": 0.5,
+                            "duration_minutes": 60
+                        }
+                    }
+                }]
+            }"#,
+        )
+        .unwrap();
+        let rollout = updates.releases[0].metadata.rollout.as_ref().unwrap();
+        assert_eq!(rollout.malformed_fields, vec!["start_epoch"]);
+        assert_eq!(rollout.start_percentage, Some(0.5));
+
+        // Held at the start, without any partial parameters.
+        let mut release = node("1");
+        Graph::inject_throttling_params(&updates, &mut release);
+        assert_eq!(release.metadata[metadata::ROLLOUT], "true");
+        assert!(!release.metadata.contains_key(metadata::START_EPOCH));
+        assert!(!release.metadata.contains_key(metadata::START_VALUE));
+        assert!(!release.metadata.contains_key(metadata::DURATION));
+
+        let updates = rollout_update("1", Some(0.5), Some(60));
+        let rollout = updates.releases[0].metadata.rollout.as_ref().unwrap();
+        assert!(rollout.malformed_fields.is_empty());
+    }
+
+    #[test]
+    fn test_age_index() {
+        let updates = metadata::UpdatesJSON {
+            stream: "stable".to_string(),
+            schema_version: None,
+            releases: vec![],
+        };
+        let mut no_arch = release("2");
+        no_arch.commits[0].architecture = "aarch64".to_string();
+        let releases = vec![release("1"), no_arch, release("3"), release("4")];
+
+        let graph = Graph::from_metadata(releases, updates, scope()).unwrap();
+        assert_eq!(graph.nodes.len(), 3);
+        let indices: Vec<u64> = graph
+            .nodes
+            .iter()
+            .map(|n| n.age_index().expect("invalid age index"))
+            .collect();
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(indices, vec![0, 2, 3]);
+
+        let mut node = node("5");
+        assert_eq!(node.age_index(), None);
+        node.metadata
+            .insert(metadata::AGE_INDEX.to_string(), "-1".to_string());
+        assert_eq!(node.age_index(), None);
+        node.set_age_index(7);
+        assert_eq!(node.age_index(), Some(7));
+    }
+
+    #[test]
+    fn test_check_required_metadata() {
+        let mut graph = Graph {
+            nodes: vec![node("1"), node("2")],
+            edges: vec![(0, 1)],
+        };
+        let keys = vec![metadata::SCHEME.to_string()];
+        assert!(graph.check_required_metadata(&[]).is_ok());
+        assert!(graph.check_required_metadata(&keys).is_err());
+
+        for node in graph.nodes.iter_mut() {
+            node.metadata
+                .insert(metadata::SCHEME.to_string(), "checksum".to_string());
+        }
+        assert!(graph.check_required_metadata(&keys).is_ok());
+    }
+
+    #[test]
+    fn test_graph_errors() {
+        let updates = metadata::UpdatesJSON {
+            stream: "stable".to_string(),
+            schema_version: None,
+            releases: vec![],
+        };
+        let err = Graph::from_metadata(vec![], updates.clone(), scope()).unwrap_err();
+        assert_eq!(err, GraphError::EmptyReleases);
+
+        let mut future = updates;
+        future.schema_version = Some("2".to_string());
+        let err = Graph::from_metadata(vec![release("1")], future, scope()).unwrap_err();
+        assert!(matches!(err, GraphError::SchemaMismatch { .. }));
+
+        let graph = Graph {
+            nodes: vec![node("1")],
+            edges: vec![(0, 1)],
+        };
+        assert_eq!(graph.check_edges(), Err(GraphError::InvalidEdge(0, 1)));
+
+        // Conversion for `?` in `Fallible` contexts.
+        let fallible = || -> failure::Fallible<()> { Ok(graph.check_edges()?) };
+        assert!(fallible().is_err());
+        assert_eq!(graph.check_edge_order(), Err(GraphError::InvalidEdge(0, 1)));
+    }
+
+    #[test]
+    fn test_check_edge_order() {
+        let mut graph = Graph {
+            nodes: vec![node("1"), node("2"), node("3")],
+            edges: vec![(0, 1), (0, 2), (1, 2)],
+        };
+        assert!(graph.check_edge_order().is_ok());
+
+        graph.edges.push((2, 1));
+        let err = graph.check_edge_order().unwrap_err();
+        assert_eq!(
+            err,
+            GraphError::MisorderedEdge("3".to_string(), "2".to_string())
+        );
+
+        graph.edges = vec![(1, 1)];
+        assert!(graph.check_edge_order().is_err());
+    }
+
+    #[test]
+    fn test_edge_sources() {
+        let updates = metadata::UpdatesJSON {
+            stream: "stable".to_string(),
+            schema_version: None,
+            releases: vec![
+                metadata::ReleaseUpdate {
+                    version: "2".to_string(),
+                    metadata: metadata::UpdateMetadata {
+                        barrier: Some(metadata::UpdateBarrier {
+                            reason: "".to_string(),
+                        }),
+                        deadend: None,
+                        rollout: None,
+                    },
+                },
+                metadata::ReleaseUpdate {
+                    version: "4".to_string(),
+                    metadata: metadata::UpdateMetadata {
+                        barrier: None,
+                        deadend: None,
+                        rollout: Some(metadata::UpdateRollout {
+                            start_epoch: None,
+                            start_percentage: Some(1.0),
+                            duration_minutes: None,
+                            malformed_fields: vec![],
+                        }),
+                    },
+                },
+            ],
+        };
+        let releases = vec![release("1"), release("2"), release("3"), release("4")];
+
+        let graph = Graph::from_metadata(releases, updates, scope()).unwrap();
+        // Rollout: 2->4, 3->4. Barrier: 1->2.
+        assert_eq!(graph.edge_sources(), (2, 1));
+        assert_eq!(Graph::default().edge_sources(), (0, 0));
+    }
+
+    #[test]
+    fn test_check_acyclic() {
+        let mut graph = Graph {
+            nodes: vec![node("1"), node("2"), node("3"), node("4")],
+            edges: vec![(0, 1), (0, 2), (1, 2), (2, 3)],
+        };
+        assert!(graph.check_acyclic().is_ok());
+        assert!(Graph::default().check_acyclic().is_ok());
+
+        graph.edges.push((3, 1));
+        let err = graph.check_acyclic().unwrap_err();
+        let cycle = vec![("2", "3"), ("3", "4"), ("4", "2")];
+        let cycle = cycle
+            .into_iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect();
+        assert_eq!(err, GraphError::CyclicGraph(cycle));
+        assert_eq!(
+            err.to_string(),
+            "cycle in update graph: 2 -> 3, 3 -> 4, 4 -> 2"
+        );
+
+        graph.edges = vec![(2, 2)];
+        assert!(graph.check_acyclic().is_err());
+    }
+
+    #[test]
+    fn test_violations() {
+        let required = vec![metadata::SCHEME.to_string()];
+        let mut graph = Graph {
+            nodes: vec![node("1"), node("2"), node("3")],
+            edges: vec![(0, 1), (1, 2)],
+        };
+        for n in &mut graph.nodes {
+            n.metadata
+                .insert(metadata::SCHEME.to_string(), "checksum".to_string());
+        }
+        assert!(graph.violations(&required).is_empty());
+        assert!(Graph::default().violations(&required).is_empty());
+
+        graph.nodes[1].metadata.clear();
+        graph.edges.extend(vec![(2, 0), (0, 5)]);
+        let violations = graph.violations(&required);
+        let cycle = ["1", "2", "3", "1"]
+            .windows(2)
+            .map(|w| (w[0].to_string(), w[1].to_string()))
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                GraphError::MissingMetadata {
+                    version: "2".to_string(),
+                    key: metadata::SCHEME.to_string(),
+                },
+                GraphError::MisorderedEdge("3".to_string(), "1".to_string()),
+                GraphError::InvalidEdge(0, 5),
+                GraphError::CyclicGraph(cycle),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_digest_ref() {
+        let digest = "a".repeat(64);
+        let valid = vec![
+            format!("quay.io/fedora/fedora-coreos@sha256:{}", digest),
+            format!("localhost:5000/repo@sha256:{}", digest),
+        ];
+        for input in valid {
+            assert!(is_valid_digest_ref(&input), "{}", input);
+        }
+
+        let invalid = vec![
+            "quay.io/fedora/fedora-coreos:stable".to_string(),
+            format!("@sha256:{}", digest),
+            format!("quay.io/fedora/fedora-coreos@sha256:{}", &digest[1..]),
+            format!("quay.io/fedora/fedora-coreos@sha256:{}", "A".repeat(64)),
+            format!("quay.io/fedora/fedora-coreos@sha512:{}", digest),
+            format!("quay.io/fedora coreos@sha256:{}", digest),
+        ];
+        for input in invalid {
+            assert!(!is_valid_digest_ref(&input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_oci_digest_ref_validation() {
+        let updates = metadata::UpdatesJSON {
+            stream: "stable".to_string(),
+            schema_version: None,
+            releases: vec![],
+        };
+        let oci_release = |version: &str, digest_ref: String| {
+            let mut r = release(version);
+            r.oci_images = Some(vec![metadata::ReleaseOciImage {
+                architecture: "x86_64".to_string(),
+                image: "quay.io/fedora/fedora-coreos".to_string(),
+                digest_ref,
+            }]);
+            r
+        };
+        let valid = format!("quay.io/fedora/fedora-coreos@sha256:{}", "0".repeat(64));
+        let releases = vec![
+            oci_release("1", valid.clone()),
+            oci_release("2", "quay.io/fedora/fedora-coreos:latest".to_string()),
+        ];
+        let mut oci_scope = scope();
+        oci_scope.oci = true;
+
+        let graph = Graph::from_metadata(releases, updates, oci_scope).unwrap();
+        assert_eq!(graph.nodes.len(), 1);
+        assert_eq!(graph.nodes[0].version, "1");
+        assert_eq!(graph.nodes[0].payload, valid);
+    }
+
+    #[test]
+    fn test_remove_nodes() {
+        let mut graph = Graph {
+            nodes: vec![node("1"), node("2"), node("3"), node("4")],
+            edges: vec![(0, 1), (0, 2), (1, 3), (2, 3)],
+        };
+
+        graph.remove_nodes(&maplit::hashset! {1});
+        let versions: Vec<&str> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["1", "3", "4"]);
+        assert_eq!(graph.edges, vec![(0, 1), (1, 2)]);
+    }
+}
@@ -2,4 +2,6 @@ pub mod graph;
 pub mod metadata;
 pub mod metrics;
 pub mod policy;
+pub mod schema;
+pub mod version;
 pub mod web;
@@ -2,12 +2,22 @@
 
 use serde_derive::Deserialize;
 
-/// Templated URL for release index.
-pub static RELEASES_JSON: &str =
-    "https://builds.coreos.fedoraproject.org/prod/streams/${stream}/releases.json";
+/// Default base URL for upstream metadata.
+pub static UPSTREAM_BASE_URL: &str = "https://builds.coreos.fedoraproject.org/";
 
-/// Templated URL for updates metadata.
-pub static UPDATES_JSON: &str = "https://builds.coreos.fedoraproject.org/updates/${stream}.json";
+/// Templated path for release index, relative to an upstream base URL.
+pub static RELEASES_JSON_PATH: &str = "prod/streams/${stream}/releases.json";
+
+/// Templated path for updates metadata, relative to an upstream base URL.
+pub static UPDATES_JSON_PATH: &str = "updates/${stream}.json";
+
+/// Updates metadata schema version understood by this implementation.
+///
+/// Documents without an explicit schema version are assumed to be at this version.
+pub static UPDATES_SCHEMA_VERSION: &str = "1";
+
+/// Namespace of the metadata keys below.
+pub static KEY_NAMESPACE: &str = "org.fedoraproject.coreos";
 
 pub static SCHEME: &str = "org.fedoraproject.coreos.scheme";
 
@@ -27,6 +37,8 @@ pub static START_VALUE: &str = "org.fedoraproject.coreos.updates.start_value";
 #[derive(Clone, Debug, Deserialize)]
 pub struct ReleasesJSON {
     pub releases: Vec<Release>,
+    /// Link to the next page of releases (possibly relative), if paginated.
+    pub next: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -35,6 +47,7 @@ pub struct Release {
     #[serde(rename = "oci-images")]
     pub oci_images: Option<Vec<ReleaseOciImage>>,
     pub version: String,
+    #[serde(default)]
     pub metadata: String,
 }
 
@@ -56,9 +69,20 @@ pub struct ReleaseOciImage {
 #[derive(Clone, Debug, Deserialize)]
 pub struct UpdatesJSON {
     pub stream: String,
+    #[serde(rename = "schema-version")]
+    pub schema_version: Option<String>,
     pub releases: Vec<ReleaseUpdate>,
 }
 
+impl UpdatesJSON {
+    /// Return the schema version of this document.
+    pub fn schema_version(&self) -> &str {
+        self.schema_version
+            .as_deref()
+            .unwrap_or(UPDATES_SCHEMA_VERSION)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct ReleaseUpdate {
     pub version: String,
@@ -83,8 +107,65 @@ pub struct UpdateDeadend {
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(from = "RawUpdateRollout")]
 pub struct UpdateRollout {
     pub start_epoch: Option<i64>,
     pub start_percentage: Option<f64>,
-    pub duration_minutes: Option<u64>,
+    /// Signed, so that bogus negative values can be detected and skipped.
+    pub duration_minutes: Option<i64>,
+    /// Fields with a value of an unexpected type, which are left unset.
+    pub malformed_fields: Vec<&'static str>,
+}
+
+/// Rollout parameters as found upstream, possibly with unexpected types.
+#[derive(Deserialize)]
+struct RawUpdateRollout {
+    start_epoch: Option<Lenient<i64>>,
+    start_percentage: Option<Lenient<f64>>,
+    duration_minutes: Option<Lenient<i64>>,
+}
+
+/// An upstream value, which may not have the expected type.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Lenient<T> {
+    Valid(T),
+    Malformed(serde::de::IgnoredAny),
+}
+
+impl<T> Lenient<T> {
+    /// Return a valid value, if any, recording the field as malformed otherwise.
+    fn check(
+        value: Option<Self>,
+        field: &'static str,
+        malformed: &mut Vec<&'static str>,
+    ) -> Option<T> {
+        match value? {
+            Lenient::Valid(v) => Some(v),
+            Lenient::Malformed(_) => {
+                malformed.push(field);
+                None
+            }
+        }
+    }
+}
+
+impl From<RawUpdateRollout> for UpdateRollout {
+    fn from(raw: RawUpdateRollout) -> Self {
+        let mut malformed_fields = vec![];
+        Self {
+            start_epoch: Lenient::check(raw.start_epoch, "start_epoch", &mut malformed_fields),
+            start_percentage: Lenient::check(
+                raw.start_percentage,
+                "start_percentage",
+                &mut malformed_fields,
+            ),
+            duration_minutes: Lenient::check(
+                raw.duration_minutes,
+                "duration_minutes",
+                &mut malformed_fields,
+            ),
+            malformed_fields,
+        }
+    }
 }
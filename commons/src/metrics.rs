@@ -4,15 +4,17 @@ use actix_web::HttpResponse;
 
 /// Serve metrics requests (Prometheus textual format).
 pub async fn serve_metrics() -> Result<HttpResponse, failure::Error> {
-    use prometheus::Encoder;
+    let content = encode_metrics()?;
+    Ok(HttpResponse::Ok().body(content))
+}
 
-    let content = {
-        let metrics = prometheus::default_registry().gather();
-        let txt_enc = prometheus::TextEncoder::new();
-        let mut buf = vec![];
-        txt_enc.encode(&metrics, &mut buf)?;
-        buf
-    };
+/// Encode all registered metrics (Prometheus textual format).
+pub fn encode_metrics() -> Result<Vec<u8>, failure::Error> {
+    use prometheus::Encoder;
 
-    Ok(HttpResponse::Ok().body(content))
+    let metrics = prometheus::default_registry().gather();
+    let txt_enc = prometheus::TextEncoder::new();
+    let mut buf = vec![];
+    txt_enc.encode(&metrics, &mut buf)?;
+    Ok(buf)
 }
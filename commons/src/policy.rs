@@ -1,14 +1,47 @@
-use crate::graph::Graph;
+use crate::graph::{CincinnatiPayload, Graph};
 use crate::metadata;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+/// Overrides of upstream dead-end markers, by version.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeadendOverrides {
+    /// Versions to keep serving updates from, even if marked as dead-ends.
+    pub ignore: HashSet<String>,
+    /// Versions to treat as dead-ends, even if not marked as such.
+    pub force: HashSet<String>,
+}
 
 /// Prune outgoing edges from "deadend" nodes.
 pub fn filter_deadends(input: Graph) -> Graph {
+    filter_deadends_with_overrides(input, &DeadendOverrides::default()).0
+}
+
+/// Prune outgoing edges from "deadend" nodes, after applying overrides.
+///
+/// Dead-end markers of overridden nodes are updated to match, so that clients
+/// see consistent metadata. This returns the pruned graph, along with the
+/// versions whose dead-end status was actually overridden.
+pub fn filter_deadends_with_overrides(
+    input: Graph,
+    overrides: &DeadendOverrides,
+) -> (Graph, Vec<String>) {
     let mut graph = input;
     let mut deadends = HashSet::new();
+    let mut overridden = vec![];
 
-    for (index, release) in graph.nodes.iter().enumerate() {
-        if release.metadata.get(metadata::DEADEND) == Some(&"true".into()) {
+    for (index, release) in graph.nodes.iter_mut().enumerate() {
+        let marked = release.metadata.get(metadata::DEADEND) == Some(&"true".into());
+        if marked && overrides.ignore.contains(&release.version) {
+            release.metadata.remove(metadata::DEADEND);
+            release.metadata.remove(metadata::DEADEND_REASON);
+            overridden.push(release.version.clone());
+        } else if !marked && overrides.force.contains(&release.version) {
+            release
+                .metadata
+                .insert(metadata::DEADEND.to_string(), "true".to_string());
+            overridden.push(release.version.clone());
+            deadends.insert(index);
+        } else if marked {
             deadends.insert(index);
         }
     }
@@ -19,6 +52,93 @@ pub fn filter_deadends(input: Graph) -> Graph {
     });
     graph.edges.shrink_to_fit();
 
+    (graph, overridden)
+}
+
+/// Placeholder for redacted barrier and dead-end reasons.
+///
+/// This is the same reason reported for markers without one upstream.
+pub static REDACTED_REASON: &str = "generic";
+
+/// Rewrites of human-readable barrier and dead-end reasons.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReasonRedactions {
+    /// Whether to redact all reasons.
+    pub redact_all: bool,
+    /// Replacements for specific reasons, matched verbatim.
+    pub replacements: HashMap<String, String>,
+}
+
+impl ReasonRedactions {
+    /// Whether no reason is rewritten.
+    pub fn is_empty(&self) -> bool {
+        !self.redact_all && self.replacements.is_empty()
+    }
+}
+
+/// Rewrite barrier and dead-end reasons, keeping the markers themselves.
+pub fn redact_reasons(input: Graph, redactions: &ReasonRedactions) -> Graph {
+    let mut graph = input;
+    if redactions.is_empty() {
+        return graph;
+    }
+
+    for release in graph.nodes.iter_mut() {
+        for key in [metadata::BARRIER_REASON, metadata::DEADEND_REASON] {
+            if let Some(reason) = release.metadata.get_mut(key) {
+                if redactions.redact_all {
+                    *reason = REDACTED_REASON.to_string();
+                } else if let Some(replacement) = redactions.replacements.get(reason) {
+                    *reason = replacement.clone();
+                }
+            }
+        }
+    }
+
+    graph
+}
+
+/// Remove nodes more than `max_span` age-index steps older than the newest node.
+///
+/// Nodes without an age index are kept. This returns the pruned graph, along
+/// with the removed versions.
+pub fn prune_age_span(input: Graph, max_span: u64) -> (Graph, Vec<String>) {
+    let mut graph = input;
+    let newest = match graph.nodes.iter().filter_map(|n| n.age_index()).max() {
+        Some(newest) => newest,
+        None => return (graph, vec![]),
+    };
+    let oldest = newest.saturating_sub(max_span);
+
+    let mut removed = HashSet::new();
+    let mut versions = vec![];
+    for (index, release) in graph.nodes.iter().enumerate() {
+        if matches!(release.age_index(), Some(age) if age < oldest) {
+            removed.insert(index);
+            versions.push(release.version.clone());
+        }
+    }
+
+    graph.remove_nodes(&removed);
+    graph.edges.shrink_to_fit();
+
+    (graph, versions)
+}
+
+/// Remove nodes which are in an active (i.e. not yet completed) rollout at `now`.
+pub fn filter_active_rollouts(input: Graph, now: i64) -> Graph {
+    let mut graph = input;
+    let active: HashSet<usize> = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, release)| matches!(rollout_progress(release, now), Some(p) if p < 1.0))
+        .map(|(index, _)| index)
+        .collect();
+
+    graph.remove_nodes(&active);
+    graph.edges.shrink_to_fit();
+
     graph
 }
 
@@ -30,50 +150,11 @@ pub fn throttle_rollouts(input: Graph, client_wariness: f64) -> Graph {
 
     for (index, release) in graph.nodes.iter().enumerate() {
         // Skip if this release is not being rolled out.
-        if !release.metadata.contains_key(metadata::ROLLOUT) {
-            continue;
-        };
-
-        // Start epoch defaults to 0.
-        let start_epoch = match release.metadata.get(metadata::START_EPOCH) {
-            Some(epoch) => epoch.parse::<i64>().unwrap_or(0),
-            None => 0i64,
-        };
-
-        // Start value defaults to 0.0.
-        let start_value = match release.metadata.get(metadata::START_VALUE) {
-            Some(val) => val.parse::<f64>().unwrap_or(0f64),
-            None => 0f64,
+        let throttling = match rollout_progress(release, now) {
+            Some(t) => t,
+            None => continue,
         };
 
-        // Duration has no default (i.e. no progress).
-        let mut minutes: Option<u64> = None;
-        if let Some(mins) = release.metadata.get(metadata::DURATION) {
-            if let Ok(m) = mins.parse::<u64>() {
-                minutes = Some(m.max(1));
-            }
-        }
-
-        let throttling: f64;
-        if let Some(mins) = minutes {
-            let end = start_epoch + (mins.saturating_mul(60)) as i64;
-            let rate = (1.0 - start_value) / (end.saturating_sub(start_epoch)) as f64;
-            if now < start_epoch {
-                throttling = 0.0;
-            } else if now > end {
-                throttling = 1.0;
-            } else {
-                throttling = start_value + rate * (now - start_epoch) as f64;
-            }
-        } else {
-            // Without duration, rollout does not progress past initial value.
-            if now < start_epoch {
-                throttling = 0.0;
-            } else {
-                throttling = start_value
-            }
-        }
-
         if client_wariness > throttling {
             hidden.insert(index);
         }
@@ -87,3 +168,213 @@ pub fn throttle_rollouts(input: Graph, client_wariness: f64) -> Graph {
 
     graph
 }
+
+/// Compute the rollout progress of a release at `now`, in `[0.0, 1.0]`.
+///
+/// This returns `None` if the release is not being rolled out.
+fn rollout_progress(release: &CincinnatiPayload, now: i64) -> Option<f64> {
+    if !release.metadata.contains_key(metadata::ROLLOUT) {
+        return None;
+    };
+
+    // Start epoch defaults to 0.
+    let start_epoch = match release.metadata.get(metadata::START_EPOCH) {
+        Some(epoch) => epoch.parse::<i64>().unwrap_or(0),
+        None => 0i64,
+    };
+
+    // Start value defaults to 0.0.
+    let start_value = match release.metadata.get(metadata::START_VALUE) {
+        Some(val) => val.parse::<f64>().unwrap_or(0f64),
+        None => 0f64,
+    };
+
+    // Duration has no default (i.e. no progress).
+    let mut minutes: Option<u64> = None;
+    if let Some(mins) = release.metadata.get(metadata::DURATION) {
+        if let Ok(m) = mins.parse::<u64>() {
+            minutes = Some(m.max(1));
+        }
+    }
+
+    let throttling: f64;
+    if let Some(mins) = minutes {
+        let end = start_epoch + (mins.saturating_mul(60)) as i64;
+        let rate = (1.0 - start_value) / (end.saturating_sub(start_epoch)) as f64;
+        if now < start_epoch {
+            throttling = 0.0;
+        } else if now > end {
+            throttling = 1.0;
+        } else {
+            throttling = start_value + rate * (now - start_epoch) as f64;
+        }
+    } else {
+        // Without duration, rollout does not progress past initial value.
+        if now < start_epoch {
+            throttling = 0.0;
+        } else {
+            throttling = start_value
+        }
+    }
+
+    Some(throttling)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn node(version: &str, rollout: Option<(i64, f64, u64)>) -> CincinnatiPayload {
+        let mut metadata = HashMap::new();
+        if let Some((start_epoch, start_value, minutes)) = rollout {
+            metadata.insert(metadata::ROLLOUT.to_string(), "true".to_string());
+            metadata.insert(metadata::START_EPOCH.to_string(), start_epoch.to_string());
+            metadata.insert(metadata::START_VALUE.to_string(), start_value.to_string());
+            metadata.insert(metadata::DURATION.to_string(), minutes.to_string());
+        }
+        CincinnatiPayload {
+            version: version.to_string(),
+            metadata,
+            payload: format!("payload-{}", version),
+        }
+    }
+
+    #[test]
+    fn test_filter_active_rollouts() {
+        let now = 1_600_000_000;
+        let graph = Graph {
+            nodes: vec![
+                node("1", None),
+                // Completed rollout.
+                node("2", Some((now - 7200, 0.0, 60))),
+                // In-progress rollout.
+                node("3", Some((now - 1800, 0.0, 60))),
+                // Not yet started rollout.
+                node("4", Some((now + 60, 0.0, 60))),
+            ],
+            edges: vec![(0, 1), (0, 2), (1, 2), (0, 3), (1, 3), (2, 3)],
+        };
+
+        let graph = filter_active_rollouts(graph, now);
+        let versions: Vec<&str> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["1", "2"]);
+        assert_eq!(graph.edges, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_filter_deadends_with_overrides() {
+        let deadend = |version| {
+            let mut n = node(version, None);
+            n.metadata
+                .insert(metadata::DEADEND.to_string(), "true".to_string());
+            n.metadata
+                .insert(metadata::DEADEND_REASON.to_string(), "bug".to_string());
+            n
+        };
+        let graph = Graph {
+            nodes: vec![deadend("1"), deadend("2"), node("3", None), node("4", None)],
+            edges: vec![(0, 3), (1, 3), (2, 3)],
+        };
+        let overrides = DeadendOverrides {
+            ignore: maplit::hashset! {"1".to_string(), "3".to_string()},
+            force: maplit::hashset! {"2".to_string()},
+        };
+
+        let (graph, overridden) = filter_deadends_with_overrides(graph, &overrides);
+        // "2" and "3" are already dead-end and non-dead-end, respectively.
+        assert_eq!(overridden, vec!["1"]);
+        assert_eq!(graph.edges, vec![(0, 3), (2, 3)]);
+        assert!(graph.nodes[0].metadata.is_empty());
+
+        let overrides = DeadendOverrides {
+            ignore: HashSet::new(),
+            force: maplit::hashset! {"3".to_string()},
+        };
+        let (graph, overridden) = filter_deadends_with_overrides(graph, &overrides);
+        assert_eq!(overridden, vec!["3"]);
+        assert_eq!(graph.edges, vec![(0, 3)]);
+        assert_eq!(graph.nodes[2].metadata[metadata::DEADEND], "true");
+    }
+
+    #[test]
+    fn test_redact_reasons() {
+        let mut barrier = node("1", None);
+        barrier
+            .metadata
+            .insert(metadata::BARRIER.to_string(), "true".to_string());
+        barrier
+            .metadata
+            .insert(metadata::BARRIER_REASON.to_string(), "incident".to_string());
+        let mut deadend = node("2", None);
+        deadend
+            .metadata
+            .insert(metadata::DEADEND.to_string(), "true".to_string());
+        deadend
+            .metadata
+            .insert(metadata::DEADEND_REASON.to_string(), "bug".to_string());
+        let graph = Graph {
+            nodes: vec![barrier, deadend, node("3", None)],
+            edges: vec![(0, 1)],
+        };
+
+        let unchanged = redact_reasons(graph.clone(), &ReasonRedactions::default());
+        for (node, original) in unchanged.nodes.iter().zip(&graph.nodes) {
+            assert_eq!(node.metadata, original.metadata);
+        }
+
+        let mut redactions = ReasonRedactions::default();
+        redactions
+            .replacements
+            .insert("incident".to_string(), "maintenance".to_string());
+        let replaced = redact_reasons(graph.clone(), &redactions);
+        assert_eq!(
+            replaced.nodes[0].metadata[metadata::BARRIER_REASON],
+            "maintenance"
+        );
+        assert_eq!(replaced.nodes[1].metadata[metadata::DEADEND_REASON], "bug");
+
+        redactions.redact_all = true;
+        let redacted = redact_reasons(graph.clone(), &redactions);
+        assert_eq!(
+            redacted.nodes[0].metadata[metadata::BARRIER_REASON],
+            REDACTED_REASON
+        );
+        assert_eq!(
+            redacted.nodes[1].metadata[metadata::DEADEND_REASON],
+            REDACTED_REASON
+        );
+        // Markers are kept, and nothing else is touched.
+        assert_eq!(redacted.nodes[0].metadata[metadata::BARRIER], "true");
+        assert_eq!(redacted.nodes[1].metadata[metadata::DEADEND], "true");
+        assert_eq!(redacted.nodes[2].metadata, graph.nodes[2].metadata);
+        assert_eq!(redacted.edges, graph.edges);
+    }
+
+    #[test]
+    fn test_prune_age_span() {
+        let mut nodes: Vec<CincinnatiPayload> = (0..5)
+            .map(|age| {
+                let mut n = node(&format!("v{}", age), None);
+                n.set_age_index(age);
+                n
+            })
+            .collect();
+        // Unknown age, always kept.
+        nodes.push(node("unknown", None));
+        let graph = Graph {
+            nodes,
+            edges: vec![(0, 4), (1, 2), (2, 3), (3, 4), (5, 4)],
+        };
+
+        let (graph, removed) = prune_age_span(graph, 2);
+        assert_eq!(removed, vec!["v0", "v1"]);
+        let versions: Vec<&str> = graph.nodes.iter().map(|n| n.version.as_str()).collect();
+        assert_eq!(versions, vec!["v2", "v3", "v4", "unknown"]);
+        assert_eq!(graph.edges, vec![(0, 1), (1, 2), (3, 2)]);
+
+        let (graph, removed) = prune_age_span(graph, 10);
+        assert!(removed.is_empty());
+        assert_eq!(graph.nodes.len(), 4);
+    }
+}
@@ -0,0 +1,195 @@
+//! JSON Schema for served graphs.
+//!
+//! This is maintained by hand alongside `Graph` and `CincinnatiPayload`, with
+//! tests checking it against their actual serialization.
+
+use crate::metadata;
+use serde_json::{json, Map, Value};
+
+/// Media type of JSON Schema documents.
+pub const SCHEMA_MEDIA_TYPE: &str = "application/schema+json";
+
+/// Known node metadata keys (in the default namespace), with their schema.
+fn known_metadata_keys() -> Vec<(&'static str, Value)> {
+    let flag = |description: &str| {
+        json!({
+            "type": "string",
+            "enum": ["true", "false"],
+            "description": description,
+        })
+    };
+    let integer = |pattern: &str, description: &str| {
+        json!({
+            "type": "string",
+            "pattern": pattern,
+            "description": description,
+        })
+    };
+    vec![
+        (
+            metadata::SCHEME,
+            json!({
+                "type": "string",
+                "enum": ["checksum", "oci"],
+                "description": "Kind of payload: an OSTree commit checksum, or an OCI image reference.",
+            }),
+        ),
+        (
+            metadata::AGE_INDEX,
+            integer(
+                "^[0-9]+$",
+                "Position of the release in the stream history, oldest first.",
+            ),
+        ),
+        (
+            metadata::BARRIER,
+            flag("Whether all updates go through this release."),
+        ),
+        (
+            metadata::BARRIER_REASON,
+            json!({"type": "string", "description": "Why this release is a barrier."}),
+        ),
+        (
+            metadata::DEADEND,
+            flag("Whether there are no updates out of this release."),
+        ),
+        (
+            metadata::DEADEND_REASON,
+            json!({"type": "string", "description": "Why this release is a dead-end."}),
+        ),
+        (
+            metadata::ROLLOUT,
+            flag("Whether updates to this release are being rolled out."),
+        ),
+        (
+            metadata::START_EPOCH,
+            integer("^-?[0-9]+$", "Start of the rollout, as a Unix timestamp."),
+        ),
+        (
+            metadata::START_VALUE,
+            json!({
+                "type": "string",
+                "description": "Initial fraction (between 0 and 1) of clients offered the rollout.",
+            }),
+        ),
+        (
+            metadata::DURATION,
+            integer("^[0-9]+$", "Duration of the rollout, in minutes."),
+        ),
+    ]
+}
+
+/// Build the JSON Schema of served graphs.
+///
+/// Known metadata keys are moved to `namespace`, if not the default one.
+/// Unknown metadata keys are allowed, as long as their values are strings.
+pub fn graph_schema(namespace: Option<&str>) -> Value {
+    let default_prefix = format!("{}.", metadata::KEY_NAMESPACE);
+    let mut metadata_keys = Map::new();
+    for (key, schema) in known_metadata_keys() {
+        let key = match (namespace, key.strip_prefix(&default_prefix)) {
+            (Some(ns), Some(name)) => format!("{}.{}", ns, name),
+            _ => key.to_string(),
+        };
+        metadata_keys.insert(key, schema);
+    }
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "Fedora CoreOS update graph",
+        "type": "object",
+        "required": ["nodes", "edges"],
+        "properties": {
+            "nodes": {
+                "type": "array",
+                "description": "Releases, oldest first.",
+                "items": {"$ref": "#/definitions/node"},
+            },
+            "edges": {
+                "type": "array",
+                "description": "Update paths, as pairs of indices into `nodes`.",
+                "items": {
+                    "type": "array",
+                    "items": {"type": "integer", "minimum": 0},
+                    "minItems": 2,
+                    "maxItems": 2,
+                },
+            },
+        },
+        "definitions": {
+            "node": {
+                "type": "object",
+                "required": ["version", "metadata", "payload"],
+                "properties": {
+                    "version": {"type": "string"},
+                    "metadata": {
+                        "type": "object",
+                        "properties": metadata_keys,
+                        "additionalProperties": {"type": "string"},
+                    },
+                    "payload": {
+                        "type": "string",
+                        "description": "OSTree commit checksum or OCI image reference, depending on the scheme.",
+                    },
+                },
+                "additionalProperties": false,
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{CincinnatiPayload, Graph};
+    use std::collections::HashMap;
+
+    /// Keys of a JSON object, sorted.
+    fn keys(value: &Value) -> Vec<String> {
+        let mut keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// Entries of a JSON array of strings, sorted.
+    fn strings(value: &Value) -> Vec<String> {
+        let mut strings: Vec<_> = value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s.as_str().unwrap().to_string())
+            .collect();
+        strings.sort();
+        strings
+    }
+
+    #[test]
+    fn test_graph_schema() {
+        let mut metadata = HashMap::new();
+        metadata.insert(metadata::SCHEME.to_string(), "checksum".to_string());
+        let graph = Graph {
+            nodes: vec![CincinnatiPayload {
+                version: "1".to_string(),
+                metadata,
+                payload: "checksum-1".to_string(),
+            }],
+            edges: vec![],
+        };
+        let serialized = serde_json::to_value(&graph).unwrap();
+
+        let schema = graph_schema(None);
+        assert_eq!(strings(&schema["required"]), keys(&serialized));
+        assert_eq!(keys(&schema["properties"]), keys(&serialized));
+        let node = &schema["definitions"]["node"];
+        assert_eq!(strings(&node["required"]), keys(&serialized["nodes"][0]));
+        assert_eq!(keys(&node["properties"]), keys(&serialized["nodes"][0]));
+        assert!(node["properties"]["metadata"]["properties"]
+            .get(metadata::SCHEME)
+            .is_some());
+
+        let schema = graph_schema(Some("com.example.os"));
+        let keys = keys(&schema["definitions"]["node"]["properties"]["metadata"]["properties"]);
+        assert!(keys.contains(&"com.example.os.scheme".to_string()));
+        assert!(keys.iter().all(|k| k.starts_with("com.example.os.")));
+    }
+}
@@ -0,0 +1,88 @@
+//! Client (agent) versions.
+
+use failure::{bail, Fallible};
+use std::fmt;
+use std::str::FromStr;
+
+/// Numeric dotted version, as reported by clients (e.g. `0.0.24`).
+///
+/// Any pre-release or build suffix (after `-` or `+`) is ignored, and
+/// missing trailing components compare as zero.
+#[derive(Clone, Debug)]
+pub struct ClientVersion {
+    components: Vec<u64>,
+}
+
+impl FromStr for ClientVersion {
+    type Err = failure::Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        let core = input.trim().split(['-', '+']).next();
+        let core = core.unwrap_or_default();
+        if core.is_empty() {
+            bail!("empty version");
+        }
+        let mut components = Vec::new();
+        for c in core.split('.') {
+            match c.parse() {
+                Ok(n) => components.push(n),
+                Err(_) => bail!("invalid version '{}'", input),
+            }
+        }
+        Ok(Self { components })
+    }
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let components: Vec<String> = self.components.iter().map(u64::to_string).collect();
+        write!(f, "{}", components.join("."))
+    }
+}
+
+impl Ord for ClientVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let len = self.components.len().max(other.components.len());
+        let component = |v: &Self, i: usize| v.components.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| component(self, i).cmp(&component(other, i)))
+            .find(|o| o.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
+impl PartialOrd for ClientVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ClientVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for ClientVersion {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(input: &str) -> ClientVersion {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_version() {
+        assert!(v("0.0.24") > v("0.0.9"));
+        assert!(v("1.0") > v("0.99.99"));
+        assert_eq!(v("0.1"), v("0.1.0"));
+        assert_eq!(v("0.0.24-dev+abcd"), v("0.0.24"));
+        assert_eq!(v(" 1.2.3 ").to_string(), "1.2.3");
+
+        for invalid in &["", "-rc1", "1..2", "1.x", "v1.0"] {
+            assert!(invalid.parse::<ClientVersion>().is_err(), "{}", invalid);
+        }
+    }
+}
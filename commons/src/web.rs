@@ -1,7 +1,11 @@
 use crate::graph::GraphScope;
 use actix_cors::CorsFactory;
-use failure::{bail, ensure, err_msg};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use failure::{bail, ensure, err_msg, format_err};
+use serde_derive::Serialize;
 use std::collections::HashSet;
+use std::net::IpAddr;
 
 /// Build a CORS middleware.
 ///
@@ -22,6 +26,243 @@ pub fn build_cors_middleware(origin_allowlist: &Option<Vec<String>>) -> CorsFact
     builder.finish()
 }
 
+/// Machine-readable body for error responses.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    /// Human-readable description.
+    pub error: String,
+    /// Stable error identifier (e.g. `invalid_scope`).
+    pub kind: String,
+}
+
+/// Build an error response with a JSON body.
+pub fn json_error(status: StatusCode, kind: &str, error: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        error: error.to_string(),
+        kind: kind.to_string(),
+    })
+}
+
+/// An IP network, in CIDR notation (e.g. `10.0.0.0/8` or `fd00::/8`).
+///
+/// A bare address is accepted as a single-host network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Whether an address belongs to this network.
+    ///
+    /// IPv4-mapped IPv6 addresses are matched as IPv4 ones.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap_ipv4(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for IpNetwork {
+    type Err = failure::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match input.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (input, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format_err!("invalid network address '{}'", input))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .map_err(|_| format_err!("invalid prefix length in '{}'", input))?,
+            None => max_len,
+        };
+        ensure!(
+            prefix_len <= max_len,
+            "prefix length too long in '{}'",
+            input
+        );
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Convert an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to IPv4.
+fn unmap_ipv4(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        if let [0, 0, 0, 0, 0, 0xffff, hi, lo] = v6.segments() {
+            let [a, b] = hi.to_be_bytes();
+            let [c, d] = lo.to_be_bytes();
+            return IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d));
+        }
+    }
+    ip
+}
+
+/// Whether an address belongs to any of the trusted proxy networks.
+fn is_trusted_proxy(ip: IpAddr, trusted_proxies: &[IpNetwork]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(ip))
+}
+
+/// Whether the direct peer of a request is a trusted proxy.
+///
+/// Peers without an address are connected through a unix-domain socket, i.e.
+/// they are local proxies and always trusted.
+fn is_trusted_peer(req: &HttpRequest, trusted_proxies: &[IpNetwork]) -> bool {
+    req.peer_addr()
+        .map(|addr| is_trusted_proxy(addr.ip(), trusted_proxies))
+        .unwrap_or(true)
+}
+
+/// Determine the IP address of the client which sent a request.
+///
+/// The `X-Forwarded-For` header is only honored if the direct peer is a
+/// trusted proxy. In that case, the rightmost address which is not a trusted
+/// proxy is the client one. This returns `None` if no address is known, e.g.
+/// for local proxies on a unix-domain socket not forwarding any.
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[IpNetwork]) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if !is_trusted_peer(req, trusted_proxies) {
+        return peer;
+    }
+
+    let mut client = peer;
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.iter().rev() {
+        let ip = match hop.trim().parse() {
+            Ok(ip) => ip,
+            Err(_) => break,
+        };
+        client = Some(ip);
+        if !is_trusted_proxy(ip, trusted_proxies) {
+            break;
+        }
+    }
+    client
+}
+
+/// Determine the scheme (`http` or `https`) used by the client which sent a request.
+///
+/// The `X-Forwarded-Proto` header is only honored if the direct peer is a
+/// trusted proxy.
+pub fn client_scheme(req: &HttpRequest, trusted_proxies: &[IpNetwork]) -> &'static str {
+    let local = if req.app_config().secure() {
+        "https"
+    } else {
+        "http"
+    };
+    if !is_trusted_peer(req, trusted_proxies) {
+        return local;
+    }
+    let forwarded = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    match forwarded.as_deref() {
+        Some("https") => "https",
+        Some("http") => "http",
+        _ => local,
+    }
+}
+
+/// Check whether a request explicitly accepts the given media type.
+pub fn accepts(req: &HttpRequest, media_type: &str) -> bool {
+    req.headers()
+        .get_all("accept")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| v.split(';').next())
+        .any(|v| v.trim().eq_ignore_ascii_case(media_type))
+}
+
+/// Check whether a request accepts the given content coding (e.g. `gzip`).
+///
+/// Codings are accepted unless listed with a zero quality value.
+pub fn accepts_encoding(req: &HttpRequest, coding: &str) -> bool {
+    req.headers()
+        .get_all("accept-encoding")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| {
+            let mut params = v.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let rejected = params.any(|p| {
+                let p = p.trim();
+                p.starts_with("q=") && p[2..].parse::<f32>().map(|q| q == 0.0).unwrap_or(false)
+            });
+            name.eq_ignore_ascii_case(coding) && !rejected
+        })
+}
+
+/// Check whether an `If-None-Match` header value matches an entity tag.
+///
+/// Weak comparison is used, as per RFC 7232.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = strip_weak(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+}
+
+/// Check whether a resource last modified at `last_modified` is unchanged
+/// since the date in an `If-Modified-Since` header value.
+///
+/// Invalid dates never match, and dates have a one-second resolution.
+pub fn not_modified_since(
+    if_modified_since: &str,
+    last_modified: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    match chrono::DateTime::parse_from_rfc2822(if_modified_since.trim()) {
+        Ok(since) => last_modified.timestamp() <= since.timestamp(),
+        Err(_) => false,
+    }
+}
+
+/// Combine the `oci` and `scheme` (`oci` or `checksum`) query parameters.
+///
+/// Either one may be used, but both must agree if set.
+pub fn resolve_oci(
+    oci: Option<bool>,
+    scheme: Option<&str>,
+) -> Result<Option<bool>, failure::Error> {
+    let from_scheme = match scheme {
+        None => return Ok(oci),
+        Some("oci") => true,
+        Some("checksum") => false,
+        Some(other) => bail!("unknown scheme '{}'", other),
+    };
+    if let Some(oci) = oci {
+        ensure!(
+            oci == from_scheme,
+            "conflicting scheme '{}' and oci='{}'",
+            scheme.unwrap_or_default(),
+            oci
+        );
+    }
+    Ok(Some(from_scheme))
+}
+
 /// Validate input query parameters into a valid graph scope.
 pub fn validate_scope(
     basearch: Option<String>,
@@ -61,6 +302,121 @@ pub fn validate_scope(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::test::TestRequest;
+
+    fn networks(input: &[&str]) -> Vec<IpNetwork> {
+        input.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let nets = networks(&["10.0.0.0/8", "192.168.1.1", "fd00::/8", "0.0.0.0/0"]);
+        assert!(nets[0].contains("10.1.2.3".parse().unwrap()));
+        assert!(!nets[0].contains("11.0.0.1".parse().unwrap()));
+        assert!(nets[0].contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(nets[1].contains("192.168.1.1".parse().unwrap()));
+        assert!(!nets[1].contains("192.168.1.2".parse().unwrap()));
+        assert!(nets[2].contains("fd12::1".parse().unwrap()));
+        assert!(!nets[2].contains("10.0.0.1".parse().unwrap()));
+        assert!(nets[3].contains("8.8.8.8".parse().unwrap()));
+
+        for invalid in &["10.0.0.0/33", "fd00::/129", "example.com/8", "10.0.0.0/x"] {
+            assert!(invalid.parse::<IpNetwork>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let peer = |addr: &str| addr.parse().unwrap();
+
+        // Spoofed headers from untrusted peers are ignored.
+        let req = TestRequest::default()
+            .peer_addr(peer("203.0.113.7:1234"))
+            .header("x-forwarded-for", "198.51.100.1")
+            .header("x-forwarded-proto", "https")
+            .to_http_request();
+        assert_eq!(
+            client_ip(&req, &trusted),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        assert_eq!(client_scheme(&req, &trusted), "http");
+        assert_eq!(client_ip(&req, &[]), Some("203.0.113.7".parse().unwrap()));
+
+        // Trusted proxies are skipped, from the right.
+        let req = TestRequest::default()
+            .peer_addr(peer("10.0.0.1:1234"))
+            .header("x-forwarded-for", "198.51.100.9, 198.51.100.1, 10.0.0.2")
+            .header("x-forwarded-proto", "https")
+            .to_http_request();
+        assert_eq!(
+            client_ip(&req, &trusted),
+            Some("198.51.100.1".parse().unwrap())
+        );
+        assert_eq!(client_scheme(&req, &trusted), "https");
+
+        // Without forwarding headers, the trusted peer is the client.
+        let req = TestRequest::default()
+            .peer_addr(peer("10.0.0.1:1234"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &trusted), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(client_scheme(&req, &trusted), "http");
+
+        // Local proxies on a unix-domain socket have no address, and are trusted.
+        let req = TestRequest::default()
+            .header("x-forwarded-for", "198.51.100.1, 10.0.0.2")
+            .header("x-forwarded-proto", "https")
+            .to_http_request();
+        assert_eq!(
+            client_ip(&req, &trusted),
+            Some("198.51.100.1".parse().unwrap())
+        );
+        assert_eq!(client_scheme(&req, &trusted), "https");
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(client_ip(&req, &trusted), None);
+    }
+
+    #[test]
+    fn test_accepts_encoding() {
+        let accepts = |header: &str| {
+            let req = TestRequest::default()
+                .header("accept-encoding", header)
+                .to_http_request();
+            accepts_encoding(&req, "gzip")
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("deflate, GZIP;q=0.5"));
+        assert!(!accepts("deflate, br"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("gzip; q=0.000"));
+        assert!(!accepts("x-gzip"));
+        assert!(!accepts_encoding(
+            &TestRequest::default().to_http_request(),
+            "gzip"
+        ));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"foo\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\"", "\"abc\""));
+        assert!(!etag_matches("", "\"abc\""));
+    }
+
+    #[test]
+    fn test_not_modified_since() {
+        use chrono::TimeZone;
+
+        let t = chrono::Utc.with_ymd_and_hms(2020, 3, 4, 5, 6, 7).unwrap()
+            + chrono::Duration::milliseconds(500);
+        assert!(not_modified_since("Wed, 04 Mar 2020 05:06:07 GMT", t));
+        assert!(not_modified_since("Thu, 05 Mar 2020 00:00:00 GMT", t));
+        assert!(!not_modified_since("Wed, 04 Mar 2020 05:06:06 GMT", t));
+        assert!(!not_modified_since("yesterday", t));
+        assert!(!not_modified_since("", t));
+    }
 
     #[test]
     fn test_validate_scope() {
@@ -102,4 +458,131 @@ mod tests {
             assert!(r.is_ok());
         }
     }
+
+    #[test]
+    fn test_resolve_oci() {
+        assert_eq!(resolve_oci(None, None).unwrap(), None);
+        assert_eq!(resolve_oci(Some(true), None).unwrap(), Some(true));
+        assert_eq!(resolve_oci(None, Some("oci")).unwrap(), Some(true));
+        assert_eq!(resolve_oci(None, Some("checksum")).unwrap(), Some(false));
+        assert_eq!(
+            resolve_oci(Some(false), Some("checksum")).unwrap(),
+            Some(false)
+        );
+        assert!(resolve_oci(Some(true), Some("checksum")).is_err());
+        assert!(resolve_oci(None, Some("OCI")).is_err());
+        assert!(resolve_oci(None, Some("")).is_err());
+    }
+
+    #[test]
+    fn test_validate_scope_oci() {
+        let scope = |oci| {
+            let basearch = Some("x86_64".to_string());
+            let stream = Some("stable".to_string());
+            validate_scope(basearch, stream, oci, &None).unwrap()
+        };
+        assert!(!scope(None).oci);
+        assert!(!scope(Some(false)).oci);
+        assert!(scope(Some(true)).oci);
+        assert_ne!(scope(Some(true)), scope(Some(false)));
+        assert_eq!(scope(None), scope(Some(false)));
+
+        // OCI and checksum scopes are allowed independently.
+        let checksum_only = Some(maplit::hashset! {scope(Some(false))});
+        let basearch = Some("x86_64".to_string());
+        let stream = Some("stable".to_string());
+        assert!(validate_scope(basearch, stream, Some(true), &checksum_only).is_err());
+    }
+
+    /// Graph query parameters, as parsed by the graph-builder.
+    #[derive(Debug, serde_derive::Deserialize)]
+    struct ScopeQuery {
+        basearch: Option<String>,
+        stream: Option<String>,
+        oci: Option<bool>,
+        scheme: Option<String>,
+    }
+
+    /// Generate a random, possibly malformed, query string.
+    fn random_query(rng: &mut impl rand::Rng) -> String {
+        use rand::seq::SliceRandom;
+
+        const KEYS: &[&str] = &["basearch", "stream", "oci", "scheme", "", "basearch[]"];
+        const VALUES: &[&str] = &[
+            "x86_64", "stable", "true", "false", "oci", "checksum", "", "%", "%FF", "%00", "%2G",
+            "+", "%26", "%3D", "\u{fffd}", "\u{0}", "1", "TRUE", "null",
+        ];
+        const SEPARATORS: &[&str] = &["&", "=", ";", "&&", "==", "?", "#"];
+
+        let mut query = String::new();
+        for _ in 0..rng.gen_range(0, 8) {
+            if !query.is_empty() {
+                query.push_str(SEPARATORS.choose(rng).unwrap());
+            }
+            query.push_str(KEYS.choose(rng).unwrap());
+            if rng.gen_bool(0.9) {
+                query.push('=');
+            }
+            if rng.gen_bool(0.2) {
+                // Random printable and non-ASCII noise, possibly very long.
+                let len = if rng.gen_bool(0.05) {
+                    4096
+                } else {
+                    rng.gen_range(0, 16)
+                };
+                query.extend((0..len).map(|_| rng.gen::<char>()));
+            } else {
+                query.push_str(VALUES.choose(rng).unwrap());
+            }
+        }
+        query
+    }
+
+    #[test]
+    fn test_validate_scope_fuzz() {
+        use rand::SeedableRng;
+
+        // Fixed seed, so that failures are reproducible.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x5c09e);
+        let stable = GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let allowlist = Some(maplit::hashset! {stable});
+
+        for _ in 0..10_000 {
+            let input = random_query(&mut rng);
+            let query = match actix_web::web::Query::<ScopeQuery>::from_query(&input) {
+                Ok(q) => q.into_inner(),
+                Err(_) => continue,
+            };
+            let oci = match resolve_oci(query.oci, query.scheme.as_deref()) {
+                Ok(oci) => oci,
+                Err(_) => continue,
+            };
+            if let Some(scheme) = &query.scheme {
+                assert_eq!(oci, Some(scheme == "oci"), "query: {:?}", input);
+            }
+
+            for filter in &[None, allowlist.clone()] {
+                let result =
+                    validate_scope(query.basearch.clone(), query.stream.clone(), oci, filter);
+                let scope = match result {
+                    Ok(scope) => scope,
+                    Err(e) => {
+                        assert!(!e.to_string().is_empty(), "query: {:?}", input);
+                        continue;
+                    }
+                };
+                assert_eq!(Some(&scope.basearch), query.basearch.as_ref());
+                assert_eq!(Some(&scope.stream), query.stream.as_ref());
+                assert!(!scope.basearch.is_empty() && !scope.stream.is_empty());
+                assert_eq!(scope.oci, oci.unwrap_or(false));
+                if let Some(allowed) = filter {
+                    assert!(allowed.contains(&scope), "query: {:?}", input);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,75 @@
+//! Golden-file tests for graph assembly.
+//!
+//! Each directory under `tests/fixtures` holds a `releases.json` release index
+//! and an `updates.json` updates document, plus the expected `graph.json`.
+//! Run with `UPDATE_GOLDENS=1` to regenerate the expected graphs.
+
+use commons::graph::{Graph, GraphScope};
+use commons::metadata::{ReleasesJSON, UpdatesJSON};
+use std::path::{Path, PathBuf};
+
+/// Environment variable enabling the "update goldens" mode.
+const UPDATE_GOLDENS_ENV: &str = "UPDATE_GOLDENS";
+
+fn fixture_dir(case: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(case)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> T {
+    let content = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("failed to read '{}': {}", path.display(), e));
+    serde_json::from_str(&content)
+        .unwrap_or_else(|e| panic!("failed to parse '{}': {}", path.display(), e))
+}
+
+fn check_golden(case: &str) {
+    let dir = fixture_dir(case);
+    let releases: ReleasesJSON = read_json(&dir.join("releases.json"));
+    let updates: UpdatesJSON = read_json(&dir.join("updates.json"));
+    let scope = GraphScope {
+        basearch: "x86_64".to_string(),
+        stream: "stable".to_string(),
+        oci: false,
+    };
+    let graph = Graph::from_metadata(releases.releases, updates, scope).unwrap();
+
+    // Go through a `Value` to get a canonical (sorted-keys) form.
+    let actual = serde_json::to_value(&graph).unwrap();
+    let golden = dir.join("graph.json");
+    if std::env::var_os(UPDATE_GOLDENS_ENV).is_some() {
+        let mut content = serde_json::to_string_pretty(&actual).unwrap();
+        content.push('\n');
+        std::fs::write(&golden, content).unwrap();
+        return;
+    }
+
+    let expected: serde_json::Value = read_json(&golden);
+    assert_eq!(
+        actual, expected,
+        "graph for '{}' differs from golden file, rerun with {}=1 to update it",
+        case, UPDATE_GOLDENS_ENV
+    );
+}
+
+#[test]
+fn golden_no_barriers() {
+    check_golden("no_barriers");
+}
+
+#[test]
+fn golden_single_barrier() {
+    check_golden("single_barrier");
+}
+
+#[test]
+fn golden_in_progress_barrier() {
+    check_golden("in_progress_barrier");
+}
+
+#[test]
+fn golden_multiple_rollouts() {
+    check_golden("multiple_rollouts");
+}
@@ -0,0 +1,42 @@
+//! Build script, recording build information for the `build_info` metric
+//! and the `version` subcommand.
+
+use chrono::TimeZone;
+use std::process::Command;
+
+/// Run a command and return its trimmed stdout, if successful.
+fn command_output(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+fn main() {
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    // Honor `SOURCE_DATE_EPOCH`, for reproducible builds.
+    let build_date = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| chrono::Utc.timestamp_opt(epoch, 0).single())
+        .unwrap_or_else(chrono::Utc::now);
+
+    println!("cargo:rustc-env=GB_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=GB_RUSTC_VERSION={}", rustc_version);
+    println!(
+        "cargo:rustc-env=GB_BUILD_DATE={}",
+        build_date.format("%Y-%m-%dT%H:%M:%SZ")
+    );
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+}
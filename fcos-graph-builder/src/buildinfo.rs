@@ -0,0 +1,36 @@
+//! Build information, recorded by the build script.
+
+use serde::Serialize;
+use std::fmt;
+
+/// Details about the running build.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct BuildInfo {
+    pub(crate) name: &'static str,
+    pub(crate) version: &'static str,
+    pub(crate) commit: &'static str,
+    pub(crate) build_date: &'static str,
+    pub(crate) rustc_version: &'static str,
+}
+
+impl BuildInfo {
+    /// Return information about the current build.
+    pub(crate) fn current() -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("GB_GIT_COMMIT"),
+            build_date: env!("GB_BUILD_DATE"),
+            rustc_version: env!("GB_RUSTC_VERSION"),
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.name, self.version)?;
+        writeln!(f, "commit: {}", self.commit)?;
+        writeln!(f, "build date: {}", self.build_date)?;
+        write!(f, "rustc: {}", self.rustc_version)
+    }
+}
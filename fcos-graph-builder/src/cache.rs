@@ -0,0 +1,67 @@
+//! Small bounded caches.
+
+use std::collections::VecDeque;
+
+/// Least-recently-used cache, with a fixed capacity.
+///
+/// This is meant for a handful of entries, lookups are linear.
+#[derive(Clone, Debug)]
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    /// Entries, most recently used first.
+    entries: VecDeque<(K, V)>,
+}
+
+impl<K: PartialEq, V> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Look up an entry, marking it as the most recently used one.
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let pos = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(pos)?;
+        self.entries.push_front(entry);
+        self.entries.front().map(|(_, v)| v)
+    }
+
+    /// Insert an entry, evicting the least recently used one if full.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(pos) = self.entries.iter().position(|(k, _)| *k == key) {
+            self.entries.remove(pos);
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front((key, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+
+        // "b" is the least recently used one.
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+
+        cache.insert("a", 4);
+        assert_eq!(cache.get(&"a"), Some(&4));
+        assert_eq!(cache.entries.len(), 2);
+    }
+}
@@ -1,17 +1,60 @@
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, Subcommand};
 use log::LevelFilter;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 /// CLI configuration options.
 #[derive(Debug, Parser)]
+#[clap(subcommand_negates_reqs = true)]
 pub(crate) struct CliOptions {
     /// Verbosity level (higher is more verbose).
     #[clap(short = 'v', action = ArgAction::Count)]
     verbosity: u8,
 
-    /// Path to configuration file.
-    #[clap(short = 'c')]
-    pub config_path: PathBuf,
+    /// Path to configuration file or directory (can be repeated, merged in order).
+    #[clap(short = 'c', action = ArgAction::Append, required = true)]
+    pub config_paths: Vec<PathBuf>,
+
+    /// Address for the main service, overriding the configuration file.
+    #[clap(long = "listen", value_name = "ADDR:PORT")]
+    pub listen: Option<SocketAddr>,
+
+    /// Address for the status service, overriding the configuration file.
+    #[clap(long = "status-listen", value_name = "ADDR:PORT")]
+    pub status_listen: Option<SocketAddr>,
+
+    /// Reject unknown configuration keys, instead of ignoring them with a warning.
+    #[clap(long = "strict-config")]
+    pub strict_config: bool,
+
+    /// Subcommand to run instead of the service, if any.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// CLI subcommands.
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Print detailed build information.
+    Version {
+        /// Print as JSON.
+        #[clap(long = "json")]
+        json: bool,
+    },
+    /// Validate a graph from a JSON file, reporting all invariant violations.
+    Validate {
+        /// Path to the graph.
+        #[clap(long = "file", value_name = "PATH")]
+        file: PathBuf,
+
+        /// Additional metadata key that every node must have (can be repeated).
+        #[clap(long = "require-key", value_name = "KEY", action = ArgAction::Append)]
+        required_keys: Vec<String>,
+
+        /// Namespace of built-in metadata keys in the graph, if not the default one.
+        #[clap(long = "key-namespace", value_name = "NAMESPACE")]
+        key_namespace: Option<String>,
+    },
 }
 
 impl CliOptions {
@@ -1,14 +1,786 @@
-use failure::Fallible;
-use std::path::Path;
+use commons::metadata;
+use failure::{bail, ensure, Fallible, ResultExt};
+use serde::de::{self, IntoDeserializer};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Current schema version of configuration files.
+///
+/// Bumped on incompatible changes, e.g. renamed keys.
+pub const SCHEMA_VERSION: u32 = 1;
 
 /// Configuration file.
-#[derive(Debug, Default)]
-pub struct FileConfig {}
+#[derive(Debug, Default, Deserialize)]
+pub struct FileConfig {
+    /// Schema version of this file, assumed to be the first one if unset.
+    pub schema_version: Option<u32>,
+    /// Main service (graph endpoint) section.
+    pub service: Option<ServiceConfig>,
+    /// Status server section.
+    pub status: Option<StatusConfig>,
+}
 
 impl FileConfig {
-    pub fn parse_file(_path: impl AsRef<Path>) -> Fallible<Self> {
-        // TODO(lucab): translate config entries.
-        let cfg = FileConfig::default();
+    /// Parse and merge multiple configuration sources, in order.
+    ///
+    /// Each source can be either a file or a directory, in which case all
+    /// the `*.toml` fragments inside it are merged in lexical order.
+    ///
+    /// Unknown keys are reported as warnings, or rejected if `strict`.
+    pub fn parse_paths(paths: &[PathBuf], strict: bool) -> Fallible<Self> {
+        let mut cfg = FileConfig::default();
+        for path in paths {
+            for file in Self::expand_path(path)? {
+                log::debug!("parsing config fragment: {}", file.display());
+                cfg.merge(Self::parse_file(&file, strict)?);
+            }
+        }
+        Ok(cfg)
+    }
+
+    /// Return the configuration files for a source path.
+    fn expand_path(path: &Path) -> Fallible<Vec<PathBuf>> {
+        if !path.is_dir() {
+            return Ok(vec![path.to_path_buf()]);
+        }
+
+        let mut fragments = vec![];
+        let entries = std::fs::read_dir(path)
+            .with_context(|_| format!("failed to read config directory '{}'", path.display()))?;
+        for entry in entries {
+            let fragment = entry?.path();
+            if fragment.is_file() && fragment.extension() == Some(OsStr::new("toml")) {
+                fragments.push(fragment);
+            }
+        }
+        fragments.sort();
+        Ok(fragments)
+    }
+
+    /// Merge another configuration on top of this one.
+    ///
+    /// Scalar values from `other` take precedence, lists are appended.
+    pub fn merge(&mut self, other: FileConfig) {
+        merge_scalar(&mut self.schema_version, other.schema_version);
+        merge_section(&mut self.service, other.service, ServiceConfig::merge);
+        merge_section(&mut self.status, other.status, StatusConfig::merge);
+    }
+
+    /// Parse a single configuration file.
+    ///
+    /// Unknown keys are reported as warnings, or rejected if `strict`.
+    pub fn parse_file(path: impl AsRef<Path>, strict: bool) -> Fallible<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|_| format!("failed to read config file '{}'", path.display()))?;
+        let cfg: FileConfig = toml::from_str(&content)
+            .with_context(|_| format!("failed to parse config file '{}'", path.display()))?;
+        cfg.check_schema_version()
+            .with_context(|_| format!("invalid config file '{}'", path.display()))?;
+
+        let unknown = Self::unknown_keys(&content)?;
+        if strict {
+            ensure!(
+                unknown.is_empty(),
+                "unknown keys in config file '{}': {}",
+                path.display(),
+                unknown.join(", ")
+            );
+        }
+        for key in unknown {
+            log::warn!(
+                "ignoring unknown key '{}' in config file '{}' (possibly misspelled, \
+                 or renamed in a newer schema)",
+                key,
+                path.display()
+            );
+        }
         Ok(cfg)
     }
+
+    /// Check that this file can be handled, warning about older schemas.
+    fn check_schema_version(&self) -> Fallible<()> {
+        let version = self.schema_version.unwrap_or(1);
+        if version == 0 {
+            bail!("invalid schema_version: must be positive");
+        }
+        if version > SCHEMA_VERSION {
+            bail!(
+                "unsupported schema_version {}, newer than {}: a newer fcos-graph-builder \
+                 is required",
+                version,
+                SCHEMA_VERSION
+            );
+        }
+        if version < SCHEMA_VERSION {
+            log::warn!(
+                "config uses an older schema_version ({}, current is {}): \
+                 some keys may have been renamed, check the sample configuration",
+                version,
+                SCHEMA_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// Return the keys in a configuration file which are ignored when parsing it,
+    /// as dotted paths.
+    fn unknown_keys(content: &str) -> Fallible<Vec<String>> {
+        let value: toml::Value = toml::from_str(content)?;
+        let unknown = RefCell::new(vec![]);
+        FileConfig::deserialize(KeyTracker {
+            value,
+            path: String::new(),
+            unknown: &unknown,
+        })?;
+        Ok(unknown.into_inner())
+    }
+}
+
+/// A deserializer over a TOML value, recording unknown struct fields.
+///
+/// Such fields are otherwise silently ignored when deserializing.
+struct KeyTracker<'a> {
+    value: toml::Value,
+    /// Dotted path to this value.
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'a> KeyTracker<'a> {
+    fn visit_table<'de, V: de::Visitor<'de>>(
+        self,
+        fields: Option<&'static [&'static str]>,
+        visitor: V,
+    ) -> Result<V::Value, toml::de::Error> {
+        match self.value {
+            toml::Value::Table(table) => visitor.visit_map(KeyTrackerMap {
+                entries: table.into_iter(),
+                value: None,
+                fields,
+                path: self.path,
+                unknown: self.unknown,
+            }),
+            toml::Value::Array(array) => {
+                let path = self.path;
+                let unknown = self.unknown;
+                let elements = array
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, value)| KeyTracker {
+                        value,
+                        path: format!("{}[{}]", path, index),
+                        unknown,
+                    });
+                visitor.visit_seq(de::value::SeqDeserializer::new(elements))
+            }
+            other => de::Deserializer::deserialize_any(other, visitor),
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for KeyTracker<'a> {
+    type Error = toml::de::Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.visit_table(None, visitor)
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.visit_table(Some(fields), visitor)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_enum(self.value, name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map identifier
+        ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, toml::de::Error> for KeyTracker<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+struct KeyTrackerMap<'a> {
+    entries: <toml::value::Table as IntoIterator>::IntoIter,
+    /// Value for the last returned key.
+    value: Option<KeyTracker<'a>>,
+    /// Known fields, if a struct.
+    fields: Option<&'static [&'static str]>,
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for KeyTrackerMap<'a> {
+    type Error = toml::de::Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let (key, value) = match self.entries.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let path = if self.path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", self.path, key)
+        };
+        if let Some(fields) = self.fields {
+            if !fields.contains(&key.as_str()) {
+                self.unknown.borrow_mut().push(path.clone());
+            }
+        }
+        self.value = Some(KeyTracker {
+            value,
+            path,
+            unknown: self.unknown,
+        });
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let value = self.value.take().expect("value without a key");
+        seed.deserialize(value)
+    }
+}
+
+/// Config section for the main service.
+#[derive(Debug, Default, Deserialize)]
+pub struct ServiceConfig {
+    /// Listening address and port, e.g. `0.0.0.0:8080` or `[::]:8080`.
+    pub listen: Option<SocketAddr>,
+    /// Path of a unix-domain socket to listen on, instead of `listen`.
+    pub listen_uds: Option<PathBuf>,
+    /// HTTP keep-alive timeout, in seconds (0 disables keep-alive).
+    pub keep_alive_secs: Option<usize>,
+    /// Timeout for clients to send the first request, in milliseconds.
+    pub client_timeout_ms: Option<u64>,
+    /// Maximum number of concurrent connections per worker.
+    pub max_connections: Option<usize>,
+    /// Number of HTTP worker threads.
+    pub workers: Option<usize>,
+    /// Versions to remove from graphs, per stream.
+    pub blocked_versions: Option<HashMap<String, Vec<String>>>,
+    /// Overrides of upstream dead-end markers, per stream.
+    pub deadend_overrides: Option<HashMap<String, DeadendOverridesConfig>>,
+    /// Delay (in milliseconds) before answering requests for unknown scopes.
+    pub unknown_scope_delay_ms: Option<u64>,
+    /// Duration (in milliseconds) above which graph requests are logged as slow.
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Compression level (1-9) for gzip-encoded graphs.
+    pub gzip_level: Option<u32>,
+    /// Number of past graphs retained per scope, for diffing.
+    pub diff_history_len: Option<usize>,
+    /// Canary streams, composed from a base stream plus overlay releases.
+    pub canary_streams: Option<HashMap<String, CanaryStreamConfig>>,
+    /// Per-client rate limiting for graph requests.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Whether to pause graph refreshes, serving the last cached graphs.
+    pub frozen: Option<bool>,
+    /// Whether to serve empty graphs, during maintenance.
+    pub maintenance: Option<bool>,
+    /// Metadata keys that every node in a graph must have.
+    pub required_metadata_keys: Option<Vec<String>>,
+    /// Whether to check that OCI and checksum graphs have the same versions.
+    pub check_scope_consistency: Option<bool>,
+    /// Transforms to apply to assembled graphs, in order.
+    pub transforms: Option<Vec<TransformConfig>>,
+    /// Maximum age-index span of graph nodes behind the newest one.
+    pub max_age_span: Option<u64>,
+    /// Namespace for metadata keys in served graphs.
+    pub metadata_key_namespace: Option<String>,
+    /// Path to a PEM-encoded Ed25519 private key for signing served graphs.
+    pub signing_key_path: Option<PathBuf>,
+    /// Path to a TOML file of extra metadata for graph nodes, per version.
+    pub extra_metadata_path: Option<PathBuf>,
+    /// Whether extra metadata replaces keys already set on graph nodes.
+    pub extra_metadata_override: Option<bool>,
+    /// Whether to redact all barrier and dead-end reasons in served graphs.
+    pub redact_reasons: Option<bool>,
+    /// Replacements for specific barrier and dead-end reasons, verbatim.
+    pub reason_replacements: Option<HashMap<String, String>>,
+    /// Scope for graph requests without any scope parameters.
+    pub default_scope: Option<DefaultScopeConfig>,
+    /// Origins allowed for CORS requests, or all of them if unset.
+    pub origin_allowlist: Option<Vec<String>>,
+    /// Origins allowed for CORS graph requests, per stream.
+    pub stream_origin_allowlist: Option<HashMap<String, Vec<String>>>,
+    /// Minimum client version required to fetch graphs, per stream.
+    pub min_client_versions: Option<HashMap<String, String>>,
+    /// Whether to also serve `/metrics` on the main service.
+    pub serve_metrics: Option<bool>,
+    /// `User-Agent` for upstream requests.
+    pub user_agent: Option<String>,
+    /// Directory for persisting the last graphs, to serve them on startup.
+    pub warm_cache_dir: Option<PathBuf>,
+    /// Networks (CIDR) of proxies trusted for `X-Forwarded-*` headers.
+    pub trusted_proxies: Option<Vec<String>>,
+    /// Basearch to serve instead, for basearches without a graph.
+    pub fallback_basearch: Option<HashMap<String, String>>,
+    /// Base URLs for upstream metadata, tried in order.
+    pub upstream_mirrors: Option<Vec<String>>,
+    /// Timeout (in seconds) for each upstream request to a mirror.
+    pub upstream_timeout_secs: Option<u64>,
+    /// Delay (in seconds) before the first upstream scrape.
+    pub initial_scrape_delay_secs: Option<u64>,
+    /// Maximum number of streams doing their first upstream scrape concurrently.
+    pub initial_scrape_concurrency: Option<usize>,
+    /// Whether to fail startup unless the first scrape succeeds for all scopes.
+    pub require_all_scopes: Option<bool>,
+}
+
+impl ServiceConfig {
+    fn merge(&mut self, other: ServiceConfig) {
+        merge_scalar(&mut self.listen, other.listen);
+        merge_scalar(&mut self.listen_uds, other.listen_uds);
+        merge_scalar(&mut self.keep_alive_secs, other.keep_alive_secs);
+        merge_scalar(&mut self.client_timeout_ms, other.client_timeout_ms);
+        merge_scalar(&mut self.max_connections, other.max_connections);
+        merge_scalar(&mut self.workers, other.workers);
+        merge_scalar(
+            &mut self.unknown_scope_delay_ms,
+            other.unknown_scope_delay_ms,
+        );
+        merge_scalar(
+            &mut self.slow_request_threshold_ms,
+            other.slow_request_threshold_ms,
+        );
+        merge_scalar(&mut self.gzip_level, other.gzip_level);
+        merge_scalar(&mut self.diff_history_len, other.diff_history_len);
+        merge_scalar(&mut self.rate_limit, other.rate_limit);
+        merge_scalar(&mut self.frozen, other.frozen);
+        merge_scalar(&mut self.maintenance, other.maintenance);
+        merge_scalar(
+            &mut self.check_scope_consistency,
+            other.check_scope_consistency,
+        );
+        merge_scalar(&mut self.default_scope, other.default_scope);
+        merge_scalar(&mut self.serve_metrics, other.serve_metrics);
+        merge_scalar(&mut self.user_agent, other.user_agent);
+        merge_scalar(&mut self.warm_cache_dir, other.warm_cache_dir);
+        merge_scalar(&mut self.max_age_span, other.max_age_span);
+        merge_scalar(
+            &mut self.metadata_key_namespace,
+            other.metadata_key_namespace,
+        );
+        merge_scalar(&mut self.upstream_timeout_secs, other.upstream_timeout_secs);
+        merge_scalar(
+            &mut self.initial_scrape_delay_secs,
+            other.initial_scrape_delay_secs,
+        );
+        merge_scalar(
+            &mut self.initial_scrape_concurrency,
+            other.initial_scrape_concurrency,
+        );
+        merge_scalar(&mut self.require_all_scopes, other.require_all_scopes);
+        merge_scalar(&mut self.signing_key_path, other.signing_key_path);
+        merge_scalar(&mut self.extra_metadata_path, other.extra_metadata_path);
+        merge_scalar(
+            &mut self.extra_metadata_override,
+            other.extra_metadata_override,
+        );
+        merge_scalar(&mut self.redact_reasons, other.redact_reasons);
+        merge_section(
+            &mut self.reason_replacements,
+            other.reason_replacements,
+            |replacements, other| replacements.extend(other),
+        );
+        merge_section(
+            &mut self.transforms,
+            other.transforms,
+            |transforms, other| transforms.extend(other),
+        );
+        merge_section(
+            &mut self.upstream_mirrors,
+            other.upstream_mirrors,
+            |mirrors, other| mirrors.extend(other),
+        );
+        merge_section(
+            &mut self.trusted_proxies,
+            other.trusted_proxies,
+            |proxies, other| proxies.extend(other),
+        );
+        merge_section(
+            &mut self.fallback_basearch,
+            other.fallback_basearch,
+            |fallbacks, other| fallbacks.extend(other),
+        );
+        merge_section(
+            &mut self.origin_allowlist,
+            other.origin_allowlist,
+            |origins, other| origins.extend(other),
+        );
+        merge_section(
+            &mut self.stream_origin_allowlist,
+            other.stream_origin_allowlist,
+            |allowlists, other| {
+                for (stream, origins) in other {
+                    allowlists.entry(stream).or_default().extend(origins);
+                }
+            },
+        );
+        merge_section(
+            &mut self.min_client_versions,
+            other.min_client_versions,
+            |versions, other| versions.extend(other),
+        );
+        merge_section(
+            &mut self.required_metadata_keys,
+            other.required_metadata_keys,
+            |keys, other| keys.extend(other),
+        );
+        merge_section(
+            &mut self.deadend_overrides,
+            other.deadend_overrides,
+            |overrides, other| {
+                for (stream, cfg) in other {
+                    match overrides.get_mut(&stream) {
+                        Some(existing) => existing.merge(cfg),
+                        None => {
+                            overrides.insert(stream, cfg);
+                        }
+                    }
+                }
+            },
+        );
+        merge_section(
+            &mut self.blocked_versions,
+            other.blocked_versions,
+            |blocked, other| {
+                for (stream, versions) in other {
+                    blocked.entry(stream).or_default().extend(versions);
+                }
+            },
+        );
+        merge_section(
+            &mut self.canary_streams,
+            other.canary_streams,
+            |canaries, other| {
+                for (stream, canary) in other {
+                    match canaries.get_mut(&stream) {
+                        Some(existing) => existing.merge(canary),
+                        None => {
+                            canaries.insert(stream, canary);
+                        }
+                    }
+                }
+            },
+        );
+    }
+}
+
+/// Config section for pushing metrics to a Prometheus Pushgateway.
+#[derive(Debug, Deserialize)]
+pub struct PushgatewayConfig {
+    /// Base URL of the Pushgateway.
+    pub url: String,
+    /// Job name to group pushed metrics under.
+    pub job: Option<String>,
+}
+
+/// Config section for overriding upstream dead-end markers of a stream.
+#[derive(Debug, Deserialize)]
+pub struct DeadendOverridesConfig {
+    /// Versions to keep serving updates from, even if marked as dead-ends.
+    pub ignore: Option<Vec<String>>,
+    /// Versions to treat as dead-ends, even if not marked as such.
+    pub force: Option<Vec<String>>,
+}
+
+impl DeadendOverridesConfig {
+    fn merge(&mut self, other: DeadendOverridesConfig) {
+        merge_section(&mut self.ignore, other.ignore, |ignore, other| {
+            ignore.extend(other)
+        });
+        merge_section(&mut self.force, other.force, |force, other| {
+            force.extend(other)
+        });
+    }
+}
+
+/// Config section for per-client rate limiting.
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    /// Sustained number of requests per second, per client.
+    pub requests_per_second: f64,
+    /// Maximum burst of requests, per client.
+    pub burst: u32,
+    /// Whether to identify clients via the `X-Forwarded-For` header from any peer.
+    ///
+    /// Deprecated, `service.trusted_proxies` should be used instead.
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+/// Config section for a graph transform step.
+#[derive(Debug, Deserialize)]
+pub struct TransformConfig {
+    /// Transform name, e.g. `strip-metadata`.
+    pub name: String,
+    /// Metadata keys, for `strip-metadata`.
+    pub keys: Option<Vec<String>>,
+    /// Metadata entries, for `add-static-metadata`.
+    pub metadata: Option<BTreeMap<String, String>>,
+}
+
+/// Config section for the default graph scope.
+#[derive(Debug, Deserialize)]
+pub struct DefaultScopeConfig {
+    pub basearch: String,
+    pub stream: String,
+    #[serde(default)]
+    pub oci: bool,
+}
+
+/// Config section for a canary stream.
+#[derive(Debug, Deserialize)]
+pub struct CanaryStreamConfig {
+    /// Stream providing the base graph.
+    pub base: String,
+    /// Additional releases, on top of the base stream ones.
+    #[serde(default)]
+    pub overlay: Vec<metadata::Release>,
+}
+
+impl CanaryStreamConfig {
+    fn merge(&mut self, other: CanaryStreamConfig) {
+        self.base = other.base;
+        self.overlay.extend(other.overlay);
+    }
+}
+
+/// Config section for the status server.
+#[derive(Debug, Default, Deserialize)]
+pub struct StatusConfig {
+    /// Whether to run the status server at all.
+    pub enabled: Option<bool>,
+    /// Listening address and port, e.g. `0.0.0.0:9080` or `[::]:9080`.
+    pub listen: Option<SocketAddr>,
+    /// Path of a unix-domain socket to listen on, instead of `listen`.
+    pub listen_uds: Option<PathBuf>,
+    /// HTTP keep-alive timeout, in seconds (0 disables keep-alive).
+    pub keep_alive_secs: Option<usize>,
+    /// Timeout for clients to send the first request, in milliseconds.
+    pub client_timeout_ms: Option<u64>,
+    /// Maximum number of concurrent connections per worker.
+    pub max_connections: Option<usize>,
+    /// Number of HTTP worker threads.
+    pub workers: Option<usize>,
+    /// Bearer token for admin endpoints, which are disabled if unset.
+    pub admin_token: Option<String>,
+    /// Whether to serve the last fetched upstream metadata, for debugging.
+    pub debug_upstream: Option<bool>,
+    /// Whether to retain and serve graphs including edges out of dead-ends, for auditing.
+    pub debug_full_graph: Option<bool>,
+    /// Prometheus Pushgateway to push final metrics to, on shutdown.
+    pub pushgateway: Option<PushgatewayConfig>,
+}
+
+impl StatusConfig {
+    fn merge(&mut self, other: StatusConfig) {
+        merge_scalar(&mut self.enabled, other.enabled);
+        merge_scalar(&mut self.listen, other.listen);
+        merge_scalar(&mut self.listen_uds, other.listen_uds);
+        merge_scalar(&mut self.keep_alive_secs, other.keep_alive_secs);
+        merge_scalar(&mut self.client_timeout_ms, other.client_timeout_ms);
+        merge_scalar(&mut self.max_connections, other.max_connections);
+        merge_scalar(&mut self.workers, other.workers);
+        merge_scalar(&mut self.admin_token, other.admin_token);
+        merge_scalar(&mut self.debug_upstream, other.debug_upstream);
+        merge_scalar(&mut self.debug_full_graph, other.debug_full_graph);
+        merge_scalar(&mut self.pushgateway, other.pushgateway);
+    }
+}
+
+/// Merge an optional scalar value, with `other` taking precedence if set.
+fn merge_scalar<T>(value: &mut Option<T>, other: Option<T>) {
+    if other.is_some() {
+        *value = other;
+    }
+}
+
+/// Merge an optional section, via `merge_fn` if both sides are set.
+fn merge_section<T>(value: &mut Option<T>, other: Option<T>, merge_fn: impl FnOnce(&mut T, T)) {
+    match (value.as_mut(), other) {
+        (Some(current), Some(other)) => merge_fn(current, other),
+        (None, Some(other)) => *value = Some(other),
+        (_, None) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut cfg: FileConfig = toml::from_str(
+            r#"
+            [service]
+            workers = 2
+            unknown_scope_delay_ms = 100
+            upstream_mirrors = ["https://a.example.com/"]
+            origin_allowlist = ["https://a.example.com"]
+            [service.stream_origin_allowlist]
+            stable = ["https://a.example.com"]
+            [service.blocked_versions]
+            stable = ["1"]
+            [service.deadend_overrides.stable]
+            ignore = ["4"]
+            [[service.transforms]]
+            name = "strip-metadata"
+            keys = ["a"]
+            "#,
+        )
+        .unwrap();
+        let other: FileConfig = toml::from_str(
+            r#"
+            [service]
+            workers = 4
+            upstream_mirrors = ["https://b.example.com/"]
+            origin_allowlist = ["https://b.example.com"]
+            [service.stream_origin_allowlist]
+            stable = ["https://b.example.com"]
+            [service.blocked_versions]
+            stable = ["2"]
+            testing = ["3"]
+            [service.deadend_overrides.stable]
+            ignore = ["6"]
+            force = ["5"]
+            [[service.transforms]]
+            name = "add-static-metadata"
+            metadata = { b = "1" }
+            [status]
+            workers = 1
+            "#,
+        )
+        .unwrap();
+        cfg.merge(other);
+
+        let service = cfg.service.unwrap();
+        assert_eq!(service.workers, Some(4));
+        assert_eq!(service.unknown_scope_delay_ms, Some(100));
+        let blocked = service.blocked_versions.unwrap();
+        assert_eq!(blocked["stable"], vec!["1", "2"]);
+        assert_eq!(blocked["testing"], vec!["3"]);
+        let overrides = &service.deadend_overrides.unwrap()["stable"];
+        let versions = |v: &[&str]| Some(v.iter().map(|v| v.to_string()).collect());
+        assert_eq!(overrides.ignore, versions(&["4", "6"]));
+        assert_eq!(overrides.force, versions(&["5"]));
+        assert_eq!(
+            service.upstream_mirrors.unwrap(),
+            vec!["https://a.example.com/", "https://b.example.com/"]
+        );
+        let transforms: Vec<_> = service
+            .transforms
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(transforms, vec!["strip-metadata", "add-static-metadata"]);
+        let origins = vec!["https://a.example.com", "https://b.example.com"];
+        assert_eq!(service.origin_allowlist.unwrap(), origins);
+        assert_eq!(service.stream_origin_allowlist.unwrap()["stable"], origins);
+        assert_eq!(cfg.status.unwrap().workers, Some(1));
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let unknown = FileConfig::unknown_keys(
+            r#"
+            schema_version = 1
+            shcema_version = 1
+            [service]
+            workers = 2
+            worker = 2
+            [service.blocked_versions]
+            stable = ["1"]
+            [service.canary_streams.canary]
+            base = "stable"
+            overlay = [{ version = "1", commits = [], metadata = "", url = "" }]
+            [[service.transforms]]
+            name = "strip-metadata"
+            keys = ["a"]
+            [[service.transforms]]
+            name = "add-static-metadata"
+            metdata = { a = "1" }
+            [status.pushgateway]
+            url = "http://localhost:9091"
+            [debug]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            unknown,
+            vec![
+                "debug",
+                "service.canary_streams.canary.overlay[0].url",
+                "service.transforms[1].metdata",
+                "service.worker",
+                "shcema_version",
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("gb-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("unknown.toml");
+        std::fs::write(&path, "[service]\nworker = 2\n").unwrap();
+        assert!(FileConfig::parse_file(&path, false).is_ok());
+        let err = FileConfig::parse_file(&path, true).unwrap_err();
+        assert!(err.to_string().contains("service.worker"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let check = |input: &str| {
+            let cfg: FileConfig = toml::from_str(input).unwrap();
+            cfg.check_schema_version()
+        };
+        assert!(check("").is_ok());
+        assert!(check("schema_version = 1").is_ok());
+        assert!(check("schema_version = 0").is_err());
+        let err = check(&format!("schema_version = {}", SCHEMA_VERSION + 1)).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{}", err);
+    }
 }
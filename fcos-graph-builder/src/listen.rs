@@ -0,0 +1,80 @@
+//! Listening sockets for HTTP servers.
+
+use failure::{bail, Fallible};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Address for a server to listen on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ListenAddr {
+    /// TCP address and port.
+    Tcp(SocketAddr),
+    /// Path of a unix-domain socket.
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Remove a leftover unix-domain socket from a previous run, if any.
+///
+/// Sockets still accepting connections, and other kinds of files, are left
+/// alone and reported as errors.
+pub(crate) fn remove_stale_socket(path: &Path) -> Fallible<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => bail!("failed to inspect '{}': {}", path.display(), e),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("'{}' exists and is not a socket", path.display());
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("socket '{}' is already in use", path.display());
+    }
+
+    log::info!("removing stale socket '{}'", path.display());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = std::env::temp_dir().join(format!("gb-listen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gb.sock");
+
+        // Nothing to remove.
+        remove_stale_socket(&path).unwrap();
+
+        // Live sockets are kept.
+        let listener = UnixListener::bind(&path).unwrap();
+        remove_stale_socket(&path).unwrap_err();
+        assert!(path.exists());
+
+        // Stale ones are removed.
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+
+        // Other files are kept.
+        std::fs::write(&path, "").unwrap();
+        remove_stale_socket(&path).unwrap_err();
+        assert!(path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
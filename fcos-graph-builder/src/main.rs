@@ -3,23 +3,48 @@ extern crate log;
 #[macro_use]
 extern crate prometheus;
 
+mod buildinfo;
+mod cache;
 mod cli;
 mod config;
+mod listen;
+mod pushgateway;
+mod ratelimit;
+mod reload;
 mod scraper;
 mod settings;
+mod signing;
+mod transforms;
+mod warmcache;
 
 use actix::prelude::*;
-use actix_web::{web, App, HttpResponse};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpRequest, HttpResponse};
 use clap::{crate_name, crate_version, Parser};
+use commons::version::ClientVersion;
+use commons::web::json_error;
 use commons::{graph, metrics};
 use failure::{Fallible, ResultExt};
-use prometheus::{IntCounterVec, IntGauge, IntGaugeVec};
-use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use prometheus::{GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /// Top-level log target for this application.
 static APP_LOG_TARGET: &str = "fcos_graph_builder";
 
+/// Header carrying the request-id, for tracing requests across proxies.
+static REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Maximum accepted length for client-provided request-ids.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Log target for requests towards unknown scopes (e.g. enumeration attempts).
+static UNKNOWN_SCOPE_LOG_TARGET: &str = "fcos_graph_builder::unknown_scope";
+
 lazy_static::lazy_static! {
     static ref CACHED_GRAPH_REQUESTS: IntCounterVec = register_int_counter_vec!(
         "fcos_cincinnati_gb_cache_graph_requests_total",
@@ -31,21 +56,132 @@ lazy_static::lazy_static! {
         "Number of edges in the cached graph, after processing",
         &["basearch", "stream", "type"]
     ).unwrap();
+    static ref GRAPH_EDGES_BY_SOURCE: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_gb_scraper_graph_edges_by_source",
+        "Number of edges in the cached graph, by source (rollouts or barriers)",
+        &["basearch", "stream", "type", "source"]
+    ).unwrap();
     static ref GRAPH_FINAL_RELEASES: IntGaugeVec = register_int_gauge_vec!(
         "fcos_cincinnati_gb_scraper_graph_final_releases",
         "Number of releases in the cached graph, after processing",
         &["basearch", "stream", "type"]
     ).unwrap();
+    static ref GRAPH_VALIDATION_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_gb_scraper_graph_validation_errors_total",
+        "Total number of assembled graphs rejected by validation",
+        &["basearch", "stream", "type", "check"]
+    ).unwrap();
+    static ref EMPTY_GRAPHS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_gb_scraper_empty_graphs_total",
+        "Total number of assembled graphs without any release",
+        &["basearch", "stream", "type"]
+    ).unwrap();
+    static ref DEADEND_OVERRIDES: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_gb_scraper_graph_deadend_overrides",
+        "Number of releases with an overridden dead-end status in the cached graph",
+        &["basearch", "stream", "type"]
+    ).unwrap();
+    static ref SCOPE_MISSING_VERSIONS: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_gb_scraper_graph_scope_missing_versions",
+        "Number of versions missing from a graph, but present in the other scheme graph",
+        &["basearch", "stream", "type"]
+    ).unwrap();
     static ref LAST_REFRESH: IntGaugeVec = register_int_gauge_vec!(
        "fcos_cincinnati_gb_scraper_graph_last_refresh_timestamp",
         "UTC timestamp of last graph refresh",
         &["basearch", "stream", "type"]
     ).unwrap();
+    static ref UPSTREAM_LAST_MODIFIED: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_gb_upstream_last_modified_timestamp",
+        "UTC timestamp of the last upstream change to metadata of a cached graph",
+        &["basearch", "stream", "type"]
+    ).unwrap();
+    static ref SCRAPE_SUCCESS_RATIO: GaugeVec = register_gauge_vec!(
+       "fcos_cincinnati_gb_scraper_graph_scrape_success_ratio",
+        "Ratio of recent scrapes which refreshed a graph, over a sliding window",
+        &["basearch", "stream", "type"]
+    ).unwrap();
+    static ref RATE_LIMITED_REQUESTS: IntCounter = register_int_counter!(opts!(
+        "fcos_cincinnati_gb_rate_limited_requests_total",
+        "Total number of graph requests rejected by rate limiting"
+    )).unwrap();
+    static ref CONDITIONAL_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_gb_conditional_requests_total",
+        "Total number of conditional graph requests, by evaluated condition and result",
+        &["condition", "result"]
+    ).unwrap();
     static ref UPSTREAM_SCRAPES: IntCounterVec = register_int_counter_vec!(
        "fcos_cincinnati_gb_scraper_upstream_scrapes_total",
        "Total number of upstream scrapes",
         &["stream"]
     ).unwrap();
+    static ref UPSTREAM_RESPONSES: IntCounterVec = register_int_counter_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_responses_total",
+       "Total number of upstream responses, by HTTP status (200 changed, 304 unchanged)",
+        &["stream", "status"]
+    ).unwrap();
+    static ref UPSTREAM_ERRORS: IntCounterVec = register_int_counter_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_errors_total",
+       "Total number of rejected upstream metadata documents",
+        &["stream", "kind"]
+    ).unwrap();
+    static ref UPSTREAM_ACTIVE_MIRROR: IntGaugeVec = register_int_gauge_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_active_mirror",
+       "Index of the upstream mirror which served the last successful scrape",
+        &["stream"]
+    ).unwrap();
+    static ref UPSTREAM_MIRROR_FAILURES: IntCounterVec = register_int_counter_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_mirror_failures_total",
+       "Total number of failed scrapes, per upstream mirror index",
+        &["stream", "mirror"]
+    ).unwrap();
+    static ref UPSTREAM_MALFORMED_ROLLOUTS: IntGaugeVec = register_int_gauge_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_malformed_rollouts",
+       "Number of releases with malformed rollout parameters in the last updates metadata",
+        &["stream"]
+    ).unwrap();
+    static ref UPSTREAM_RELEASE_INDEX_PAGES: IntGaugeVec = register_int_gauge_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_release_index_pages",
+       "Number of pages in the last fetched upstream release index",
+        &["stream"]
+    ).unwrap();
+    static ref UPSTREAM_SCHEMA: IntGaugeVec = register_int_gauge_vec!(
+       "fcos_cincinnati_gb_scraper_upstream_schema_info",
+       "Schema version of the last accepted upstream updates metadata",
+        &["stream", "version"]
+    ).unwrap();
+    static ref SCRAPER_FROZEN: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_gb_scraper_frozen",
+        "Whether graph refreshes are currently frozen (1) or not (0)",
+        &["stream"]
+    ).unwrap();
+    static ref MAINTENANCE_MODE: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_gb_maintenance_mode",
+        "Whether empty graphs are currently served for maintenance (1) or not (0)"
+    )).unwrap();
+    static ref CONFIG_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_gb_config_reload_total",
+        "Total number of configuration reloads",
+        &["result"]
+    ).unwrap();
+    static ref CONFIG_LAST_RELOAD: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_gb_config_last_reload_timestamp",
+        "UTC timestamp of last successful configuration reload"
+    )).unwrap();
+    static ref INFLIGHT_REQUESTS: IntGauge = register_int_gauge!(opts!(
+        "fcos_cincinnati_gb_inflight_requests",
+        "Number of graph requests currently being served"
+    )).unwrap();
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fcos_cincinnati_gb_http_requests_total",
+        "Total number of HTTP requests, by method, route and status",
+        &["method", "path", "status"]
+    ).unwrap();
+    static ref BUILD_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "fcos_cincinnati_gb_build_info",
+        "Build information, as labels on a constant value of 1",
+        &["version", "commit", "rustc_version"]
+    ).unwrap();
     // NOTE(lucab): alternatively this could come from the runtime library, see
     // https://prometheus.io/docs/instrumenting/writing_clientlibs/#process-metrics
     static ref PROCESS_START_TIME: IntGauge = register_int_gauge!(opts!(
@@ -57,73 +193,365 @@ lazy_static::lazy_static! {
 fn main() -> Fallible<()> {
     // Parse command-line options.
     let cli_opts = cli::CliOptions::parse();
+    if let Some(command) = &cli_opts.command {
+        return run_command(command);
+    }
 
     // Setup logging.
     env_logger::Builder::from_default_env()
         .format_timestamp_secs()
         .format_module_path(false)
         .filter(Some(APP_LOG_TARGET), cli_opts.loglevel())
+        .filter(Some("commons"), cli_opts.loglevel())
         .try_init()
         .context("failed to initialize logging")?;
 
-    let sys = actix::System::new("fcos_cincinnati_gb");
+    let mut sys = actix::System::new("fcos_cincinnati_gb");
 
     // Parse config file and validate settings.
     let (service_settings, status_settings) = {
-        debug!("config file location: {}", cli_opts.config_path.display());
-        let cfg = config::FileConfig::parse_file(cli_opts.config_path)?;
+        debug!("config file locations: {:?}", cli_opts.config_paths);
+        let cfg = config::FileConfig::parse_paths(&cli_opts.config_paths, cli_opts.strict_config)?;
         let settings = settings::GraphBuilderSettings::validate_config(cfg)?;
         (settings.service, settings.status)
     };
 
-    let mut scrapers = HashMap::with_capacity(service_settings.streams.len());
+    let mut pending = Vec::with_capacity(service_settings.streams.len());
+    let warm_cache = service_settings
+        .warm_cache_dir
+        .as_ref()
+        .map(warmcache::WarmCache::new);
+    // Shared by all scrapers, bounding the cold-start burst of first scrapes.
+    let initial_permits = service_settings
+        .upstream
+        .initial_concurrency
+        .map(|n| Arc::new(tokio::sync::Semaphore::new(n)));
     for (&stream, &arches) in &service_settings.streams {
-        let addr = scraper::Scraper::new(
+        let scraper = scraper::Scraper::new(
             stream.to_string(),
             arches.iter().map(|&arch| String::from(arch)).collect(),
+            None,
+            &service_settings.user_agent,
+            &service_settings.upstream,
+            warm_cache.clone(),
+            scraper::ReloadSettings::for_stream(&service_settings, stream),
         )?
-        .start();
-        scrapers.insert(stream.to_string(), addr);
+        .with_initial_permits(initial_permits.clone())
+        .with_full_graphs(status_settings.debug_full_graph)
+        .with_diff_history_len(service_settings.diff_history_len);
+        pending.push(scraper);
     }
+    for (stream, canary) in &service_settings.canary_streams {
+        let arches = service_settings.stream_arches(stream).unwrap_or_default();
+        let scraper = scraper::Scraper::new(
+            stream.clone(),
+            arches.iter().map(|&arch| String::from(arch)).collect(),
+            Some(canary.clone()),
+            &service_settings.user_agent,
+            &service_settings.upstream,
+            warm_cache.clone(),
+            scraper::ReloadSettings::for_stream(&service_settings, stream),
+        )?
+        .with_initial_permits(initial_permits.clone())
+        .with_full_graphs(status_settings.debug_full_graph)
+        .with_diff_history_len(service_settings.diff_history_len);
+        pending.push(scraper);
+    }
+    if service_settings.require_all_scopes {
+        info!("scraping all streams before startup");
+        let scrapes = pending.into_iter().map(scraper::Scraper::initial_scrape);
+        let results = sys.block_on(futures::future::join_all(scrapes));
+        let failed = results.iter().filter(|r| r.is_err()).count();
+        if failed > 0 {
+            for err in results.iter().filter_map(|r| r.as_ref().err()) {
+                log::error!("{}", err);
+            }
+            failure::bail!("initial scrape failed for {} streams", failed);
+        }
+        pending = results.into_iter().filter_map(Result::ok).collect();
+    }
+    let graphs: HashMap<_, _> = pending
+        .iter()
+        .map(|scraper| (scraper.stream().to_string(), scraper.graph_store()))
+        .collect();
+    let scrapers: HashMap<_, _> = pending
+        .into_iter()
+        .map(|scraper| (scraper.stream().to_string(), scraper.start()))
+        .collect();
+
+    let maintenance = Arc::new(AtomicBool::new(service_settings.maintenance));
+    MAINTENANCE_MODE.set(service_settings.maintenance as i64);
+    actix::spawn(reload::watch_sighup(
+        cli_opts.config_paths.clone(),
+        cli_opts.strict_config,
+        scrapers.clone(),
+        maintenance.clone(),
+    ));
 
     // TODO(lucab): get allowed scopes from config file.
     let service_state = AppState {
         scope_filter: None,
         scrapers,
+        graphs,
+        unknown_scope_delay: service_settings.unknown_scope_delay,
+        slow_request_threshold: service_settings.slow_request_threshold,
+        gzip_level: service_settings.gzip_level,
+        rate_limiter: service_settings.rate_limit.as_ref().map(|rl| {
+            let limiter = ratelimit::RateLimiter::new(rl.requests_per_second, rl.burst);
+            Arc::new(limiter)
+        }),
+        trusted_proxies: Arc::new(service_settings.trusted_proxies.clone()),
+        admin_token: status_settings.admin_token.clone(),
+        default_scope: service_settings.default_scope.clone(),
+        min_client_versions: Arc::new(service_settings.min_client_versions.clone()),
+        fallback_basearch: Arc::new(service_settings.fallback_basearch.clone()),
+        origin_allowlist: service_settings.origin_allowlist.clone().map(Arc::new),
+        stream_origin_allowlist: Arc::new(service_settings.stream_origin_allowlist.clone()),
+        maintenance,
+        graph_schema: {
+            let namespace = service_settings.metadata_key_namespace.as_deref();
+            let schema = commons::schema::graph_schema(namespace);
+            Arc::new(serde_json::to_string_pretty(&schema)?)
+        },
     };
 
     let start_timestamp = chrono::Utc::now();
     PROCESS_START_TIME.set(start_timestamp.timestamp());
+    let build_info = buildinfo::BuildInfo::current();
+    BUILD_INFO
+        .with_label_values(&[
+            build_info.version,
+            build_info.commit,
+            build_info.rustc_version,
+        ])
+        .set(1);
     info!("starting server ({} {})", crate_name!(), crate_version!());
 
     // Graph-builder main service.
-    let service_socket = service_settings.socket_addr();
+    let service_socket = cli_opts
+        .listen
+        .map(listen::ListenAddr::Tcp)
+        .unwrap_or_else(|| service_settings.listen_addr());
     debug!("main service address: {}", service_socket);
     let gb_service = service_state.clone();
-    actix_web::HttpServer::new(move || {
+    let service_workers = service_settings.workers;
+    let service_connections = service_settings.connections.clone();
+    let service_metrics = service_settings.serve_metrics;
+    let mut service_server = actix_web::HttpServer::new(move || {
         App::new()
             .wrap(commons::web::build_cors_middleware(
-                &service_settings.origin_allowlist,
+                &service_settings.cors_origins(),
             ))
+            .wrap_fn(record_http_request)
             .data(gb_service.clone())
             .route("/v1/graph", web::get().to(gb_serve_graph))
-    })
-    .bind(service_socket)?
-    .run();
+            .route("/v1/graph", web::head().to(gb_serve_graph))
+            .route("/v1/graph/diff", web::get().to(gb_serve_graph_diff))
+            .route("/v1/barriers", web::get().to(gb_serve_barriers))
+            .route("/v1/schema", web::get().to(gb_serve_schema))
+            .configure(|cfg| {
+                if service_metrics {
+                    cfg.route("/metrics", web::get().to(metrics::serve_metrics));
+                }
+            })
+    });
+    if let Some(workers) = service_workers {
+        service_server = service_server.workers(workers);
+    }
+    if let Some(secs) = service_connections.keep_alive_secs {
+        service_server = service_server.keep_alive(secs);
+    }
+    if let Some(ms) = service_connections.client_timeout_ms {
+        service_server = service_server.client_timeout(ms);
+    }
+    if let Some(max) = service_connections.max_connections {
+        service_server = service_server.maxconn(max);
+    }
+    service_server = match service_socket {
+        listen::ListenAddr::Tcp(addr) => service_server.bind(addr)?,
+        listen::ListenAddr::Unix(path) => {
+            listen::remove_stale_socket(&path)?;
+            service_server.bind_uds(path)?
+        }
+    };
+    service_server.run();
 
     // Graph-builder status service.
-    let status_socket = status_settings.socket_addr();
+    //
+    // Metrics are still collected when it is disabled, and can be served
+    // by the main service instead.
+    let pushgateway = status_settings.pushgateway.clone();
+    if status_settings.enabled {
+        run_status_server(&cli_opts, status_settings, service_state)?;
+    } else {
+        debug!("status service disabled");
+    }
+
+    sys.run()?;
+
+    // Push final metrics, once gracefully stopped (e.g. on SIGTERM).
+    if let Some(pushgateway) = pushgateway {
+        let url = pushgateway.url.clone();
+        let push = async move { pushgateway::push_metrics(&pushgateway).await };
+        match actix::System::new("fcos_cincinnati_gb_push").block_on(push) {
+            Ok(_) => info!("pushed final metrics to {}", url),
+            Err(e) => log::error!("failed to push metrics to {}: {}", url, e),
+        }
+    }
+    Ok(())
+}
+
+/// Start the status server (metrics and admin endpoints).
+fn run_status_server(
+    cli_opts: &cli::CliOptions,
+    status_settings: settings::StatusSettings,
+    gb_status: AppState,
+) -> Fallible<()> {
+    let status_socket = cli_opts
+        .status_listen
+        .map(listen::ListenAddr::Tcp)
+        .unwrap_or_else(|| status_settings.listen_addr());
     debug!("status service address: {}", status_socket);
-    let gb_status = service_state;
-    actix_web::HttpServer::new(move || {
+    let debug_upstream = status_settings.debug_upstream;
+    let debug_full_graph = status_settings.debug_full_graph;
+    let mut status_server = actix_web::HttpServer::new(move || {
+        let admin_enabled = gb_status.admin_token.is_some();
         App::new()
+            .wrap_fn(record_http_request)
             .data(gb_status.clone())
             .route("/metrics", web::get().to(metrics::serve_metrics))
-    })
-    .bind(status_socket)?
-    .run();
+            .configure(|cfg| {
+                if admin_enabled {
+                    cfg.route("/admin/refresh", web::post().to(gb_admin_refresh));
+                }
+                if debug_upstream {
+                    cfg.route("/debug/upstream", web::get().to(gb_debug_upstream));
+                }
+                if debug_full_graph {
+                    cfg.route("/debug/full-graph", web::get().to(gb_debug_full_graph));
+                }
+            })
+    });
+    if let Some(workers) = status_settings.workers {
+        status_server = status_server.workers(workers);
+    }
+    let connections = &status_settings.connections;
+    if let Some(secs) = connections.keep_alive_secs {
+        status_server = status_server.keep_alive(secs);
+    }
+    if let Some(ms) = connections.client_timeout_ms {
+        status_server = status_server.client_timeout(ms);
+    }
+    if let Some(max) = connections.max_connections {
+        status_server = status_server.maxconn(max);
+    }
+    status_server = match status_socket {
+        listen::ListenAddr::Tcp(addr) => status_server.bind(addr)?,
+        listen::ListenAddr::Unix(path) => {
+            listen::remove_stale_socket(&path)?;
+            status_server.bind_uds(path)?
+        }
+    };
+    status_server.run();
+    Ok(())
+}
 
-    sys.run()?;
+/// Middleware recording generic HTTP metrics for all requests.
+///
+/// Requests are labeled by route, with a catch-all for unknown paths and
+/// methods, to keep metrics cardinality bounded. All routes are static, so
+/// a route is the same as the path of a matching request.
+fn record_http_request<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl std::future::Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    use actix_web::http::Method;
+
+    let method = match *req.method() {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::OPTIONS
+        | Method::PATCH => req.method().as_str().to_string(),
+        _ => "other".to_string(),
+    };
+    let path = if req.resource_map().has_resource(req.path()) {
+        req.path().to_string()
+    } else {
+        "unmatched".to_string()
+    };
+
+    let fut = srv.call(req);
+    async move {
+        let res = fut.await;
+        let status = match &res {
+            Ok(resp) => resp.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        HTTP_REQUESTS
+            .with_label_values(&[&method, &path, status.as_str()])
+            .inc();
+        res
+    }
+}
+
+/// Run a CLI subcommand, instead of the service.
+fn run_command(command: &cli::Command) -> Fallible<()> {
+    match command {
+        cli::Command::Version { json } => {
+            let build_info = buildinfo::BuildInfo::current();
+            if *json {
+                println!("{}", serde_json::to_string(&build_info)?);
+            } else {
+                println!("{}", build_info);
+            }
+        }
+        cli::Command::Validate {
+            file,
+            required_keys,
+            key_namespace,
+        } => {
+            let content = std::fs::read(file)
+                .map_err(|e| failure::format_err!("failed to read '{}': {}", file.display(), e))?;
+            let graph: graph::Graph = serde_json::from_slice(&content)
+                .map_err(|e| failure::format_err!("invalid graph '{}': {}", file.display(), e))?;
+
+            // Assembled graphs always carry these keys, possibly namespaced.
+            let default_prefix = format!("{}.", commons::metadata::KEY_NAMESPACE);
+            let builtin = [commons::metadata::AGE_INDEX, commons::metadata::SCHEME];
+            let mut keys: Vec<String> = builtin
+                .iter()
+                .map(
+                    |key| match (key_namespace, key.strip_prefix(&default_prefix)) {
+                        (Some(ns), Some(name)) => format!("{}.{}", ns, name),
+                        _ => key.to_string(),
+                    },
+                )
+                .collect();
+            keys.extend(required_keys.iter().cloned());
+            let violations = graph.violations(&keys);
+            for violation in &violations {
+                eprintln!("{}", violation);
+            }
+            failure::ensure!(
+                violations.is_empty(),
+                "graph '{}' has {} violations",
+                file.display(),
+                violations.len()
+            );
+            println!(
+                "graph '{}' is valid ({} nodes, {} edges)",
+                file.display(),
+                graph.nodes.len(),
+                graph.edges.len()
+            );
+        }
+    }
     Ok(())
 }
 
@@ -131,6 +559,171 @@ fn main() -> Fallible<()> {
 pub(crate) struct AppState {
     scope_filter: Option<HashSet<graph::GraphScope>>,
     scrapers: HashMap<String, Addr<scraper::Scraper>>,
+    /// Current graphs of each stream, served without going through scrapers.
+    graphs: HashMap<String, scraper::GraphStore>,
+    unknown_scope_delay: Option<std::time::Duration>,
+    /// Duration above which graph requests are logged as slow, if any.
+    slow_request_threshold: Option<std::time::Duration>,
+    /// Compression level for gzip-encoded graphs.
+    gzip_level: u32,
+    /// Per-client rate limiter, if enabled.
+    rate_limiter: Option<Arc<ratelimit::RateLimiter>>,
+    /// Networks of proxies trusted for `X-Forwarded-*` headers.
+    trusted_proxies: Arc<Vec<commons::web::IpNetwork>>,
+    /// Bearer token for admin endpoints, which are disabled if unset.
+    admin_token: Option<String>,
+    /// Scope for graph requests without any scope parameters, if any.
+    default_scope: Option<graph::GraphScope>,
+    /// stream --> minimum client version required to fetch its graphs
+    min_client_versions: Arc<BTreeMap<String, ClientVersion>>,
+    /// basearch --> basearch to serve instead, if it has no graph
+    fallback_basearch: Arc<BTreeMap<String, String>>,
+    /// Origins allowed for CORS requests, or all of them if unset.
+    origin_allowlist: Option<Arc<Vec<String>>>,
+    /// stream --> origins allowed for CORS graph requests, instead of the global ones
+    stream_origin_allowlist: Arc<BTreeMap<String, Vec<String>>>,
+    /// Whether to serve empty graphs, during maintenance.
+    maintenance: Arc<AtomicBool>,
+    /// JSON Schema of served graphs, for the metadata key namespace at startup.
+    graph_schema: Arc<String>,
+}
+
+impl AppState {
+    /// Get a cached graph.
+    fn cached_graph(
+        &self,
+        scope: &graph::GraphScope,
+        variant: scraper::GraphVariant,
+    ) -> Result<scraper::CachedGraphResult, failure::Error> {
+        match self.graphs.get(&scope.stream) {
+            None => Ok(scraper::CachedGraphResult::UnknownScope),
+            Some(store) => store.cached_graph(scope, variant),
+        }
+    }
+
+    /// Handle a request for a scope without a configured scraper.
+    async fn reject_unknown_scope(&self, scope: &graph::GraphScope) -> HttpResponse {
+        log::error!(
+            target: UNKNOWN_SCOPE_LOG_TARGET,
+            "no scraper configured for scope: basearch='{}', stream='{}'",
+            scope.basearch,
+            scope.stream,
+        );
+        if let Some(delay) = self.unknown_scope_delay {
+            actix::clock::delay_for(delay).await;
+        }
+        json_error(
+            StatusCode::NOT_FOUND,
+            "unknown_scope",
+            format!(
+                "no graph available for basearch '{}', stream '{}'",
+                scope.basearch, scope.stream
+            ),
+        )
+    }
+
+    /// Reject clients older than the minimum version configured for a stream.
+    fn check_client_version(
+        &self,
+        req: &HttpRequest,
+        client_version: Option<&str>,
+        stream: &str,
+    ) -> Option<HttpResponse> {
+        let min_version = self.min_client_versions.get(stream)?;
+        let reported = client_version.or_else(|| {
+            req.headers()
+                .get("X-Client-Version")
+                .and_then(|v| v.to_str().ok())
+        });
+        let version = reported.and_then(|v| v.parse::<ClientVersion>().ok());
+        if matches!(&version, Some(v) if v >= min_version) {
+            return None;
+        }
+
+        let resp = json_error(
+            StatusCode::UPGRADE_REQUIRED,
+            "client_too_old",
+            format!(
+                "stream '{}' requires client version {} or newer",
+                stream, min_version
+            ),
+        );
+        Some(resp)
+    }
+
+    /// Whether the `Origin` of a request is allowed for a stream.
+    ///
+    /// Requests without an `Origin` (i.e. not from browsers) are always allowed.
+    fn is_origin_allowed(&self, req: &HttpRequest, stream: &str) -> bool {
+        let origin = match req.headers().get("Origin") {
+            Some(origin) => origin,
+            None => return true,
+        };
+        let allowlist = match self.stream_origin_allowlist.get(stream) {
+            Some(origins) => origins,
+            None => match &self.origin_allowlist {
+                Some(origins) => origins,
+                None => return true,
+            },
+        };
+        origin
+            .to_str()
+            .map(|origin| allowlist.iter().any(|o| o == origin))
+            .unwrap_or(false)
+    }
+
+    /// Check the `Origin` of a stream request against the allowlist for its stream.
+    fn check_origin(&self, req: &HttpRequest, stream: &str) -> Option<HttpResponse> {
+        if self.is_origin_allowed(req, stream) {
+            return None;
+        }
+
+        let resp = json_error(
+            StatusCode::FORBIDDEN,
+            "origin_not_allowed",
+            format!("origin not allowed for stream '{}'", stream),
+        );
+        Some(resp)
+    }
+
+    /// Check whether a request carries the admin bearer token.
+    fn is_admin(&self, req: &HttpRequest) -> bool {
+        let expected = match &self.admin_token {
+            Some(t) => t.as_bytes(),
+            None => return false,
+        };
+        let provided = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::as_bytes)
+            .unwrap_or_default();
+
+        // Compare in constant time (for a given length), to avoid leaking the token.
+        provided.len() == expected.len()
+            && provided
+                .iter()
+                .zip(expected)
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    /// Check whether a request exceeds its client rate limit, returning the rejection if so.
+    fn check_rate_limit(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        let limiter = self.rate_limiter.as_ref()?;
+        let client = commons::web::client_ip(req, &self.trusted_proxies)?;
+        let wait = limiter.check(client).err()?;
+
+        RATE_LIMITED_REQUESTS.inc();
+        log::debug!("rate limiting graph request from client {}", client);
+        // Round up, so that clients do not retry too early.
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        let resp = HttpResponse::TooManyRequests()
+            .header("Retry-After", retry_after.to_string())
+            .finish();
+        Some(resp)
+    }
 }
 
 /// Mandatory parameters for querying a graph from graph-builder.
@@ -139,25 +732,143 @@ struct GraphQuery {
     basearch: Option<String>,
     stream: Option<String>,
     oci: Option<bool>,
+    /// Update scheme (`oci` or `checksum`), alternatively to `oci`.
+    scheme: Option<String>,
+    /// Serialization format (`pretty`, `compact`, `versions` or `weighted`).
+    format: Option<String>,
+    /// Client version, alternatively reported via the `X-Client-Version` header.
+    client_version: Option<String>,
+    /// Rollouts handling (`include` or `exclude`).
+    rollouts: Option<String>,
+    /// Comma-separated node fields to serialize (`version`, `metadata`, `payload`).
+    fields: Option<String>,
+    /// Whether to add top-level provenance fields (`generated_at`, `upstream_schema_version`).
+    provenance: Option<bool>,
 }
 
 pub(crate) async fn gb_serve_graph(
+    req: HttpRequest,
     data: web::Data<AppState>,
     web::Query(query): web::Query<GraphQuery>,
 ) -> Result<HttpResponse, failure::Error> {
-    let scope = match commons::web::validate_scope(
-        query.basearch,
-        query.stream,
-        query.oci,
-        &data.scope_filter,
-    ) {
+    let _inflight = InflightGuard::new(&INFLIGHT_REQUESTS);
+    let start = std::time::Instant::now();
+    let request_id = request_id(&req);
+    let slow_request_threshold = data.slow_request_threshold;
+    let mut served_scope = None;
+    let result = serve_graph(&req, data, query, &request_id, &mut served_scope).await;
+    let elapsed = start.elapsed();
+    if matches!(slow_request_threshold, Some(threshold) if elapsed > threshold) {
+        let scope = served_scope
+            .map(|s| {
+                format!(
+                    "basearch='{}', stream='{}', oci='{}'",
+                    s.basearch, s.stream, s.oci
+                )
+            })
+            .unwrap_or_else(|| "unresolved scope".to_string());
+        log::warn!(
+            target: APP_LOG_TARGET,
+            "[{}] slow graph request for {}: {}ms",
+            request_id,
+            scope,
+            elapsed.as_millis()
+        );
+    }
+    let mut resp = result?;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        resp.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(resp)
+}
+
+/// Guard tracking an in-flight request in a gauge, for as long as it lives.
+///
+/// This covers all exit paths, including early returns and dropped futures.
+struct InflightGuard<'a> {
+    gauge: &'a IntGauge,
+}
+
+impl<'a> InflightGuard<'a> {
+    fn new(gauge: &'a IntGauge) -> Self {
+        gauge.inc();
+        Self { gauge }
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Return the request-id for a request, generating one if absent or invalid.
+fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars().all(|c| c.is_ascii_graphic())
+        })
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// Serve a graph request, recording in `resolved_scope` the scope actually
+/// served (after defaults and basearch fallback), once known.
+async fn serve_graph(
+    req: &HttpRequest,
+    data: web::Data<AppState>,
+    query: GraphQuery,
+    request_id: &str,
+    resolved_scope: &mut Option<graph::GraphScope>,
+) -> Result<HttpResponse, failure::Error> {
+    if let Some(rejection) = data.check_rate_limit(req) {
+        log::debug!(target: APP_LOG_TARGET, "[{}] request rate-limited", request_id);
+        return Ok(rejection);
+    }
+
+    let oci = match commons::web::resolve_oci(query.oci, query.scheme.as_deref()) {
+        Ok(oci) => oci,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_scheme", e)),
+    };
+    let (mut basearch, mut stream, mut oci) = (query.basearch, query.stream, oci);
+    if let (None, None, None, Some(default)) = (&basearch, &stream, &oci, &data.default_scope) {
+        log::debug!(
+            target: APP_LOG_TARGET,
+            "[{}] no scope requested, using default: basearch='{}', stream='{}', oci='{}'",
+            request_id,
+            default.basearch,
+            default.stream,
+            default.oci,
+        );
+        basearch = Some(default.basearch.clone());
+        stream = Some(default.stream.clone());
+        oci = Some(default.oci);
+    }
+
+    let scope = match commons::web::validate_scope(basearch, stream, oci, &data.scope_filter) {
         Err(e) => {
-            log::error!("graph request with invalid scope: {}", e);
-            return Ok(HttpResponse::BadRequest().finish());
+            log::error!(
+                target: APP_LOG_TARGET,
+                "[{}] graph request with invalid scope: {}",
+                request_id,
+                e
+            );
+            return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_scope", e));
         }
         Ok(s) => {
             log::trace!(
-                "serving request for valid scope: basearch='{}', stream='{}', oci='{}'",
+                target: APP_LOG_TARGET,
+                "[{}] serving {} request from {} for valid scope: basearch='{}', stream='{}', oci='{}'",
+                request_id,
+                commons::web::client_scheme(req, &data.trusted_proxies),
+                commons::web::client_ip(req, &data.trusted_proxies)
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "unknown client".to_string()),
                 s.basearch,
                 s.stream,
                 s.oci,
@@ -165,23 +876,686 @@ pub(crate) async fn gb_serve_graph(
             s
         }
     };
+    *resolved_scope = Some(scope.clone());
 
-    let addr = match data.scrapers.get(&scope.stream) {
-        None => {
+    if let Some(rejection) = data.check_origin(req, &scope.stream) {
+        log::debug!(target: APP_LOG_TARGET, "[{}] origin not allowed", request_id);
+        return Ok(rejection);
+    }
+    if let Some(rejection) =
+        data.check_client_version(req, query.client_version.as_deref(), &scope.stream)
+    {
+        log::debug!(target: APP_LOG_TARGET, "[{}] client too old", request_id);
+        return Ok(rejection);
+    }
+
+    let mut variant = scraper::GraphVariant::default();
+    if let Some(format) = &query.format {
+        variant.format = match format.parse() {
+            Ok(f) => f,
+            Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_format", e)),
+        };
+    }
+    match query.rollouts.as_deref() {
+        None | Some("include") => {}
+        Some("exclude") => variant.exclude_rollouts = true,
+        Some(other) => {
+            let msg = format!("unknown rollouts mode '{}'", other);
+            return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_rollouts", msg));
+        }
+    }
+    // An explicitly requested format takes precedence over content negotiation.
+    if query.format.is_none() && commons::web::accepts(req, graph::CINCINNATI_MEDIA_TYPE) {
+        variant.format = scraper::GraphFormat::Cincinnati;
+    }
+    let json_graph = matches!(
+        variant.format,
+        scraper::GraphFormat::Pretty | scraper::GraphFormat::Compact
+    );
+    if let Some(fields) = &query.fields {
+        variant.fields = match fields.parse() {
+            Ok(f) => Some(f),
+            Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_fields", e)),
+        };
+        if !json_graph {
+            let msg = "node fields can only be selected for JSON graphs";
+            return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_fields", msg));
+        }
+    }
+    if query.provenance == Some(true) {
+        if !json_graph {
+            let msg = "provenance is only available for JSON graphs";
+            return Ok(json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_provenance",
+                msg,
+            ));
+        }
+        variant.provenance = true;
+    }
+    if commons::web::accepts_encoding(req, "gzip") {
+        variant.gzip_level = Some(data.gzip_level);
+    }
+    let content_type = variant.format.content_type();
+    let gzipped = variant.gzip_level.is_some();
+
+    // During maintenance, serve a well-formed empty graph for any scope,
+    // so that clients gracefully find no updates.
+    let maintenance = data.maintenance.load(Ordering::Relaxed);
+    let mut served_scope = scope.clone();
+    let mut cached = if maintenance {
+        let provenance = scraper::Provenance::default();
+        variant
+            .render(&graph::Graph::default(), None, &provenance)
+            .map(|data| {
+                scraper::CachedGraphResult::Graph(scraper::CachedGraph {
+                    etag: scraper::graph_etag(&data),
+                    data,
+                    last_modified: None,
+                    signature: None,
+                })
+            })
+    } else {
+        data.cached_graph(&scope, variant.clone())
+    };
+    if let (Ok(scraper::CachedGraphResult::UnknownScope), Some(fallback)) =
+        (&cached, data.fallback_basearch.get(&scope.basearch))
+    {
+        log::debug!(
+            target: APP_LOG_TARGET,
+            "[{}] no graph for basearch '{}', falling back to '{}'",
+            request_id,
+            scope.basearch,
+            fallback
+        );
+        served_scope.basearch = fallback.clone();
+        *resolved_scope = Some(served_scope.clone());
+        cached = data.cached_graph(&served_scope, variant);
+    }
+    let cached_graph = match cached {
+        Ok(scraper::CachedGraphResult::Graph(graph)) => graph,
+        Ok(scraper::CachedGraphResult::UnknownScope) => {
+            log::debug!(target: APP_LOG_TARGET, "[{}] unknown scope", request_id);
+            return Ok(data.reject_unknown_scope(&scope).await);
+        }
+        Ok(scraper::CachedGraphResult::NotReady) => {
+            log::debug!(target: APP_LOG_TARGET, "[{}] graph not ready", request_id);
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not_ready",
+                "no graph available yet for this scope",
+            ));
+        }
+        Err(e) => {
             log::error!(
-                "no scraper configured for scope: basearch='{}', stream='{}'",
-                scope.basearch,
-                scope.stream,
+                target: APP_LOG_TARGET,
+                "[{}] failed to get cached graph: {}",
+                request_id,
+                e
             );
-            return Ok(HttpResponse::NotFound().finish());
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "graph temporarily unavailable",
+            ));
         }
+    };
+
+    let etag = cached_graph.etag;
+    let last_modified = cached_graph
+        .last_modified
+        .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+
+    // As per RFC 7232, `If-Modified-Since` is only evaluated when there is
+    // no `If-None-Match`.
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let condition = match (header("If-None-Match"), header("If-Modified-Since")) {
+        (Some(if_none_match), _) => Some((
+            "if_none_match",
+            commons::web::etag_matches(if_none_match, &etag),
+        )),
+        (None, Some(if_modified_since)) => Some((
+            "if_modified_since",
+            cached_graph.last_modified.map_or(false, |t| {
+                commons::web::not_modified_since(if_modified_since, t)
+            }),
+        )),
+        (None, None) => None,
+    };
+    if let Some((condition, not_modified)) = condition {
+        if not_modified {
+            CONDITIONAL_REQUESTS
+                .with_label_values(&[condition, "304"])
+                .inc();
+            let mut resp = HttpResponse::NotModified();
+            resp.header("ETag", etag);
+            if let Some(last_modified) = last_modified {
+                resp.header("Last-Modified", last_modified);
+            }
+            return Ok(resp.finish());
+        }
+        CONDITIONAL_REQUESTS
+            .with_label_values(&[condition, "200"])
+            .inc();
+    }
+
+    // The graph is pre-serialized by the scraper and shared (refcounted)
+    // across requests, so no per-request copy of the body is made. It is
+    // always served as a sized body (with an explicit `Content-Length`) and
+    // an ETag computed over exactly these bytes, never chunked.
+    //
+    // This also serves HEAD requests, for which the body is omitted on the
+    // wire while the headers (including `Content-Length`) are kept.
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(content_type)
+        .header("ETag", etag)
+        .header("Vary", "Accept, Accept-Encoding")
+        .header("X-Graph-Stream", served_scope.stream)
+        .header("X-Graph-Basearch", served_scope.basearch.as_str())
+        .header("X-Graph-Oci", served_scope.oci.to_string())
+        .no_chunking();
+    if served_scope.basearch != scope.basearch {
+        resp.header("X-Graph-Fallback-From", scope.basearch);
+    }
+    if gzipped {
+        resp.header("Content-Encoding", "gzip");
+    }
+    if maintenance {
+        resp.header("X-Graph-Maintenance", "true");
+    }
+    if let Some(last_modified) = last_modified {
+        resp.header("Last-Modified", last_modified);
+    }
+    if let Some(signature) = cached_graph.signature {
+        resp.header("X-Graph-Signature", signature);
+    }
+    Ok(resp.body(cached_graph.data))
+}
+
+/// Parameters for querying changes to a graph from graph-builder.
+#[derive(Deserialize)]
+struct GraphDiffQuery {
+    basearch: Option<String>,
+    stream: Option<String>,
+    oci: Option<bool>,
+    /// Digest of the baseline graph, or RFC 3339 time at which it was current
+    /// (defaults to the previous refresh).
+    since: Option<String>,
+}
+
+pub(crate) async fn gb_serve_graph_diff(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    web::Query(query): web::Query<GraphDiffQuery>,
+) -> Result<HttpResponse, failure::Error> {
+    let scope = match commons::web::validate_scope(
+        query.basearch,
+        query.stream,
+        query.oci,
+        &data.scope_filter,
+    ) {
+        Err(e) => {
+            log::error!("graph diff request with invalid scope: {}", e);
+            return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_scope", e));
+        }
+        Ok(s) => s,
+    };
+
+    if let Some(rejection) = data.check_origin(&req, &scope.stream) {
+        return Ok(rejection);
+    }
+    let addr = match data.scrapers.get(&scope.stream) {
+        None => return Ok(data.reject_unknown_scope(&scope).await),
         Some(addr) => addr,
     };
 
-    let graph_json_bytes = addr.send(scraper::GetCachedGraph { scope }).await??;
+    let since = match query.since.as_deref().map(str::parse).transpose() {
+        Ok(since) => since,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_since", e)),
+    };
+    let msg = scraper::GetGraphDiff {
+        scope: scope.clone(),
+        since,
+    };
+    let diff = match addr.send(msg).await?? {
+        scraper::GraphDiffResult::Diff(d) => d,
+        scraper::GraphDiffResult::UnknownScope => {
+            return Ok(data.reject_unknown_scope(&scope).await);
+        }
+        scraper::GraphDiffResult::NotReady => {
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not_ready",
+                "no graph available yet for this scope",
+            ));
+        }
+        scraper::GraphDiffResult::UnknownDigest => {
+            log::debug!("graph diff request with unknown baseline digest");
+            return Ok(json_error(
+                StatusCode::NOT_FOUND,
+                "unknown_digest",
+                "baseline digest is not in retained history",
+            ));
+        }
+        scraper::GraphDiffResult::TooOld(oldest) => {
+            let msg = format!(
+                "baseline time is older than retained history, oldest available snapshot is from {}",
+                oldest.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            );
+            return Ok(json_error(
+                StatusCode::BAD_REQUEST,
+                "history_unavailable",
+                msg,
+            ));
+        }
+    };
 
+    let json = serde_json::to_string_pretty(&diff)?;
     let resp = HttpResponse::Ok()
         .content_type("application/json")
-        .body(graph_json_bytes);
+        .body(json);
     Ok(resp)
 }
+
+/// Barriers across all configured scopes.
+#[derive(Serialize)]
+struct BarriersResponse {
+    scopes: Vec<scraper::ScopeBarriers>,
+}
+
+pub(crate) async fn gb_serve_barriers(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, failure::Error> {
+    // Only streams allowed for the requesting origin.
+    let mut streams: Vec<_> = data
+        .scrapers
+        .iter()
+        .filter(|(stream, _)| data.is_origin_allowed(&req, stream))
+        .collect();
+    streams.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut scopes = vec![];
+    for (_stream, addr) in streams {
+        scopes.extend(addr.send(scraper::GetBarriers {}).await?);
+    }
+
+    let json = serde_json::to_string_pretty(&BarriersResponse { scopes })?;
+    let resp = HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json);
+    Ok(resp)
+}
+
+/// Serve the JSON Schema of graphs.
+pub(crate) async fn gb_serve_schema(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(commons::schema::SCHEMA_MEDIA_TYPE)
+        .body(data.graph_schema.as_str().to_owned())
+}
+
+#[derive(Deserialize)]
+struct DebugUpstreamQuery {
+    stream: String,
+    /// Upstream document (`release-index` or `updates`).
+    document: String,
+}
+
+/// Serve the last fetched upstream metadata for a stream, as is.
+///
+/// Upstream metadata is per stream, covering all basearches.
+pub(crate) async fn gb_debug_upstream(
+    data: web::Data<AppState>,
+    web::Query(query): web::Query<DebugUpstreamQuery>,
+) -> Result<HttpResponse, failure::Error> {
+    let document = match query.document.parse() {
+        Ok(d) => d,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_document", e)),
+    };
+    let addr = match data.scrapers.get(&query.stream) {
+        Some(addr) => addr,
+        None => {
+            let msg = format!("unknown stream '{}'", query.stream);
+            return Ok(json_error(StatusCode::NOT_FOUND, "unknown_stream", msg));
+        }
+    };
+
+    let raw = match addr.send(scraper::GetRawUpstream { document }).await? {
+        Some(raw) => raw,
+        None => {
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "upstream metadata not fetched yet",
+            ))
+        }
+    };
+    let mut resp = HttpResponse::Ok();
+    resp.content_type("application/json");
+    if let Some(etag) = raw.etag {
+        resp.header("X-Upstream-ETag", etag);
+    }
+    Ok(resp.body(raw.data))
+}
+
+/// Parameters for full graph debugging requests.
+#[derive(Deserialize)]
+struct DebugFullGraphQuery {
+    basearch: Option<String>,
+    stream: Option<String>,
+    oci: Option<bool>,
+}
+
+/// Serve the last assembled graph for a scope, including edges out of dead-ends.
+pub(crate) async fn gb_debug_full_graph(
+    data: web::Data<AppState>,
+    web::Query(query): web::Query<DebugFullGraphQuery>,
+) -> Result<HttpResponse, failure::Error> {
+    let scope = match commons::web::validate_scope(
+        query.basearch,
+        query.stream,
+        query.oci,
+        &data.scope_filter,
+    ) {
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid_scope", e)),
+        Ok(s) => s,
+    };
+    let addr = match data.scrapers.get(&scope.stream) {
+        Some(addr) => addr,
+        None => {
+            let msg = format!("unknown stream '{}'", scope.stream);
+            return Ok(json_error(StatusCode::NOT_FOUND, "unknown_stream", msg));
+        }
+    };
+
+    let data = match addr.send(scraper::GetFullGraph { scope }).await?? {
+        Some(data) => data,
+        None => {
+            return Ok(json_error(
+                StatusCode::NOT_FOUND,
+                "unavailable",
+                "no graph assembled for this scope",
+            ))
+        }
+    };
+    let resp = HttpResponse::Ok()
+        .content_type("application/json")
+        .body(data);
+    Ok(resp)
+}
+
+/// Parameters for admin refresh requests.
+#[derive(Deserialize)]
+struct AdminRefreshQuery {
+    stream: String,
+}
+
+pub(crate) async fn gb_admin_refresh(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    web::Query(query): web::Query<AdminRefreshQuery>,
+) -> Result<HttpResponse, failure::Error> {
+    if !data.is_admin(&req) {
+        log::warn!("unauthorized admin refresh request");
+        return Ok(json_error(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "missing or invalid admin token",
+        ));
+    }
+
+    let addr = match data.scrapers.get(&query.stream) {
+        Some(addr) => addr,
+        None => {
+            let msg = format!("unknown stream '{}'", query.stream);
+            return Ok(json_error(StatusCode::NOT_FOUND, "unknown_stream", msg));
+        }
+    };
+
+    let resp = match addr.send(scraper::RefreshNow {}).await? {
+        Ok(scraper::RefreshNowResult::Refreshed) => HttpResponse::Ok().finish(),
+        Ok(scraper::RefreshNowResult::Frozen) => json_error(
+            StatusCode::CONFLICT,
+            "frozen",
+            format!("refreshes for stream '{}' are frozen", query.stream),
+        ),
+        Ok(scraper::RefreshNowResult::InProgress) => json_error(
+            StatusCode::CONFLICT,
+            "refresh_in_progress",
+            format!("a refresh for stream '{}' is in progress", query.stream),
+        ),
+        Err(e) => {
+            log::error!(
+                "on-demand refresh for stream '{}' failed: {}",
+                query.stream,
+                e
+            );
+            json_error(StatusCode::BAD_GATEWAY, "refresh_failed", e)
+        }
+    };
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::Body;
+    use actix_web::test::{self, TestRequest};
+
+    fn stable_scope(basearch: &str) -> graph::GraphScope {
+        graph::GraphScope {
+            basearch: basearch.to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        }
+    }
+
+    /// Application state serving a single-release graph for stable/x86_64.
+    fn test_state() -> AppState {
+        let node = graph::CincinnatiPayload {
+            version: "1".to_string(),
+            metadata: HashMap::new(),
+            payload: "payload-1".to_string(),
+        };
+        let graph = graph::Graph {
+            nodes: vec![node],
+            edges: vec![],
+        };
+        let store = scraper::GraphStore::with_graph(stable_scope("x86_64"), graph).unwrap();
+        AppState {
+            scope_filter: None,
+            scrapers: HashMap::new(),
+            graphs: maplit::hashmap! { "stable".to_string() => store },
+            unknown_scope_delay: None,
+            slow_request_threshold: None,
+            gzip_level: 1,
+            rate_limiter: None,
+            trusted_proxies: Arc::new(vec![]),
+            admin_token: None,
+            default_scope: None,
+            min_client_versions: Arc::new(BTreeMap::new()),
+            fallback_basearch: Arc::new(BTreeMap::new()),
+            origin_allowlist: None,
+            stream_origin_allowlist: Arc::new(BTreeMap::new()),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            graph_schema: Arc::new(String::new()),
+        }
+    }
+
+    /// Serve a single request with the given state.
+    fn call(state: &AppState, req: TestRequest) -> ServiceResponse<Body> {
+        let state = state.clone();
+        let mut sys = actix::System::new("test");
+        sys.block_on(async move {
+            let mut app = test::init_service(
+                App::new()
+                    .data(state)
+                    .route("/v1/graph", web::get().to(gb_serve_graph))
+                    .route("/admin/refresh", web::post().to(gb_admin_refresh)),
+            )
+            .await;
+            test::call_service(&mut app, req.to_request()).await
+        })
+    }
+
+    fn graph_request(basearch: &str) -> TestRequest {
+        let uri = format!("/v1/graph?basearch={}&stream=stable&oci=false", basearch);
+        TestRequest::get().uri(&uri)
+    }
+
+    fn header<'a>(resp: &'a ServiceResponse<Body>, name: &str) -> Option<&'a str> {
+        resp.headers().get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[test]
+    fn test_client_version() {
+        let mut state = test_state();
+        let min_version = "1.2.0".parse().unwrap();
+        state.min_client_versions = Arc::new(maplit::btreemap! {
+            "stable".to_string() => min_version,
+        });
+
+        let resp = call(&state, graph_request("x86_64"));
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("X-Client-Version", "1.1.9"),
+        );
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("X-Client-Version", "1.2.0"),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_origin_allowlist() {
+        let mut state = test_state();
+        let allowed = vec!["https://allowed.example.com".to_string()];
+        state.origin_allowlist = Some(Arc::new(allowed));
+
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("Origin", "https://other.example.com"),
+        );
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("Origin", "https://allowed.example.com"),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Requests not from browsers are always allowed.
+        let resp = call(&state, graph_request("x86_64"));
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_admin_refresh_auth() {
+        let mut state = test_state();
+        state.admin_token = Some("secret".to_string());
+        let refresh = || TestRequest::post().uri("/admin/refresh?stream=stable");
+
+        let resp = call(&state, refresh());
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = call(&state, refresh().header("Authorization", "Bearer secreT"));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = call(&state, refresh().header("Authorization", "secret"));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Authorized, but there is no scraper for the stream in this state.
+        let resp = call(&state, refresh().header("Authorization", "Bearer secret"));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let state = test_state();
+        let resp = call(&state, graph_request("x86_64"));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = header(&resp, "ETag").unwrap().to_string();
+        let last_modified = header(&resp, "Last-Modified").unwrap().to_string();
+
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("If-None-Match", etag.as_str()),
+        );
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&resp, "ETag"), Some(etag.as_str()));
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("If-None-Match", "\"stale\""),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("If-Modified-Since", last_modified.as_str()),
+        );
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let resp = call(
+            &state,
+            graph_request("x86_64").header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // `If-None-Match` takes precedence.
+        let resp = call(
+            &state,
+            graph_request("x86_64")
+                .header("If-None-Match", "\"stale\"")
+                .header("If-Modified-Since", last_modified.as_str()),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_format_negotiation() {
+        let state = test_state();
+        let cincinnati = || graph_request("x86_64").header("Accept", graph::CINCINNATI_MEDIA_TYPE);
+        let resp = call(&state, cincinnati());
+        assert_eq!(
+            header(&resp, "Content-Type"),
+            Some(graph::CINCINNATI_MEDIA_TYPE)
+        );
+
+        // Explicit formats take precedence.
+        let uri = "/v1/graph?basearch=x86_64&stream=stable&oci=false&format=versions";
+        let resp = call(&state, cincinnati().uri(uri));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let mut state = test_state();
+        state.graphs.clear();
+        let resp = call(&state, graph_request("x86_64"));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Any scope gets an empty graph.
+        state.maintenance.store(true, Ordering::Relaxed);
+        let resp = call(&state, graph_request("x86_64"));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "X-Graph-Maintenance"), Some("true"));
+    }
+
+    #[test]
+    fn test_fallback_basearch() {
+        let mut state = test_state();
+        let resp = call(&state, graph_request("aarch64"));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        state.fallback_basearch = Arc::new(maplit::btreemap! {
+            "aarch64".to_string() => "x86_64".to_string(),
+        });
+        let resp = call(&state, graph_request("aarch64"));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "X-Graph-Fallback-From"), Some("aarch64"));
+        assert_eq!(header(&resp, "X-Graph-Basearch"), Some("x86_64"));
+        let resp = call(&state, graph_request("x86_64"));
+        assert_eq!(header(&resp, "X-Graph-Fallback-From"), None);
+        assert_eq!(header(&resp, "X-Graph-Maintenance"), None);
+    }
+}
@@ -0,0 +1,31 @@
+//! Pushing final metrics to a Prometheus Pushgateway, on shutdown.
+//!
+//! This is meant for short-lived deployments, whose last metrics may
+//! otherwise never be scraped.
+
+use crate::settings::PushgatewaySettings;
+use failure::{Fallible, ResultExt};
+use std::time::Duration;
+
+/// Timeout for pushing metrics, so that shutdown is never held up for long.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Push all registered metrics, replacing previously pushed ones for this job.
+pub(crate) async fn push_metrics(pushgateway: &PushgatewaySettings) -> Fallible<()> {
+    let body = commons::metrics::encode_metrics()?;
+    let url = pushgateway.push_url();
+    log::debug!("pushing metrics to {}", url);
+
+    let client = reqwest::ClientBuilder::new()
+        .timeout(PUSH_TIMEOUT)
+        .build()?;
+    client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+        .body(body)
+        .send()
+        .await
+        .context("failed to reach pushgateway")?
+        .error_for_status()?;
+    Ok(())
+}
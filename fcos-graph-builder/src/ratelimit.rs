@@ -0,0 +1,100 @@
+//! Per-client rate limiting.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of tracked clients above which idle buckets get pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Token-bucket rate limiter, keyed by client IP.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// Tokens refilled per second.
+    rate: f64,
+    /// Maximum number of tokens in a bucket.
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Try to consume a token for the given client.
+    ///
+    /// On rejection, this returns how long the client should wait before
+    /// retrying.
+    pub(crate) fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= PRUNE_THRESHOLD {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| b.refilled(now, rate, burst) < burst);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            last_update: now,
+        });
+        bucket.tokens = bucket.refilled(now, self.rate, self.burst);
+        bucket.last_update = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.rate;
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+impl Bucket {
+    /// Amount of tokens available at the given time.
+    fn refilled(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        (self.tokens + elapsed.as_secs_f64() * rate).min(burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1.0, 2);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.check_at(client, start).is_ok());
+        assert!(limiter.check_at(client, start).is_ok());
+        let wait = limiter.check_at(client, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+
+        // Other clients have their own bucket.
+        assert!(limiter.check_at(other, start).is_ok());
+
+        // Buckets refill over time.
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at(client, later).is_ok());
+        assert!(limiter.check_at(client, later).is_err());
+    }
+}
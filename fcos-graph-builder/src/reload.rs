@@ -0,0 +1,71 @@
+//! Configuration reloading, on SIGHUP.
+
+use crate::{config, scraper, settings};
+use actix::Addr;
+use failure::Fallible;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Reload configuration whenever a SIGHUP is received.
+///
+/// Only runtime-tunable settings (e.g. blocked versions, freezing) are applied, others
+/// (e.g. listening sockets) require a restart.
+pub(crate) async fn watch_sighup(
+    config_paths: Vec<PathBuf>,
+    strict: bool,
+    scrapers: HashMap<String, Addr<scraper::Scraper>>,
+    maintenance: Arc<AtomicBool>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("failed to set up SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        log::info!("SIGHUP received, reloading configuration");
+        match reload(&config_paths, strict, &scrapers, &maintenance) {
+            Ok(_) => {
+                crate::CONFIG_RELOADS.with_label_values(&["success"]).inc();
+                crate::CONFIG_LAST_RELOAD.set(chrono::Utc::now().timestamp());
+            }
+            Err(e) => {
+                crate::CONFIG_RELOADS.with_label_values(&["failure"]).inc();
+                log::error!("configuration reload rejected: {}", e);
+            }
+        }
+    }
+}
+
+/// Parse and validate the configuration files, then apply it to scrapers.
+fn reload(
+    config_paths: &[PathBuf],
+    strict: bool,
+    scrapers: &HashMap<String, Addr<scraper::Scraper>>,
+    maintenance: &AtomicBool,
+) -> Fallible<()> {
+    let cfg = config::FileConfig::parse_paths(config_paths, strict)?;
+    let settings = settings::GraphBuilderSettings::validate_config(cfg)?;
+
+    let engaged = settings.service.maintenance;
+    if maintenance.swap(engaged, Ordering::Relaxed) != engaged {
+        log::info!(
+            "{} maintenance mode",
+            if engaged { "entering" } else { "leaving" }
+        );
+    }
+    crate::MAINTENANCE_MODE.set(engaged as i64);
+
+    for (stream, addr) in scrapers {
+        addr.do_send(scraper::ReloadSettings::for_stream(
+            &settings.service,
+            stream,
+        ));
+    }
+    Ok(())
+}
@@ -1,64 +1,848 @@
+use crate::cache::LruCache;
+use crate::settings;
+use crate::signing::SigningKey;
+use crate::transforms::{self, Transform};
+use crate::warmcache::WarmCache;
 use actix::prelude::*;
 use actix_web::web::Bytes;
-use commons::{graph, metadata};
+use commons::{graph, metadata, policy};
 use failure::{Error, Fallible};
-use reqwest::Method;
-use std::collections::HashMap;
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::num::NonZeroU64;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 /// Default timeout for HTTP requests (30 minutes).
 const DEFAULT_HTTP_REQ_TIMEOUT: Duration = Duration::from_secs(30 * 60);
 
+/// Maximum number of serialized graph variants cached per scope.
+const VARIANT_CACHE_CAPACITY: usize = 8;
+
+/// Granularity (in seconds) for evaluating rollouts in cached graph variants.
+const ROLLOUTS_EVALUATION_INTERVAL_SECS: i64 = 60;
+
+/// Maximum number of pages in an upstream release index.
+const MAX_RELEASE_INDEX_PAGES: usize = 100;
+
+/// Maximum length of upstream content included in parsing diagnostics.
+const PARSE_ERROR_SNIPPET_LEN: usize = 256;
+
+/// Number of recent scrapes over which success ratios are computed.
+const SCRAPE_WINDOW_LEN: usize = 20;
+
+/// Graphs for a stream, keyed by basearch.
+type GraphMap = HashMap<String, graph::Graph>;
+type FullGraphMap = HashMap<graph::GraphScope, Arc<graph::Graph>>;
+
+/// Upstream release-index and updates metadata, if changed since last fetch.
+type UpstreamContent = (
+    Option<CachedUpstream<Vec<metadata::Release>>>,
+    Option<CachedUpstream<metadata::UpdatesJSON>>,
+);
+
+/// Upstream metadata URLs on a mirror.
+#[derive(Clone, Debug)]
+struct MirrorUrls {
+    base: reqwest::Url,
+    release_index: reqwest::Url,
+    updates: reqwest::Url,
+}
+
+/// Upstream metadata document, along with the ETag it was served with.
+#[derive(Clone, Debug)]
+struct CachedUpstream<T> {
+    etag: Option<HeaderValue>,
+    /// When upstream last changed the document, if known.
+    last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    content: T,
+    /// Document as fetched, for debugging.
+    raw: Bytes,
+}
+
+/// Snapshot of a cached graph, identified by its digest.
+///
+/// Snapshots are immutable and shared, so that a refresh swaps in a new
+/// snapshot as a whole: readers holding the previous one keep a consistent
+/// view of nodes, edges and serialized data.
+#[derive(Debug)]
+struct GraphSnapshot {
+    digest: String,
+    graph: graph::Graph,
+    /// Serialized graph, as served by default.
+    data: Bytes,
+    /// Entity tag of `data`.
+    etag: String,
+    /// Namespace for metadata keys in serialized data, if not the default one.
+    key_namespace: Option<String>,
+    /// Signature of the served graph, if signing is enabled.
+    signature: Option<String>,
+    /// When this graph was first cached.
+    created: chrono::DateTime<chrono::Utc>,
+    /// Schema version of the upstream updates metadata, if known.
+    upstream_schema_version: Option<String>,
+}
+
+impl GraphSnapshot {
+    fn new(
+        graph: graph::Graph,
+        key_namespace: Option<&str>,
+        signing_key: Option<&SigningKey>,
+        upstream_schema_version: Option<String>,
+        created: chrono::DateTime<chrono::Utc>,
+    ) -> Fallible<Self> {
+        use sha2::{Digest, Sha256};
+
+        let digest = format!("sha256:{:x}", Sha256::digest(&canonical_json(&graph)?));
+        let data = GraphVariant::default().render(&graph, key_namespace, &Provenance::default())?;
+        let signature = sign_graph(&graph, key_namespace, signing_key)?;
+        Ok(Self {
+            digest,
+            graph,
+            etag: graph_etag(&data),
+            data,
+            key_namespace: key_namespace.map(String::from),
+            signature,
+            created,
+            upstream_schema_version,
+        })
+    }
+
+    /// Return this snapshot, re-serialized with another metadata key namespace
+    /// and signing key.
+    fn rerender(
+        &self,
+        key_namespace: Option<&str>,
+        signing_key: Option<&SigningKey>,
+    ) -> Fallible<Self> {
+        let data =
+            GraphVariant::default().render(&self.graph, key_namespace, &Provenance::default())?;
+        Ok(Self {
+            digest: self.digest.clone(),
+            graph: self.graph.clone(),
+            etag: graph_etag(&data),
+            data,
+            key_namespace: key_namespace.map(String::from),
+            signature: sign_graph(&self.graph, key_namespace, signing_key)?,
+            created: self.created,
+            upstream_schema_version: self.upstream_schema_version.clone(),
+        })
+    }
+
+    /// Provenance of this graph, for clients.
+    fn provenance(&self) -> Provenance {
+        Provenance {
+            generated_at: Some(
+                self.created
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            ),
+            upstream_schema_version: self.upstream_schema_version.clone(),
+        }
+    }
+}
+
+/// Provenance of a served graph, as optional top-level fields.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Provenance {
+    /// When the graph was generated (RFC 3339).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_at: Option<String>,
+    /// Schema version of the upstream updates metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_schema_version: Option<String>,
+}
+
+/// A serialized graph, along with its provenance.
+#[derive(Serialize)]
+struct WithProvenance<'a, G: Serialize> {
+    #[serde(flatten)]
+    graph: &'a G,
+    #[serde(flatten)]
+    provenance: &'a Provenance,
+}
+
+/// When an upstream document last changed, from response headers.
+///
+/// This falls back to the response `Date`, for upstreams without
+/// `Last-Modified`.
+fn last_modified(headers: &header::HeaderMap) -> Option<chrono::DateTime<chrono::Utc>> {
+    [header::LAST_MODIFIED, header::DATE]
+        .iter()
+        .find_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            let time = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            Some(time.with_timezone(&chrono::Utc))
+        })
+}
+
+/// Resolve the link to the next page of a paginated upstream document.
+///
+/// `pages` are the URLs of pages fetched so far, all on the same origin.
+fn next_page_url(pages: &[reqwest::Url], link: &str) -> Fallible<reqwest::Url> {
+    let current = pages.last().expect("no current page");
+    let url = current
+        .join(link)
+        .map_err(|e| failure::format_err!("invalid next page link '{}': {}", link, e))?;
+    failure::ensure!(
+        url.origin() == current.origin(),
+        "next page link '{}' leaves origin {}",
+        link,
+        current.origin().ascii_serialization()
+    );
+    failure::ensure!(
+        !pages.contains(&url),
+        "next page link '{}' loops back to a previous page",
+        link
+    );
+    Ok(url)
+}
+
+/// Compress some data with gzip, at the given level.
+fn gzip(data: &[u8], level: u32) -> Fallible<Vec<u8>> {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Serialize a graph canonically, as compact JSON with sorted keys.
+fn canonical_json(graph: &graph::Graph) -> Fallible<Vec<u8>> {
+    // Go through a `Value`, whose maps are sorted.
+    Ok(serde_json::to_vec(&serde_json::to_value(graph)?)?)
+}
+
+/// Sign a graph as served, i.e. with metadata keys in the given namespace.
+fn sign_graph(
+    graph: &graph::Graph,
+    key_namespace: Option<&str>,
+    signing_key: Option<&SigningKey>,
+) -> Fallible<Option<String>> {
+    let signing_key = match signing_key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let canonical = match key_namespace {
+        Some(namespace) => {
+            let mut graph = graph.clone();
+            graph.rename_key_namespace(namespace);
+            canonical_json(&graph)?
+        }
+        None => canonical_json(graph)?,
+    };
+    signing_key.sign(&canonical).map(Some)
+}
+
+/// Current and past graphs for a scope, retained for diffing.
+#[derive(Clone, Debug, Default)]
+struct GraphHistory {
+    current: Option<Arc<GraphSnapshot>>,
+    /// Previously current graphs, most recent last.
+    past: VecDeque<Arc<GraphSnapshot>>,
+}
+
+impl GraphHistory {
+    /// Record a freshly assembled graph, rotating the current one if it changed.
+    ///
+    /// At most `max_past` previous graphs are retained.
+    fn push(&mut self, snapshot: Arc<GraphSnapshot>, max_past: usize) {
+        let changed = self
+            .current
+            .as_ref()
+            .map(|c| c.digest != snapshot.digest)
+            .unwrap_or(true);
+        if changed {
+            if let Some(previous) = self.current.replace(snapshot) {
+                self.past.push_back(previous);
+            }
+            while self.past.len() > max_past {
+                self.past.pop_front();
+            }
+        }
+    }
+
+    /// Find a retained graph by digest.
+    fn find_digest(&self, digest: &str) -> Option<&Arc<GraphSnapshot>> {
+        self.current
+            .iter()
+            .chain(self.past.iter().rev())
+            .find(|s| s.digest == digest)
+    }
+
+    /// Find the graph which was current at `time`.
+    ///
+    /// This fails with the time of the oldest retained graph, if `time` is
+    /// before it.
+    fn find_time(
+        &self,
+        time: chrono::DateTime<chrono::Utc>,
+    ) -> Result<&Arc<GraphSnapshot>, chrono::DateTime<chrono::Utc>> {
+        let mut oldest = None;
+        for snapshot in self.current.iter().chain(self.past.iter().rev()) {
+            if snapshot.created <= time {
+                return Ok(snapshot);
+            }
+            oldest = Some(snapshot.created);
+        }
+        Err(oldest.unwrap_or(time))
+    }
+}
+
+/// Current snapshot for a scope, along with variants rendered from it.
+#[derive(Debug)]
+struct StoreEntry {
+    snapshot: Arc<GraphSnapshot>,
+    /// Recently served non-default variants, dropped along with the snapshot.
+    variants: Mutex<LruCache<GraphVariant, (Bytes, String)>>,
+}
+
+/// Cached graphs of a stream, shared with request handlers.
+///
+/// The scraper actor publishes snapshots here whenever they change, while
+/// graphs are served straight from the store. This way, serving a graph is
+/// never queued in the actor mailbox, e.g. behind a refresh.
+#[derive(Clone, Debug)]
+pub(crate) struct GraphStore {
+    stream: String,
+    /// Architectures with graphs for this stream.
+    arches: Arc<Vec<String>>,
+    entries: Arc<RwLock<HashMap<graph::GraphScope, Arc<StoreEntry>>>>,
+}
+
+impl GraphStore {
+    fn new(stream: String, arches: Vec<String>) -> Self {
+        Self {
+            stream,
+            arches: Arc::new(arches),
+            entries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Create a store with a graph published for a single scope, for tests.
+    #[cfg(test)]
+    pub(crate) fn with_graph(scope: graph::GraphScope, graph: graph::Graph) -> Fallible<Self> {
+        let store = Self::new(scope.stream.clone(), vec![scope.basearch.clone()]);
+        let snapshot = GraphSnapshot::new(graph, None, None, None, chrono::Utc::now())?;
+        store.publish(scope, Arc::new(snapshot));
+        Ok(store)
+    }
+
+    /// Publish the current snapshot for a scope, unless already published.
+    fn publish(&self, scope: graph::GraphScope, snapshot: Arc<GraphSnapshot>) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.get(&scope) {
+            if Arc::ptr_eq(&entry.snapshot, &snapshot) {
+                return;
+            }
+        }
+        let entry = StoreEntry {
+            snapshot,
+            variants: Mutex::new(LruCache::new(VARIANT_CACHE_CAPACITY)),
+        };
+        entries.insert(scope, Arc::new(entry));
+    }
+
+    /// Return the cached graph for a scope.
+    pub(crate) fn cached_graph(
+        &self,
+        scope: &graph::GraphScope,
+        variant: GraphVariant,
+    ) -> Fallible<CachedGraphResult> {
+        let graph_type = if scope.oci { "oci" } else { "checksum" };
+
+        if scope.stream != self.stream {
+            failure::bail!("unexpected stream '{}'", scope.stream);
+        }
+        if !self.arches.contains(&scope.basearch) {
+            return Ok(CachedGraphResult::UnknownScope);
+        }
+        crate::CACHED_GRAPH_REQUESTS
+            .with_label_values(&[&scope.basearch, &scope.stream, graph_type])
+            .inc();
+
+        // Grab the current entry once, so that data and metadata match.
+        let entry = self
+            .entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(scope)
+            .cloned();
+        let entry = match entry {
+            Some(e) => e,
+            None => return Ok(CachedGraphResult::NotReady),
+        };
+        let snapshot = &entry.snapshot;
+        let signature = if variant.is_whole_graph() {
+            snapshot.signature.clone()
+        } else {
+            None
+        };
+        let (data, etag) = if variant != GraphVariant::default() {
+            Self::get_variant(&entry, variant)?
+        } else {
+            (snapshot.data.clone(), snapshot.etag.clone())
+        };
+        Ok(CachedGraphResult::Graph(CachedGraph {
+            data,
+            etag,
+            last_modified: Some(snapshot.created),
+            signature,
+        }))
+    }
+
+    /// Return a serialized graph variant and its entity tag, rendering and
+    /// caching them if needed.
+    ///
+    /// Rendered variants are shared by all requests, so that memory usage does
+    /// not grow with the number of concurrent requests. Rollouts progress over
+    /// time, so variants depending on them are cached per evaluation interval.
+    fn get_variant(entry: &StoreEntry, mut variant: GraphVariant) -> Fallible<(Bytes, String)> {
+        if variant.exclude_rollouts {
+            let now = chrono::Utc::now().timestamp();
+            variant.rollouts_at = Some(now - now.rem_euclid(ROLLOUTS_EVALUATION_INTERVAL_SECS));
+        }
+        let variants = || entry.variants.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(rendered) = variants().get(&variant) {
+            return Ok(rendered.clone());
+        }
+
+        // Render without holding the lock, so that other variants can still be
+        // served meanwhile.
+        let snapshot = &entry.snapshot;
+        let data = variant.render(
+            &snapshot.graph,
+            snapshot.key_namespace.as_deref(),
+            &snapshot.provenance(),
+        )?;
+        let rendered = (data.clone(), graph_etag(&data));
+        variants().insert(variant, rendered.clone());
+        Ok(rendered)
+    }
+}
+
+/// Outcomes of the most recent scrapes for a scope, oldest first.
+#[derive(Clone, Debug, Default)]
+struct ScrapeWindow {
+    successes: VecDeque<bool>,
+}
+
+impl ScrapeWindow {
+    /// Record the outcome of a scrape, returning the success ratio over the window.
+    fn record(&mut self, success: bool) -> f64 {
+        if self.successes.len() == SCRAPE_WINDOW_LEN {
+            self.successes.pop_front();
+        }
+        self.successes.push_back(success);
+        let succeeded = self.successes.iter().filter(|&&s| s).count();
+        succeeded as f64 / self.successes.len() as f64
+    }
+}
+
+/// Changes to a cached graph between two refreshes.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ScopedGraphDiff {
+    /// Digest of the baseline graph, if any.
+    pub(crate) from_digest: Option<String>,
+    /// Digest of the currently cached graph.
+    pub(crate) to_digest: String,
+    #[serde(flatten)]
+    pub(crate) diff: graph::GraphDiff,
+}
+
+/// Serialization format for graphs.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) enum GraphFormat {
+    /// Indented JSON.
+    Pretty,
+    /// JSON without whitespace.
+    Compact,
+    /// Upstream Cincinnati wire format, for standard Cincinnati clients.
+    Cincinnati,
+    /// Only the list of node versions, in age order.
+    Versions,
+    /// JSON without whitespace, with a priority for each edge.
+    Weighted,
+}
+
+impl GraphFormat {
+    /// Media type of graphs serialized in this format.
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            GraphFormat::Pretty
+            | GraphFormat::Compact
+            | GraphFormat::Versions
+            | GraphFormat::Weighted => "application/json",
+            GraphFormat::Cincinnati => graph::CINCINNATI_MEDIA_TYPE,
+        }
+    }
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "pretty" => Ok(GraphFormat::Pretty),
+            "compact" => Ok(GraphFormat::Compact),
+            "versions" => Ok(GraphFormat::Versions),
+            "weighted" => Ok(GraphFormat::Weighted),
+            _ => Err(failure::format_err!("unknown graph format '{}'", input)),
+        }
+    }
+}
+
+/// Node fields to serialize, when projecting graphs.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct NodeFields {
+    version: bool,
+    metadata: bool,
+    payload: bool,
+}
+
+impl std::str::FromStr for NodeFields {
+    type Err = Error;
+
+    /// Parse a comma-separated list of node fields.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut fields = Self {
+            version: false,
+            metadata: false,
+            payload: false,
+        };
+        for field in input.split(',') {
+            match field.trim() {
+                "version" => fields.version = true,
+                "metadata" => fields.metadata = true,
+                "payload" => fields.payload = true,
+                "" => failure::bail!("empty node field"),
+                other => failure::bail!("unknown node field '{}'", other),
+            }
+        }
+        Ok(fields)
+    }
+}
+
+/// Borrowed view of a graph, with only some node fields.
+#[derive(Serialize)]
+struct ProjectedGraph<'a> {
+    nodes: Vec<ProjectedNode<'a>>,
+    edges: &'a [(u64, u64)],
+}
+
+#[derive(Serialize)]
+struct ProjectedNode<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<std::collections::BTreeMap<&'a str, &'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<&'a str>,
+}
+
+impl<'a> ProjectedGraph<'a> {
+    fn new(graph: &'a graph::Graph, fields: NodeFields) -> Self {
+        let nodes = graph
+            .nodes
+            .iter()
+            .map(|n| ProjectedNode {
+                version: Some(n.version.as_str()).filter(|_| fields.version),
+                metadata: Some(&n.metadata)
+                    .filter(|_| fields.metadata)
+                    .map(|m| m.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()),
+                payload: Some(n.payload.as_str()).filter(|_| fields.payload),
+            })
+            .collect();
+        Self {
+            nodes,
+            edges: &graph.edges,
+        }
+    }
+}
+
+/// A variant of a cached graph, as served to clients.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct GraphVariant {
+    pub(crate) format: GraphFormat,
+    /// Whether to remove releases in an active rollout.
+    pub(crate) exclude_rollouts: bool,
+    /// Node fields to serialize, if not all of them.
+    ///
+    /// Only supported by the `Pretty` and `Compact` formats.
+    pub(crate) fields: Option<NodeFields>,
+    /// Whether to add provenance fields at the top level.
+    ///
+    /// Only supported by the `Pretty` and `Compact` formats.
+    pub(crate) provenance: bool,
+    /// Compression level, if gzip-encoded.
+    pub(crate) gzip_level: Option<u32>,
+    /// Time (in seconds since epoch) at which rollouts are evaluated.
+    ///
+    /// This is set when looking up the variant, truncated to
+    /// `ROLLOUTS_EVALUATION_INTERVAL_SECS` so that rendering can be shared.
+    rollouts_at: Option<i64>,
+}
+
+impl Default for GraphVariant {
+    fn default() -> Self {
+        Self {
+            format: GraphFormat::Pretty,
+            exclude_rollouts: false,
+            fields: None,
+            provenance: false,
+            gzip_level: None,
+            rollouts_at: None,
+        }
+    }
+}
+
+impl GraphVariant {
+    /// Whether this variant serves the whole graph, as covered by its signature.
+    fn is_whole_graph(&self) -> bool {
+        !self.exclude_rollouts
+            && self.fields.is_none()
+            && !self.provenance
+            && matches!(self.format, GraphFormat::Pretty | GraphFormat::Compact)
+    }
+
+    /// Serialize a graph according to this variant.
+    ///
+    /// Metadata keys are moved to a custom namespace, if any.
+    pub(crate) fn render(
+        &self,
+        graph: &graph::Graph,
+        key_namespace: Option<&str>,
+        provenance: &Provenance,
+    ) -> Fallible<Bytes> {
+        let filtered;
+        let graph = if self.exclude_rollouts {
+            let now = self
+                .rollouts_at
+                .unwrap_or_else(|| chrono::Utc::now().timestamp());
+            filtered = policy::filter_active_rollouts(graph.clone(), now);
+            &filtered
+        } else {
+            graph
+        };
+        // Computed before renaming, as it relies on metadata keys.
+        let edge_priorities = match self.format {
+            GraphFormat::Weighted => graph.edge_priorities(),
+            _ => vec![],
+        };
+        let renamed;
+        let graph = match key_namespace {
+            Some(namespace) => {
+                let mut graph = graph.clone();
+                graph.rename_key_namespace(namespace);
+                renamed = graph;
+                &renamed
+            }
+            None => graph,
+        };
+
+        let data = match self.format {
+            GraphFormat::Pretty | GraphFormat::Compact => match self.fields {
+                Some(fields) => {
+                    self.render_json(&ProjectedGraph::new(graph, fields), provenance)?
+                }
+                None => self.render_json(graph, provenance)?,
+            },
+            GraphFormat::Cincinnati => {
+                serde_json::to_vec(&graph::CincinnatiWireGraph::from(graph))?
+            }
+            GraphFormat::Versions => {
+                let mut nodes: Vec<&graph::CincinnatiPayload> = graph.nodes.iter().collect();
+                nodes.sort_by_key(|n| n.age_index());
+                let versions: Vec<&str> = nodes.iter().map(|n| n.version.as_str()).collect();
+                serde_json::to_vec(&versions)?
+            }
+            GraphFormat::Weighted => serde_json::to_vec(&graph::WeightedGraph::with_priorities(
+                graph,
+                edge_priorities,
+            ))?,
+        };
+        match self.gzip_level {
+            Some(level) => gzip(&data, level).map(Bytes::from),
+            None => Ok(Bytes::from(data)),
+        }
+    }
+
+    /// Serialize a JSON graph, possibly along with its provenance.
+    fn render_json<G: Serialize>(&self, graph: &G, provenance: &Provenance) -> Fallible<Vec<u8>> {
+        let data = match (self.format, self.provenance) {
+            (GraphFormat::Pretty, false) => serde_json::to_vec_pretty(graph)?,
+            (GraphFormat::Pretty, true) => {
+                serde_json::to_vec_pretty(&WithProvenance { graph, provenance })?
+            }
+            (_, false) => serde_json::to_vec(graph)?,
+            (_, true) => serde_json::to_vec(&WithProvenance { graph, provenance })?,
+        };
+        Ok(data)
+    }
+}
+
+/// Barriers in the cached graph for a scope.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ScopeBarriers {
+    pub(crate) stream: String,
+    pub(crate) basearch: String,
+    pub(crate) oci: bool,
+    pub(crate) barriers: Vec<Barrier>,
+}
+
+/// A barrier release, along with its reason.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Barrier {
+    pub(crate) version: String,
+    pub(crate) reason: String,
+}
+
 /// Release scraper.
 #[derive(Clone, Debug)]
 pub struct Scraper {
     stream: String,
-    /// arch -> graph
-    graphs: HashMap<String, Bytes>,
-    /// arch -> graph
-    oci_graphs: HashMap<String, Bytes>,
+    /// Architectures with graphs for this stream.
+    arches: Vec<String>,
     hclient: reqwest::Client,
     pause_secs: NonZeroU64,
-    release_index_url: reqwest::Url,
-    updates_url: reqwest::Url,
+    /// Upstream mirrors, tried in order.
+    mirrors: Vec<MirrorUrls>,
+    /// Timeout for each upstream request, if not the client default.
+    mirror_timeout: Option<Duration>,
+    /// Delay before the first scrape, if any.
+    initial_delay: Option<Duration>,
+    /// Permits bounding first scrapes across streams, until the first one.
+    initial_permits: Option<Arc<tokio::sync::Semaphore>>,
+    /// Last fetched release-index.
+    releases: Option<CachedUpstream<Vec<metadata::Release>>>,
+    /// Last fetched updates metadata.
+    updates: Option<CachedUpstream<metadata::UpdatesJSON>>,
+    /// Recent graphs, for serving and diffing.
+    history: HashMap<graph::GraphScope, GraphHistory>,
+    /// Last assembled graphs including edges out of dead-ends, if retained.
+    full_graphs: Option<FullGraphMap>,
+    /// Scopes refreshed since the start of the current scrape.
+    refreshed: HashSet<graph::GraphScope>,
+    /// Scopes whose graph was cached on the last rebuild, i.e. not rejected.
+    rebuilt: HashSet<graph::GraphScope>,
+    /// Outcomes of recent scrapes, per scope.
+    scrape_windows: HashMap<graph::GraphScope, ScrapeWindow>,
+    /// Current graphs, as served.
+    store: GraphStore,
+    /// Versions to remove from assembled graphs.
+    blocked_versions: HashSet<String>,
+    /// Overrides of upstream dead-end markers.
+    deadend_overrides: policy::DeadendOverrides,
+    /// Transforms to apply to assembled graphs, in order.
+    transforms: Vec<Transform>,
+    /// Maximum age-index span behind the newest release, if limited.
+    max_age_span: Option<u64>,
+    /// Custom namespace for metadata keys in served graphs, if any.
+    key_namespace: Option<String>,
+    /// Key for signing served graphs, if any.
+    signing_key: Option<SigningKey>,
+    /// Extra metadata to merge into graph nodes.
+    extra_metadata: settings::ExtraMetadata,
+    /// Rewrites of barrier and dead-end reasons.
+    reason_redactions: policy::ReasonRedactions,
+    /// Whether refreshes are paused.
+    frozen: bool,
+    /// Metadata keys that every node in a graph must have.
+    required_metadata_keys: Vec<String>,
+    /// Whether to check that OCI and checksum graphs have the same versions.
+    check_scope_consistency: bool,
+    /// Number of past graphs retained per scope, for diffing.
+    diff_history_len: usize,
+    /// Whether a refresh is in progress.
+    refreshing: bool,
+    /// Next scheduled refresh, if any.
+    next_tick: Option<SpawnHandle>,
+    /// Additional releases, on top of upstream ones.
+    overlay: Vec<metadata::Release>,
+    /// On-disk cache of the last graphs, if enabled.
+    warm_cache: Option<WarmCache>,
 }
 
 impl Scraper {
-    pub(crate) fn new(stream: String, arches: Vec<String>) -> Fallible<Self> {
-        let empty = {
-            let empty_graph = graph::Graph::default();
-            let data = serde_json::to_vec(&empty_graph)?;
-            Bytes::from(data)
-        };
-        let graphs = arches
-            .iter()
-            .map(|arch| (arch.clone(), empty.clone()))
-            .collect();
-        let oci_graphs = arches
-            .into_iter()
-            .map(|arch| (arch, empty.clone()))
-            .collect();
+    pub(crate) fn new(
+        stream: String,
+        arches: Vec<String>,
+        canary: Option<settings::CanaryStream>,
+        user_agent: &str,
+        upstream: &settings::UpstreamSettings,
+        warm_cache: Option<WarmCache>,
+        runtime: ReloadSettings,
+    ) -> Fallible<Self> {
+        let store = GraphStore::new(stream.clone(), arches.clone());
 
+        // Canary streams are sourced from their base stream.
+        let (upstream_stream, overlay) = match canary {
+            Some(c) => (c.base, c.overlay),
+            None => (stream.clone(), vec![]),
+        };
         let vars = maplit::hashmap! {
-            "stream".to_string() => stream.clone(),
+            "stream".to_string() => upstream_stream,
         };
-        let releases_json = envsubst::substitute(metadata::RELEASES_JSON, &vars)?;
-        let updates_json = envsubst::substitute(metadata::UPDATES_JSON, &vars)?;
+        let releases_path = envsubst::substitute(metadata::RELEASES_JSON_PATH, &vars)?;
+        let updates_path = envsubst::substitute(metadata::UPDATES_JSON_PATH, &vars)?;
+        let mirrors = upstream
+            .mirrors
+            .iter()
+            .map(|base| {
+                Ok(MirrorUrls {
+                    base: base.clone(),
+                    release_index: base.join(&releases_path)?,
+                    updates: base.join(&updates_path)?,
+                })
+            })
+            .collect::<Fallible<_>>()?;
         let hclient = reqwest::ClientBuilder::new()
             .pool_idle_timeout(Some(Duration::from_secs(10)))
             .timeout(DEFAULT_HTTP_REQ_TIMEOUT)
+            .user_agent(user_agent)
             .build()?;
 
-        let scraper = Self {
-            graphs,
-            oci_graphs,
+        let mut scraper = Self {
+            arches,
             hclient,
             pause_secs: NonZeroU64::new(30).expect("non-zero pause"),
             stream,
-            release_index_url: reqwest::Url::parse(&releases_json)?,
-            updates_url: reqwest::Url::parse(&updates_json)?,
+            mirrors,
+            mirror_timeout: upstream.timeout,
+            initial_delay: upstream.initial_delay,
+            initial_permits: None,
+            releases: None,
+            updates: None,
+            history: HashMap::new(),
+            full_graphs: None,
+            refreshed: HashSet::new(),
+            rebuilt: HashSet::new(),
+            scrape_windows: HashMap::new(),
+            store,
+            blocked_versions: runtime.blocked_versions,
+            deadend_overrides: runtime.deadend_overrides,
+            transforms: runtime.transforms,
+            max_age_span: runtime.max_age_span,
+            key_namespace: runtime.key_namespace,
+            signing_key: runtime.signing_key,
+            extra_metadata: runtime.extra_metadata,
+            reason_redactions: runtime.reason_redactions,
+            frozen: runtime.frozen,
+            required_metadata_keys: runtime.required_metadata_keys,
+            check_scope_consistency: runtime.check_scope_consistency,
+            diff_history_len: settings::ServiceSettings::DEFAULT_DIFF_HISTORY_LEN,
+            refreshing: false,
+            next_tick: None,
+            overlay,
+            warm_cache,
         };
+        scraper.load_warm_cache()?;
+        crate::SCRAPER_FROZEN
+            .with_label_values(&[&scraper.stream])
+            .set(scraper.frozen as i64);
         Ok(scraper)
     }
 
@@ -73,164 +857,816 @@ impl Scraper {
         Ok(builder)
     }
 
-    /// Fetch releases from release-index.
-    fn fetch_releases(&self) -> impl Future<Output = Result<Vec<metadata::Release>, Error>> {
-        let target = self.release_index_url.clone();
-        let req = self.new_request(Method::GET, target);
-
-        async {
-            let resp = req?.send().await?;
-            let content = resp.error_for_status()?;
-            let json = content.json::<metadata::ReleasesJSON>().await?;
-            Ok(json.releases)
+    /// Return a conditional GET request, carrying the ETag of a previous response (if any).
+    fn new_conditional_request<T>(
+        &self,
+        url: reqwest::Url,
+        cached: &Option<CachedUpstream<T>>,
+    ) -> Fallible<reqwest::RequestBuilder> {
+        let mut builder = self.new_request(Method::GET, url)?;
+        if let Some(timeout) = self.mirror_timeout {
+            builder = builder.timeout(timeout);
         }
+        if let Some(etag) = cached.as_ref().and_then(|c| c.etag.as_ref()) {
+            builder = builder.header(header::IF_NONE_MATCH, etag.clone());
+        }
+        Ok(builder)
     }
 
-    /// Fetch updates metadata.
-    fn fetch_updates(&self) -> impl Future<Output = Result<metadata::UpdatesJSON, Error>> {
-        let target = self.updates_url.clone();
-        let req = self.new_request(Method::GET, target);
+    /// Fetch releases from release-index, following pagination if any.
+    ///
+    /// Only single-page indexes are fetched conditionally. New releases are
+    /// appended to the last page, so the ETag of a paginated index is dropped
+    /// and all of its pages are fetched again next time. The raw document is
+    /// the first page.
+    ///
+    /// This returns `None` if upstream content did not change since the last fetch.
+    fn fetch_releases(
+        &self,
+        mirror: &MirrorUrls,
+    ) -> impl Future<Output = Result<Option<CachedUpstream<Vec<metadata::Release>>>, Error>> {
+        let target = mirror.release_index.clone();
+        let req = self.new_conditional_request(target.clone(), &self.releases);
+        let hclient = self.hclient.clone();
+        let timeout = self.mirror_timeout;
+        let stream = self.stream.clone();
 
-        async {
-            let resp = req?.send().await?;
-            let content = resp.error_for_status()?;
-            let json = content.json::<metadata::UpdatesJSON>().await?;
-            Ok(json)
+        async move {
+            let first = match Self::fetch_document::<metadata::ReleasesJSON>(&stream, req?).await? {
+                Some(fetched) => fetched,
+                None => return Ok(None),
+            };
+            let mut releases = first.content.releases;
+            let mut next = first.content.next;
+            let mut pages = vec![target];
+            while let Some(link) = next {
+                let url = next_page_url(&pages, &link)?;
+                failure::ensure!(
+                    pages.len() < MAX_RELEASE_INDEX_PAGES,
+                    "release index for stream '{}' has more than {} pages",
+                    stream,
+                    MAX_RELEASE_INDEX_PAGES
+                );
+                log::trace!("fetching release index page {}", url);
+                let mut req = hclient.get(url.clone());
+                if let Some(timeout) = timeout {
+                    req = req.timeout(timeout);
+                }
+                let page = Self::fetch_document::<metadata::ReleasesJSON>(&stream, req)
+                    .await?
+                    .ok_or_else(|| failure::format_err!("unexpected 304 for page {}", url))?;
+                releases.extend(page.content.releases);
+                next = page.content.next;
+                pages.push(url);
+            }
+            crate::UPSTREAM_RELEASE_INDEX_PAGES
+                .with_label_values(&[&stream])
+                .set(pages.len() as i64);
+
+            let etag = if pages.len() > 1 { None } else { first.etag };
+            Ok(Some(CachedUpstream {
+                etag,
+                last_modified: first.last_modified,
+                content: releases,
+                raw: first.raw,
+            }))
         }
     }
 
-    /// Combine release-index and updates metadata.
-    fn assemble_graphs(
+    /// Fetch updates metadata.
+    ///
+    /// This returns `None` if upstream content did not change since the last fetch.
+    fn fetch_updates(
         &self,
-    ) -> impl Future<
-        Output = Result<(HashMap<String, graph::Graph>, HashMap<String, graph::Graph>), Error>,
-    > {
-        let stream_releases = self.fetch_releases();
-        let stream_updates = self.fetch_updates();
-
-        // yuck... we clone a bunch here to keep the async closure 'static
+        mirror: &MirrorUrls,
+    ) -> impl Future<Output = Result<Option<CachedUpstream<metadata::UpdatesJSON>>, Error>> {
+        let target = mirror.updates.clone();
+        let req = self.new_conditional_request(target, &self.updates);
         let stream = self.stream.clone();
-        let arches: Vec<String> = self.graphs.keys().cloned().collect();
 
         async move {
-            let (graph, updates) =
-                futures::future::try_join(stream_releases, stream_updates).await?;
-            // first the legacy graphs
-            let mut map = HashMap::with_capacity(arches.len());
-            for arch in &arches {
-                map.insert(
-                    arch.clone(),
-                    graph::Graph::from_metadata(
-                        graph.clone(),
-                        updates.clone(),
-                        graph::GraphScope {
-                            basearch: arch.clone(),
-                            stream: stream.clone(),
-                            oci: false,
-                        },
-                    )?,
-                );
-            }
-            // now the OCI graphs
-            let mut oci_map = HashMap::with_capacity(arches.len());
-            for arch in &arches {
-                oci_map.insert(
-                    arch.clone(),
-                    graph::Graph::from_metadata(
-                        graph.clone(),
-                        updates.clone(),
-                        graph::GraphScope {
-                            basearch: arch.clone(),
-                            stream: stream.clone(),
-                            oci: true,
-                        },
-                    )?,
-                );
+            let fetched = match Self::fetch_document::<metadata::UpdatesJSON>(&stream, req?).await?
+            {
+                Some(fetched) => fetched,
+                None => return Ok(None),
+            };
+            let json = &fetched.content;
+            if json.schema_version() != metadata::UPDATES_SCHEMA_VERSION {
+                crate::UPSTREAM_ERRORS
+                    .with_label_values(&[&stream, "schema"])
+                    .inc();
+                return Err(graph::GraphError::SchemaMismatch {
+                    expected: metadata::UPDATES_SCHEMA_VERSION.to_string(),
+                    found: json.schema_version().to_string(),
+                }
+                .into());
             }
-            Ok((map, oci_map))
+            Ok(Some(fetched))
         }
     }
 
-    /// Update cached graph.
-    fn update_cached_graph(
-        &mut self,
-        arch: String,
-        oci: bool,
-        graph: graph::Graph,
-    ) -> Result<(), Error> {
-        let data = serde_json::to_vec_pretty(&graph).map_err(|e| failure::format_err!("{}", e))?;
-        let graph_type = if oci { "oci" } else { "checksum" };
-
-        let refresh_timestamp = chrono::Utc::now();
-        crate::LAST_REFRESH
-            .with_label_values(&[&arch, &self.stream, graph_type])
-            .set(refresh_timestamp.timestamp());
-        crate::GRAPH_FINAL_EDGES
-            .with_label_values(&[&arch, &self.stream, graph_type])
-            .set(graph.edges.len() as i64);
-        crate::GRAPH_FINAL_RELEASES
-            .with_label_values(&[&arch, &self.stream, graph_type])
-            .set(graph.nodes.len() as i64);
+    /// Fetch and parse a JSON document, retrying once on malformed content.
+    ///
+    /// This returns `None` if upstream content did not change since the last fetch.
+    async fn fetch_document<T: DeserializeOwned>(
+        stream: &str,
+        req: reqwest::RequestBuilder,
+    ) -> Fallible<Option<CachedUpstream<T>>> {
+        let mut retried = false;
+        loop {
+            let attempt = req
+                .try_clone()
+                .ok_or_else(|| failure::err_msg("upstream request cannot be retried"))?;
+            let resp = attempt.send().await?;
+            if !Self::record_upstream_status(stream, resp.status()) {
+                return Ok(None);
+            }
+            let content = resp.error_for_status()?;
+            let etag = content.headers().get(header::ETAG).cloned();
+            let last_modified = last_modified(content.headers());
+            let url = content.url().clone();
+            let body = content.bytes().await?;
 
-        log::trace!(
-            "cached graph for {}/{}/oci={}: releases={}, edges={}",
-            &arch,
-            self.stream,
-            oci,
-            graph.nodes.len(),
-            graph.edges.len()
-        );
+            match serde_json::from_slice::<T>(&body) {
+                Ok(json) => {
+                    return Ok(Some(CachedUpstream {
+                        etag,
+                        last_modified,
+                        content: json,
+                        raw: body,
+                    }))
+                }
+                Err(e) => {
+                    crate::UPSTREAM_ERRORS
+                        .with_label_values(&[stream, "parse"])
+                        .inc();
+                    let snippet_len = body.len().min(PARSE_ERROR_SNIPPET_LEN);
+                    log::warn!(
+                        "failed to parse upstream document {} ({} bytes): {}; content begins with: {:?}",
+                        url,
+                        body.len(),
+                        e,
+                        String::from_utf8_lossy(&body[..snippet_len])
+                    );
+                    if retried {
+                        return Err(e.into());
+                    }
+                    retried = true;
+                    log::debug!("retrying upstream fetch for {}", url);
+                }
+            }
+        }
+    }
 
-        if oci {
-            self.oci_graphs.insert(arch, Bytes::from(data));
-        } else {
-            self.graphs.insert(arch, Bytes::from(data));
+    /// Record the status of an upstream response, returning whether its content changed.
+    fn record_upstream_status(stream: &str, status: StatusCode) -> bool {
+        let changed = status != StatusCode::NOT_MODIFIED;
+        if status.is_success() || !changed {
+            crate::UPSTREAM_RESPONSES
+                .with_label_values(&[stream, status.as_str()])
+                .inc();
         }
-        Ok(())
+        changed
     }
-}
 
-impl Actor for Scraper {
-    type Context = Context<Self>;
+    /// Fetch release-index and updates metadata, only transferring changed content.
+    ///
+    /// Mirrors are tried in order, moving to the next one on failure. Both
+    /// documents are always fetched from the same mirror.
+    fn fetch_upstream(&self) -> impl Future<Output = Result<UpstreamContent, Error>> {
+        // Futures are lazy, so only the needed mirrors are actually contacted.
+        let attempts: Vec<_> = self
+            .mirrors
+            .iter()
+            .map(|mirror| {
+                let url = mirror.base.clone();
+                let fetch = futures::future::try_join(
+                    self.fetch_releases(mirror),
+                    self.fetch_updates(mirror),
+                );
+                (url, fetch)
+            })
+            .collect();
+        let stream = self.stream.clone();
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        // Kick-start the state machine.
-        Self::tick_now(ctx);
+        async move {
+            let mut last_error = failure::err_msg("no upstream mirrors");
+            for (index, (url, fetch)) in attempts.into_iter().enumerate() {
+                match fetch.await {
+                    Ok(content) => {
+                        crate::UPSTREAM_ACTIVE_MIRROR
+                            .with_label_values(&[&stream])
+                            .set(index as i64);
+                        return Ok(content);
+                    }
+                    Err(e) => {
+                        crate::UPSTREAM_MIRROR_FAILURES
+                            .with_label_values(&[&stream, &index.to_string()])
+                            .inc();
+                        log::warn!(
+                            "failed to fetch metadata for stream '{}' from mirror {} ({}): {}",
+                            stream,
+                            index,
+                            url,
+                            e
+                        );
+                        last_error = e;
+                    }
+                }
+            }
+            Err(last_error)
+        }
     }
-}
 
-pub(crate) struct RefreshTick {}
+    /// Refresh cached graphs from (possibly unchanged) upstream metadata.
+    fn refresh_graphs(&mut self, upstream: UpstreamContent) -> Fallible<()> {
+        let (releases, updates) = upstream;
 
-impl Message for RefreshTick {
-    type Result = Result<(), failure::Error>;
-}
+        // Nothing changed upstream, keep the existing graphs. Graphs rejected
+        // on the last rebuild are still stale, so they are not refreshed.
+        if releases.is_none() && updates.is_none() {
+            log::trace!("upstream metadata for stream '{}' unchanged", self.stream);
+            for scope in self.rebuilt.clone() {
+                self.mark_refreshed(&scope.basearch, scope.oci);
+            }
+            return Ok(());
+        }
 
-impl Handler<RefreshTick> for Scraper {
-    type Result = ResponseActFuture<Self, Result<(), failure::Error>>;
+        let previous_releases = releases.map(|latest| self.releases.replace(latest));
+        let previous_updates = updates.map(|latest| self.updates.replace(latest));
 
-    fn handle(&mut self, _msg: RefreshTick, _ctx: &mut Self::Context) -> Self::Result {
-        crate::UPSTREAM_SCRAPES
-            .with_label_values(&[&self.stream])
-            .inc();
+        // Metadata which cannot be assembled is dropped along with its ETag,
+        // so that it is fetched (and checked) again on the next scrape.
+        let assembled = match self.assemble_graphs() {
+            Ok(assembled) => assembled,
+            Err(e) => {
+                if let Some(previous) = previous_releases {
+                    self.releases = previous;
+                }
+                if let Some(previous) = previous_updates {
+                    self.updates = previous;
+                }
+                self.rebuilt.clear();
+                return Err(e);
+            }
+        };
+        if let Some(previous) = previous_updates {
+            let previous = previous.map(|u| u.content.schema_version().to_string());
+            self.record_schema_version(previous);
+            self.record_malformed_rollouts();
+        }
 
-        let latest_graphs = self.assemble_graphs();
-        let update_graphs = actix::fut::wrap_future::<_, Self>(latest_graphs)
-            .map(|graphs, actor, _ctx| {
-                let res: Result<(), Error> = graphs.and_then(|(g, oci_g)| {
-                    g.into_iter()
-                        .map(|(arch, graph)| (arch, false, graph))
-                        .chain(oci_g.into_iter().map(|(arch, graph)| (arch, true, graph)))
-                        .try_for_each(|(arch, oci, graph)| {
-                            actor.update_cached_graph(arch, oci, graph)
-                        })
-                });
-                if let Err(e) = res {
+        self.cache_assembled_graphs(assembled)
+    }
+
+    /// Expose the number of malformed rollouts in the current updates metadata.
+    fn record_malformed_rollouts(&self) {
+        let updates = match &self.updates {
+            Some(u) => &u.content,
+            None => return,
+        };
+        let malformed = updates
+            .releases
+            .iter()
+            .filter(|entry| match &entry.metadata.rollout {
+                Some(rollout) => !rollout.malformed_fields.is_empty(),
+                None => false,
+            })
+            .count();
+        crate::UPSTREAM_MALFORMED_ROLLOUTS
+            .with_label_values(&[&self.stream])
+            .set(malformed as i64);
+    }
+
+    /// Expose the schema version of the current updates metadata.
+    fn record_schema_version(&self, previous: Option<String>) {
+        let current = match &self.updates {
+            Some(u) => u.content.schema_version(),
+            None => return,
+        };
+        if let Some(prev) = previous {
+            if prev != current {
+                let _ = crate::UPSTREAM_SCHEMA.remove_label_values(&[&self.stream, &prev]);
+            }
+        }
+        crate::UPSTREAM_SCHEMA
+            .with_label_values(&[&self.stream, current])
+            .set(1);
+    }
+
+    /// Re-assemble and cache graphs from the last fetched upstream metadata.
+    ///
+    /// Graphs failing validation are not cached, keeping the previous ones instead.
+    fn rebuild_graphs(&mut self) -> Fallible<()> {
+        match self.assemble_graphs() {
+            Ok(assembled) => self.cache_assembled_graphs(assembled),
+            Err(e) => {
+                // No graph is up to date anymore.
+                self.rebuilt.clear();
+                Err(e)
+            }
+        }
+    }
+
+    /// Cache freshly assembled graphs, once validated.
+    fn cache_assembled_graphs(
+        &mut self,
+        (g, oci_g, full): (GraphMap, GraphMap, FullGraphMap),
+    ) -> Fallible<()> {
+        if let Some(full_graphs) = &mut self.full_graphs {
+            full_graphs.extend(full);
+        }
+        let mut rejected = 0;
+        for (arch, oci, graph) in g
+            .into_iter()
+            .map(|(arch, graph)| (arch, false, graph))
+            .chain(oci_g.into_iter().map(|(arch, graph)| (arch, true, graph)))
+        {
+            let scope = graph::GraphScope {
+                basearch: arch.clone(),
+                stream: self.stream.clone(),
+                oci,
+            };
+            self.rebuilt.remove(&scope);
+            if let Err(e) = self.validate_graph(&graph, &arch, oci) {
+                log::error!(
+                    "refusing to cache invalid graph for {}/{}/oci={}: {}",
+                    arch,
+                    self.stream,
+                    oci,
+                    e
+                );
+                rejected += 1;
+                continue;
+            }
+            if graph.nodes.is_empty() && !self.accept_empty_graph(&arch, oci) {
+                continue;
+            }
+            self.update_cached_graph(arch, oci, graph)?;
+            self.rebuilt.insert(scope);
+        }
+        if self.check_scope_consistency {
+            self.check_scopes();
+        }
+        failure::ensure!(rejected == 0, "{} graph(s) failed validation", rejected);
+        Ok(())
+    }
+
+    /// Report versions present in only one of the OCI and checksum graphs of a basearch.
+    ///
+    /// This usually means that OCI images are lagging behind ostree commits upstream.
+    fn check_scopes(&self) {
+        for arch in &self.arches {
+            let current = |oci| {
+                let scope = graph::GraphScope {
+                    basearch: arch.clone(),
+                    stream: self.stream.clone(),
+                    oci,
+                };
+                self.history.get(&scope).and_then(|h| h.current.clone())
+            };
+            let (checksum, oci) = match (current(false), current(true)) {
+                (Some(checksum), Some(oci)) => (checksum, oci),
+                _ => continue,
+            };
+
+            let diff = oci.graph.diff(&checksum.graph);
+            for (graph_type, missing) in &[
+                ("oci", &diff.removed_nodes),
+                ("checksum", &diff.added_nodes),
+            ] {
+                crate::SCOPE_MISSING_VERSIONS
+                    .with_label_values(&[arch, &self.stream, graph_type])
+                    .set(missing.len() as i64);
+                if !missing.is_empty() {
+                    log::warn!(
+                        "versions missing from {} graph for {}/{}: {}",
+                        graph_type,
+                        arch,
+                        self.stream,
+                        missing.join(", ")
+                    );
+                }
+            }
+        }
+    }
+
+    /// Record an assembled graph without any release, returning whether to cache it.
+    ///
+    /// Such a graph is likely bogus, so it never replaces a non-empty cached one.
+    fn accept_empty_graph(&self, arch: &str, oci: bool) -> bool {
+        let graph_type = if oci { "oci" } else { "checksum" };
+        crate::EMPTY_GRAPHS
+            .with_label_values(&[arch, &self.stream, graph_type])
+            .inc();
+
+        let scope = graph::GraphScope {
+            basearch: arch.to_string(),
+            stream: self.stream.clone(),
+            oci,
+        };
+        let cached_nodes = self
+            .history
+            .get(&scope)
+            .and_then(|h| h.current.as_ref())
+            .map(|c| c.graph.nodes.len())
+            .unwrap_or(0);
+        if cached_nodes > 0 {
+            log::warn!(
+                "assembled empty graph for {}/{}/oci={}, keeping cached one with {} releases",
+                arch,
+                self.stream,
+                oci,
+                cached_nodes
+            );
+            return false;
+        }
+        log::warn!(
+            "assembled empty graph for {}/{}/oci={}",
+            arch,
+            self.stream,
+            oci
+        );
+        true
+    }
+
+    /// Check an assembled graph before caching it.
+    fn validate_graph(
+        &self,
+        graph: &graph::Graph,
+        arch: &str,
+        oci: bool,
+    ) -> Result<(), graph::GraphError> {
+        let graph_type = if oci { "oci" } else { "checksum" };
+        let record = |check: &str| {
+            crate::GRAPH_VALIDATION_ERRORS
+                .with_label_values(&[arch, &self.stream, graph_type, check])
+                .inc();
+        };
+
+        if let Err(e) = graph.check_required_metadata(&self.required_metadata_keys) {
+            record("required_metadata");
+            return Err(e);
+        }
+        if let Err(e) = graph.check_edge_order() {
+            record("edge_order");
+            return Err(e);
+        }
+        if let Err(e) = graph.check_acyclic() {
+            record("acyclic");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Combine release-index and updates metadata.
+    ///
+    /// Graphs still including edges out of dead-ends are also returned, if retained.
+    fn assemble_graphs(&self) -> Fallible<(GraphMap, GraphMap, FullGraphMap)> {
+        let releases = match &self.releases {
+            Some(r) => &r.content,
+            None => failure::bail!("missing release-index for stream '{}'", self.stream),
+        };
+        let updates = match &self.updates {
+            Some(u) => &u.content,
+            None => failure::bail!("missing updates metadata for stream '{}'", self.stream),
+        };
+        let arches = &self.arches;
+        for release in releases
+            .iter()
+            .filter(|r| self.blocked_versions.contains(&r.version))
+        {
+            log::info!(
+                "leaving blocked release out of graphs for stream '{}': version={}",
+                self.stream,
+                release.version
+            );
+        }
+
+        // first the legacy graphs, then the OCI ones
+        let mut map = HashMap::with_capacity(arches.len());
+        let mut oci_map = HashMap::with_capacity(arches.len());
+        let mut full_map = HashMap::new();
+        for (oci, graphs) in [(false, &mut map), (true, &mut oci_map)] {
+            for arch in arches {
+                let scope = graph::GraphScope {
+                    basearch: arch.clone(),
+                    stream: self.stream.clone(),
+                    oci,
+                };
+                let mut full = graph::Graph::from_metadata_with_deadends(
+                    releases.clone(),
+                    updates.clone(),
+                    self.overlay.clone(),
+                    &self.blocked_versions,
+                    scope.clone(),
+                )?;
+                full.merge_node_metadata(
+                    &self.extra_metadata.versions,
+                    self.extra_metadata.overwrite,
+                );
+                let full = policy::redact_reasons(full, &self.reason_redactions);
+                let graph = self.filter_deadends(full.clone(), arch, oci);
+                if self.full_graphs.is_some() {
+                    full_map.insert(scope, Arc::new(full));
+                }
+                graphs.insert(arch.clone(), self.postprocess(graph, arch, oci));
+            }
+        }
+        Ok((map, oci_map, full_map))
+    }
+
+    /// Prune edges out of dead-ends, after applying overrides.
+    fn filter_deadends(&self, graph: graph::Graph, arch: &str, oci: bool) -> graph::Graph {
+        let (graph, overridden) =
+            policy::filter_deadends_with_overrides(graph, &self.deadend_overrides);
+        for version in &overridden {
+            let action = if self.deadend_overrides.ignore.contains(version) {
+                "ignored"
+            } else {
+                "forced"
+            };
+            log::info!(
+                "{} dead-end in graph for {}/{}/oci={}: version={}",
+                action,
+                arch,
+                self.stream,
+                oci,
+                version
+            );
+        }
+        let graph_type = if oci { "oci" } else { "checksum" };
+        crate::DEADEND_OVERRIDES
+            .with_label_values(&[arch, &self.stream, graph_type])
+            .set(overridden.len() as i64);
+        graph
+    }
+
+    /// Re-serialize all cached graphs, e.g. after a metadata key namespace or
+    /// signing key change.
+    fn rerender_graphs(&mut self) -> Fallible<()> {
+        for (scope, history) in self.history.iter_mut() {
+            if let Some(current) = &history.current {
+                let snapshot =
+                    current.rerender(self.key_namespace.as_deref(), self.signing_key.as_ref())?;
+                let snapshot = Arc::new(snapshot);
+                history.current = Some(snapshot.clone());
+                self.store.publish(scope.clone(), snapshot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Prune nodes too far behind the newest one, if configured.
+    fn prune_age_span(&self, graph: graph::Graph, arch: &str, oci: bool) -> graph::Graph {
+        let max_span = match self.max_age_span {
+            Some(span) => span,
+            None => return graph,
+        };
+
+        let (graph, removed) = policy::prune_age_span(graph, max_span);
+        if !removed.is_empty() {
+            log::debug!(
+                "pruned {} old releases from graph for {}/{}/oci={}: versions={}",
+                removed.len(),
+                arch,
+                self.stream,
+                oci,
+                removed.join(",")
+            );
+        }
+        graph
+    }
+
+    /// Apply policies and transforms to an assembled graph.
+    fn postprocess(&self, graph: graph::Graph, arch: &str, oci: bool) -> graph::Graph {
+        let graph = self.prune_age_span(graph, arch, oci);
+        transforms::apply_all(&self.transforms, graph)
+    }
+
+    /// Bump the refresh timestamp for a cached graph.
+    fn mark_refreshed(&mut self, arch: &str, oci: bool) {
+        let graph_type = if oci { "oci" } else { "checksum" };
+        let refresh_timestamp = chrono::Utc::now();
+        crate::LAST_REFRESH
+            .with_label_values(&[arch, &self.stream, graph_type])
+            .set(refresh_timestamp.timestamp());
+        let upstream_modified = std::cmp::max(
+            self.releases.as_ref().and_then(|r| r.last_modified),
+            self.updates.as_ref().and_then(|u| u.last_modified),
+        );
+        if let Some(modified) = upstream_modified {
+            crate::UPSTREAM_LAST_MODIFIED
+                .with_label_values(&[arch, &self.stream, graph_type])
+                .set(modified.timestamp());
+        }
+        self.refreshed.insert(graph::GraphScope {
+            basearch: arch.to_string(),
+            stream: self.stream.clone(),
+            oci,
+        });
+    }
+
+    /// Record which graphs the last scrape refreshed, in sliding windows.
+    ///
+    /// A failed scrape counts as a failure for all graphs, while a partially
+    /// failed one (e.g. some graphs failing validation) only for some of them.
+    fn record_scrape_outcomes(&mut self) {
+        for arch in &self.arches {
+            for &oci in &[false, true] {
+                let scope = graph::GraphScope {
+                    basearch: arch.clone(),
+                    stream: self.stream.clone(),
+                    oci,
+                };
+                let success = self.refreshed.contains(&scope);
+                let graph_type = if oci { "oci" } else { "checksum" };
+                let ratio = self
+                    .scrape_windows
+                    .entry(scope)
+                    .or_default()
+                    .record(success);
+                crate::SCRAPE_SUCCESS_RATIO
+                    .with_label_values(&[arch, &self.stream, graph_type])
+                    .set(ratio);
+            }
+        }
+        self.refreshed.clear();
+    }
+
+    /// Update cached graph.
+    fn update_cached_graph(
+        &mut self,
+        arch: String,
+        oci: bool,
+        graph: graph::Graph,
+    ) -> Result<(), Error> {
+        let scope = graph::GraphScope {
+            basearch: arch.clone(),
+            stream: self.stream.clone(),
+            oci,
+        };
+        self.cache_graph(arch.clone(), oci, graph)?;
+        self.mark_refreshed(&arch, oci);
+
+        let current = self.history.get(&scope).and_then(|h| h.current.as_ref());
+        if let (Some(warm_cache), Some(current)) = (&self.warm_cache, current) {
+            if let Err(e) = warm_cache.store(&scope, &current.graph, current.created) {
+                log::warn!("failed to persist graph to warm cache: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load graphs from the warm cache, if any, to serve until the first refresh.
+    fn load_warm_cache(&mut self) -> Fallible<()> {
+        let warm_cache = match &self.warm_cache {
+            Some(c) => c.clone(),
+            None => return Ok(()),
+        };
+        for arch in self.arches.clone() {
+            for &oci in &[false, true] {
+                let scope = graph::GraphScope {
+                    basearch: arch.clone(),
+                    stream: self.stream.clone(),
+                    oci,
+                };
+                if let Some((graph, created)) = warm_cache.load(&scope) {
+                    if let Err(e) = self.validate_graph(&graph, &arch, oci) {
+                        log::warn!("ignoring invalid warm cached graph: {}", e);
+                        continue;
+                    }
+                    log::info!(
+                        "serving warm cached graph for {}/{}/oci={} until first refresh",
+                        arch,
+                        self.stream,
+                        oci
+                    );
+                    self.cache_graph_at(arch.clone(), oci, graph, created)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a freshly assembled graph in memory, for serving.
+    fn cache_graph(&mut self, arch: String, oci: bool, graph: graph::Graph) -> Fallible<()> {
+        self.cache_graph_at(arch, oci, graph, chrono::Utc::now())
+    }
+
+    /// Store a graph first cached at `created` in memory, for serving.
+    ///
+    /// The graph replaces the previous one for its scope in a single swap.
+    fn cache_graph_at(
+        &mut self,
+        arch: String,
+        oci: bool,
+        graph: graph::Graph,
+        created: chrono::DateTime<chrono::Utc>,
+    ) -> Fallible<()> {
+        let graph_type = if oci { "oci" } else { "checksum" };
+
+        crate::GRAPH_FINAL_EDGES
+            .with_label_values(&[&arch, &self.stream, graph_type])
+            .set(graph.edges.len() as i64);
+        let (rollout_edges, barrier_edges) = graph.edge_sources();
+        crate::GRAPH_EDGES_BY_SOURCE
+            .with_label_values(&[&arch, &self.stream, graph_type, "rollouts"])
+            .set(rollout_edges as i64);
+        crate::GRAPH_EDGES_BY_SOURCE
+            .with_label_values(&[&arch, &self.stream, graph_type, "barriers"])
+            .set(barrier_edges as i64);
+        crate::GRAPH_FINAL_RELEASES
+            .with_label_values(&[&arch, &self.stream, graph_type])
+            .set(graph.nodes.len() as i64);
+
+        log::trace!(
+            "cached graph for {}/{}/oci={}: releases={}, edges={}",
+            &arch,
+            self.stream,
+            oci,
+            graph.nodes.len(),
+            graph.edges.len()
+        );
+
+        let scope = graph::GraphScope {
+            basearch: arch.clone(),
+            stream: self.stream.clone(),
+            oci,
+        };
+        let upstream_schema_version = self
+            .updates
+            .as_ref()
+            .map(|u| u.content.schema_version().to_string());
+        let snapshot = Arc::new(GraphSnapshot::new(
+            graph,
+            self.key_namespace.as_deref(),
+            self.signing_key.as_ref(),
+            upstream_schema_version,
+            created,
+        )?);
+        let history = self.history.entry(scope.clone()).or_default();
+        history.push(snapshot, self.diff_history_len);
+        if let Some(current) = history.current.clone() {
+            self.store.publish(scope, current);
+        }
+        Ok(())
+    }
+}
+
+impl Actor for Scraper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // Kick-start the state machine, possibly giving the environment
+        // (e.g. DNS or proxies) some time to settle first.
+        match self.initial_delay {
+            Some(delay) => {
+                log::info!(
+                    "delaying first scrape for stream '{}' by {}s",
+                    self.stream,
+                    delay.as_secs()
+                );
+                self.next_tick = Some(Self::tick_later(ctx, delay));
+            }
+            None => Self::tick_now(ctx),
+        }
+    }
+}
+
+pub(crate) struct RefreshTick {}
+
+impl Message for RefreshTick {
+    type Result = Result<(), failure::Error>;
+}
+
+impl Handler<RefreshTick> for Scraper {
+    type Result = ResponseActFuture<Self, Result<(), failure::Error>>;
+
+    fn handle(&mut self, _msg: RefreshTick, _ctx: &mut Self::Context) -> Self::Result {
+        self.next_tick = None;
+        if self.frozen {
+            // Refreshes are resumed on unfreeze.
+            log::debug!("skipping refresh for frozen stream '{}'", self.stream);
+            return Box::new(actix::fut::ok(()));
+        }
+        if self.refreshing {
+            // The in-flight refresh schedules the next one.
+            return Box::new(actix::fut::ok(()));
+        }
+        self.refreshing = true;
+
+        let update_graphs = self
+            .refresh()
+            .map(|res, _actor, _ctx| {
+                if let Err(e) = res {
                     log::error!("transient scraping failure: {}", e);
                 };
             })
             .then(|_r, actor, ctx| {
+                actor.refreshing = false;
                 let pause = Duration::from_secs(actor.pause_secs.get());
-                Self::tick_later(ctx, pause);
+                actor.next_tick = Some(Self::tick_later(ctx, pause));
                 actix::fut::ok(())
             });
 
@@ -238,48 +1674,521 @@ impl Handler<RefreshTick> for Scraper {
     }
 }
 
-pub(crate) struct GetCachedGraph {
+/// Request an immediate refresh, outside of the regular schedule.
+pub(crate) struct RefreshNow {}
+
+/// Outcome of an on-demand refresh, unless scraping failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RefreshNowResult {
+    Refreshed,
+    /// Refreshes are paused.
+    Frozen,
+    /// Another refresh is in progress.
+    InProgress,
+}
+
+impl Message for RefreshNow {
+    type Result = Result<RefreshNowResult, failure::Error>;
+}
+
+impl Handler<RefreshNow> for Scraper {
+    type Result = ResponseActFuture<Self, Result<RefreshNowResult, failure::Error>>;
+
+    fn handle(&mut self, _msg: RefreshNow, ctx: &mut Self::Context) -> Self::Result {
+        if self.frozen {
+            return Box::new(actix::fut::ok(RefreshNowResult::Frozen));
+        }
+        if self.refreshing {
+            return Box::new(actix::fut::ok(RefreshNowResult::InProgress));
+        }
+
+        log::info!("on-demand refresh for stream '{}'", self.stream);
+        self.refreshing = true;
+        // This replaces the next scheduled refresh.
+        if let Some(handle) = self.next_tick.take() {
+            ctx.cancel_future(handle);
+        }
+        let refresh = self.refresh().then(|res, actor, ctx| {
+            actor.refreshing = false;
+            let pause = Duration::from_secs(actor.pause_secs.get());
+            actor.next_tick = Some(Self::tick_later(ctx, pause));
+            actix::fut::result(res.map(|_| RefreshNowResult::Refreshed))
+        });
+        Box::new(refresh)
+    }
+}
+
+/// An upstream metadata document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UpstreamDocument {
+    ReleaseIndex,
+    Updates,
+}
+
+impl std::str::FromStr for UpstreamDocument {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "release-index" => Ok(UpstreamDocument::ReleaseIndex),
+            "updates" => Ok(UpstreamDocument::Updates),
+            _ => Err(failure::format_err!(
+                "unknown upstream document '{}'",
+                input
+            )),
+        }
+    }
+}
+
+/// An upstream metadata document, as last fetched.
+#[derive(Clone, Debug)]
+pub(crate) struct RawUpstream {
+    pub(crate) data: Bytes,
+    pub(crate) etag: Option<String>,
+}
+
+pub(crate) struct GetRawUpstream {
+    pub(crate) document: UpstreamDocument,
+}
+
+impl Message for GetRawUpstream {
+    /// The document, or `None` if not fetched yet.
+    type Result = Option<RawUpstream>;
+}
+
+impl Handler<GetRawUpstream> for Scraper {
+    type Result = MessageResult<GetRawUpstream>;
+
+    fn handle(&mut self, msg: GetRawUpstream, _ctx: &mut Self::Context) -> Self::Result {
+        fn raw<T>(cached: &Option<CachedUpstream<T>>) -> Option<RawUpstream> {
+            cached.as_ref().map(|c| RawUpstream {
+                data: c.raw.clone(),
+                etag: c
+                    .etag
+                    .as_ref()
+                    .and_then(|e| e.to_str().ok())
+                    .map(String::from),
+            })
+        }
+
+        let doc = match msg.document {
+            UpstreamDocument::ReleaseIndex => raw(&self.releases),
+            UpstreamDocument::Updates => raw(&self.updates),
+        };
+        MessageResult(doc)
+    }
+}
+
+pub(crate) struct GetFullGraph {
     pub(crate) scope: graph::GraphScope,
 }
 
-impl Message for GetCachedGraph {
-    type Result = Result<Bytes, Error>;
+impl Message for GetFullGraph {
+    /// The serialized graph including edges out of dead-ends, or `None` if
+    /// not retained or not assembled yet.
+    type Result = Result<Option<Bytes>, Error>;
 }
 
-impl Handler<GetCachedGraph> for Scraper {
-    type Result = ResponseActFuture<Self, Result<Bytes, Error>>;
+impl Handler<GetFullGraph> for Scraper {
+    type Result = Result<Option<Bytes>, Error>;
 
-    fn handle(&mut self, msg: GetCachedGraph, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: GetFullGraph, _ctx: &mut Self::Context) -> Self::Result {
+        let full = match self.full_graphs.as_ref().and_then(|g| g.get(&msg.scope)) {
+            Some(full) => full,
+            None => return Ok(None),
+        };
+        let data = match &self.key_namespace {
+            Some(namespace) => {
+                let mut graph = graph::Graph::clone(full);
+                graph.rename_key_namespace(namespace);
+                serde_json::to_vec_pretty(&graph)?
+            }
+            None => serde_json::to_vec_pretty(full.as_ref())?,
+        };
+        Ok(Some(Bytes::from(data)))
+    }
+}
+
+/// Compute the (strong) entity tag of a serialized graph.
+pub(crate) fn graph_etag(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("\"{:x}\"", Sha256::digest(data))
+}
+
+/// A serialized cached graph.
+#[derive(Clone, Debug)]
+pub(crate) struct CachedGraph {
+    pub(crate) data: Bytes,
+    /// Entity tag of `data`, computed once when rendering it.
+    pub(crate) etag: String,
+    /// When the graph last changed, if known.
+    pub(crate) last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// Signature of the whole graph, if signed and served as a whole.
+    pub(crate) signature: Option<String>,
+}
+
+/// Outcome of a cached graph lookup.
+#[derive(Clone, Debug)]
+pub(crate) enum CachedGraphResult {
+    Graph(CachedGraph),
+    /// The basearch is not configured for this stream.
+    UnknownScope,
+    /// No graph was published yet for this scope.
+    NotReady,
+}
+
+/// Runtime settings which can be changed on configuration reload.
+pub(crate) struct ReloadSettings {
+    pub(crate) blocked_versions: HashSet<String>,
+    pub(crate) deadend_overrides: policy::DeadendOverrides,
+    pub(crate) transforms: Vec<Transform>,
+    pub(crate) max_age_span: Option<u64>,
+    pub(crate) key_namespace: Option<String>,
+    pub(crate) signing_key: Option<SigningKey>,
+    pub(crate) extra_metadata: settings::ExtraMetadata,
+    pub(crate) reason_redactions: policy::ReasonRedactions,
+    pub(crate) frozen: bool,
+    pub(crate) required_metadata_keys: Vec<String>,
+    pub(crate) check_scope_consistency: bool,
+}
+
+impl ReloadSettings {
+    /// Extract runtime settings for a stream.
+    pub(crate) fn for_stream(settings: &settings::ServiceSettings, stream: &str) -> Self {
+        Self {
+            blocked_versions: settings
+                .blocked_versions
+                .get(stream)
+                .cloned()
+                .unwrap_or_default(),
+            deadend_overrides: settings
+                .deadend_overrides
+                .get(stream)
+                .cloned()
+                .unwrap_or_default(),
+            transforms: settings.transforms.clone(),
+            max_age_span: settings.max_age_span,
+            key_namespace: settings.metadata_key_namespace.clone(),
+            signing_key: settings.signing_key.clone(),
+            extra_metadata: settings.extra_metadata.clone(),
+            reason_redactions: settings.reason_redactions.clone(),
+            frozen: settings.frozen,
+            required_metadata_keys: settings.required_metadata_keys.clone(),
+            check_scope_consistency: settings.check_scope_consistency,
+        }
+    }
+}
+
+impl Message for ReloadSettings {
+    type Result = ();
+}
+
+impl Handler<ReloadSettings> for Scraper {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReloadSettings, ctx: &mut Self::Context) -> Self::Result {
+        if msg.frozen != self.frozen {
+            self.set_frozen(msg.frozen, ctx);
+        }
+        // Only checked on the next refresh.
+        self.required_metadata_keys = msg.required_metadata_keys;
+        self.check_scope_consistency = msg.check_scope_consistency;
+        if msg.key_namespace != self.key_namespace || msg.signing_key != self.signing_key {
+            self.key_namespace = msg.key_namespace;
+            self.signing_key = msg.signing_key;
+            if let Err(e) = self.rerender_graphs() {
+                log::error!("failed to re-serialize graphs after reload: {}", e);
+            }
+        }
+
+        if msg.blocked_versions == self.blocked_versions
+            && msg.deadend_overrides == self.deadend_overrides
+            && msg.transforms == self.transforms
+            && msg.max_age_span == self.max_age_span
+            && msg.extra_metadata == self.extra_metadata
+            && msg.reason_redactions == self.reason_redactions
+        {
+            return;
+        }
+        self.blocked_versions = msg.blocked_versions;
+        self.deadend_overrides = msg.deadend_overrides;
+        self.transforms = msg.transforms;
+        self.max_age_span = msg.max_age_span;
+        self.extra_metadata = msg.extra_metadata;
+        self.reason_redactions = msg.reason_redactions;
+
+        // Apply new settings right away, if upstream metadata is already available.
+        if self.releases.is_some() && self.updates.is_some() {
+            if let Err(e) = self.rebuild_graphs() {
+                log::error!("failed to rebuild graphs after reload: {}", e);
+            }
+        }
+    }
+}
+
+pub(crate) struct GetBarriers {}
+
+impl Message for GetBarriers {
+    type Result = Vec<ScopeBarriers>;
+}
+
+impl Handler<GetBarriers> for Scraper {
+    type Result = MessageResult<GetBarriers>;
+
+    fn handle(&mut self, _msg: GetBarriers, _ctx: &mut Self::Context) -> Self::Result {
+        let mut scopes: Vec<_> = self.history.iter().collect();
+        scopes.sort_by(|a, b| a.0.cmp(b.0));
+
+        let barriers = scopes
+            .into_iter()
+            .filter_map(|(scope, history)| {
+                let current = history.current.as_ref()?;
+                let barriers = current
+                    .graph
+                    .nodes
+                    .iter()
+                    .filter(|n| {
+                        n.metadata.get(metadata::BARRIER).map(String::as_str) == Some("true")
+                    })
+                    .map(|n| Barrier {
+                        version: n.version.clone(),
+                        reason: n
+                            .metadata
+                            .get(metadata::BARRIER_REASON)
+                            .cloned()
+                            .unwrap_or_default(),
+                    })
+                    .collect();
+                Some(ScopeBarriers {
+                    stream: scope.stream.clone(),
+                    basearch: scope.basearch.clone(),
+                    oci: scope.oci,
+                    barriers,
+                })
+            })
+            .collect();
+        MessageResult(barriers)
+    }
+}
+
+/// Baseline of a graph diff.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DiffBaseline {
+    /// Graph with this digest.
+    Digest(String),
+    /// Graph which was current at this time.
+    Time(chrono::DateTime<chrono::Utc>),
+}
+
+impl std::str::FromStr for DiffBaseline {
+    type Err = Error;
+
+    /// Parse an RFC 3339 timestamp, or else a digest.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        failure::ensure!(!input.is_empty(), "empty baseline");
+        let baseline = match chrono::DateTime::parse_from_rfc3339(input) {
+            Ok(time) => DiffBaseline::Time(time.with_timezone(&chrono::Utc)),
+            Err(_) => DiffBaseline::Digest(input.to_string()),
+        };
+        Ok(baseline)
+    }
+}
+
+/// Outcome of a graph diff request.
+#[derive(Clone, Debug)]
+pub(crate) enum GraphDiffResult {
+    Diff(ScopedGraphDiff),
+    /// The basearch is not configured for this stream.
+    UnknownScope,
+    /// No graph was cached yet for this scope.
+    NotReady,
+    /// The baseline digest is not retained.
+    UnknownDigest,
+    /// The baseline time is before the oldest retained graph, from this time.
+    TooOld(chrono::DateTime<chrono::Utc>),
+}
+
+pub(crate) struct GetGraphDiff {
+    pub(crate) scope: graph::GraphScope,
+    /// Baseline graph, defaulting to the previous refresh.
+    pub(crate) since: Option<DiffBaseline>,
+}
+
+impl Message for GetGraphDiff {
+    type Result = Result<GraphDiffResult, Error>;
+}
+
+impl Handler<GetGraphDiff> for Scraper {
+    type Result = Result<GraphDiffResult, Error>;
+
+    fn handle(&mut self, msg: GetGraphDiff, _ctx: &mut Self::Context) -> Self::Result {
         use failure::format_err;
-        let graph_type = if msg.scope.oci { "oci" } else { "checksum" };
 
         if msg.scope.stream != self.stream {
-            return Box::new(actix::fut::err(format_err!(
-                "unexpected stream '{}'",
-                msg.scope.stream
-            )));
+            return Err(format_err!("unexpected stream '{}'", msg.scope.stream));
         }
-        let target_graphmap = if msg.scope.oci {
-            &self.oci_graphs
-        } else {
-            &self.graphs
+        if !self.arches.contains(&msg.scope.basearch) {
+            return Ok(GraphDiffResult::UnknownScope);
+        }
+        let (history, current) = match self.history.get(&msg.scope) {
+            Some(h) => match &h.current {
+                Some(c) => (h, c),
+                None => return Ok(GraphDiffResult::NotReady),
+            },
+            None => return Ok(GraphDiffResult::NotReady),
         };
-        if let Some(graph) = target_graphmap.get(&msg.scope.basearch) {
-            crate::CACHED_GRAPH_REQUESTS
-                .with_label_values(&[&msg.scope.basearch, &msg.scope.stream, graph_type])
-                .inc();
 
-            Box::new(actix::fut::ok(graph.clone()))
-        } else {
-            Box::new(actix::fut::err(format_err!(
-                "unexpected basearch '{}'",
-                msg.scope.basearch
-            )))
-        }
+        let baseline = match msg.since {
+            None => history.past.back(),
+            Some(DiffBaseline::Digest(digest)) => match history.find_digest(&digest) {
+                Some(snapshot) => Some(snapshot),
+                None => return Ok(GraphDiffResult::UnknownDigest),
+            },
+            Some(DiffBaseline::Time(time)) => match history.find_time(time) {
+                Ok(snapshot) => Some(snapshot),
+                Err(oldest) => return Ok(GraphDiffResult::TooOld(oldest)),
+            },
+        };
+
+        let empty = graph::Graph::default();
+        let diff = current
+            .graph
+            .diff(baseline.map(|b| &b.graph).unwrap_or(&empty));
+        Ok(GraphDiffResult::Diff(ScopedGraphDiff {
+            from_digest: baseline.map(|b| b.digest.clone()),
+            to_digest: current.digest.clone(),
+            diff,
+        }))
     }
 }
 
 impl Scraper {
+    /// Fetch upstream metadata and refresh cached graphs.
+    fn refresh(&mut self) -> impl ActorFuture<Output = Fallible<()>, Actor = Self> {
+        let upstream = self.fetch_upstream();
+        let permits = self.initial_permits.take();
+        let stream = self.stream.clone();
+        let scrape = async move {
+            // On cold start, hold a permit until graphs are rebuilt.
+            let permit = match permits {
+                Some(permits) => Some(permits.acquire_owned().await),
+                None => None,
+            };
+            crate::UPSTREAM_SCRAPES.with_label_values(&[&stream]).inc();
+            (upstream.await, permit)
+        };
+        actix::fut::wrap_future::<_, Self>(scrape).map(|(upstream, _permit), actor, _ctx| {
+            actor.refreshed.clear();
+            let res = upstream.and_then(|content| actor.refresh_graphs(content));
+            actor.record_scrape_outcomes();
+            res
+        })
+    }
+
+    /// Scrape upstream once, before starting the actor.
+    ///
+    /// This fails unless the graphs for all scopes of this stream were
+    /// refreshed. Regular refreshes then start right away once started.
+    pub(crate) async fn initial_scrape(mut self) -> Fallible<Self> {
+        if let Some(delay) = self.initial_delay.take() {
+            log::info!(
+                "delaying first scrape for stream '{}' by {}s",
+                self.stream,
+                delay.as_secs()
+            );
+            actix::clock::delay_for(delay).await;
+        }
+        let _permit = match self.initial_permits.take() {
+            Some(permits) => Some(permits.acquire_owned().await),
+            None => None,
+        };
+        crate::UPSTREAM_SCRAPES
+            .with_label_values(&[&self.stream])
+            .inc();
+        let upstream = self.fetch_upstream().await;
+        self.refreshed.clear();
+        let res = upstream.and_then(|content| self.refresh_graphs(content));
+
+        let mut missing = vec![];
+        for arch in &self.arches {
+            for &oci in &[false, true] {
+                let scope = graph::GraphScope {
+                    basearch: arch.clone(),
+                    stream: self.stream.clone(),
+                    oci,
+                };
+                if !self.refreshed.contains(&scope) {
+                    missing.push(format!("{}/oci={}", arch, oci));
+                }
+            }
+        }
+        self.record_scrape_outcomes();
+
+        if let Err(e) = res {
+            failure::bail!("failed to scrape stream '{}': {}", self.stream, e);
+        }
+        failure::ensure!(
+            missing.is_empty(),
+            "failed to refresh graphs for stream '{}': {}",
+            self.stream,
+            missing.join(", ")
+        );
+        Ok(self)
+    }
+
+    /// Current graphs of this scraper, for serving them directly.
+    pub(crate) fn graph_store(&self) -> GraphStore {
+        self.store.clone()
+    }
+
+    /// Name of the stream served by this scraper.
+    pub(crate) fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    /// Bound the first scrape of this stream with permits shared across streams.
+    pub(crate) fn with_initial_permits(
+        mut self,
+        permits: Option<Arc<tokio::sync::Semaphore>>,
+    ) -> Self {
+        self.initial_permits = permits;
+        self
+    }
+
+    /// Retain this many past graphs per scope, for diffing.
+    pub(crate) fn with_diff_history_len(mut self, len: usize) -> Self {
+        self.diff_history_len = len;
+        self
+    }
+
+    /// Retain assembled graphs including edges out of dead-ends, for auditing.
+    pub(crate) fn with_full_graphs(mut self, retain: bool) -> Self {
+        self.full_graphs = if retain { Some(HashMap::new()) } else { None };
+        self
+    }
+
+    /// Pause or resume refreshes.
+    ///
+    /// On resume, a refresh is triggered immediately.
+    fn set_frozen(&mut self, frozen: bool, ctx: &mut Context<Self>) {
+        log::info!(
+            "{} refreshes for stream '{}'",
+            if frozen { "freezing" } else { "resuming" },
+            self.stream
+        );
+        self.frozen = frozen;
+        crate::SCRAPER_FROZEN
+            .with_label_values(&[&self.stream])
+            .set(frozen as i64);
+
+        if !frozen && !self.refreshing {
+            if let Some(handle) = self.next_tick.take() {
+                ctx.cancel_future(handle);
+            }
+            Self::tick_now(ctx);
+        }
+    }
+
     /// Schedule an immediate refresh of the state machine.
     pub fn tick_now(ctx: &mut Context<Self>) {
         ctx.notify(RefreshTick {})
@@ -290,3 +2199,753 @@ impl Scraper {
         ctx.notify_later(RefreshTick {}, after)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph_with_version(version: &str) -> graph::Graph {
+        graph::Graph {
+            nodes: vec![graph::CincinnatiPayload {
+                version: version.to_string(),
+                metadata: HashMap::new(),
+                payload: format!("payload-{}", version),
+            }],
+            edges: vec![],
+        }
+    }
+
+    fn test_scraper() -> Scraper {
+        test_scraper_with(&settings::UpstreamSettings::default(), None)
+    }
+
+    fn test_scraper_with(
+        upstream: &settings::UpstreamSettings,
+        warm_cache: Option<WarmCache>,
+    ) -> Scraper {
+        // Frozen, so that no upstream refresh kicks in.
+        let runtime = ReloadSettings {
+            blocked_versions: HashSet::new(),
+            deadend_overrides: policy::DeadendOverrides::default(),
+            transforms: vec![],
+            max_age_span: None,
+            key_namespace: None,
+            signing_key: None,
+            extra_metadata: settings::ExtraMetadata::default(),
+            reason_redactions: policy::ReasonRedactions::default(),
+            frozen: true,
+            required_metadata_keys: vec![],
+            check_scope_consistency: false,
+        };
+        Scraper::new(
+            "stable".to_string(),
+            vec!["x86_64".to_string()],
+            None,
+            "test",
+            upstream,
+            warm_cache,
+            runtime,
+        )
+        .unwrap()
+    }
+
+    /// Return the cached graph for a scope, which must have been published.
+    fn served_graph(
+        store: &GraphStore,
+        scope: &graph::GraphScope,
+        variant: GraphVariant,
+    ) -> CachedGraph {
+        match store.cached_graph(scope, variant).unwrap() {
+            CachedGraphResult::Graph(graph) => graph,
+            other => panic!("unexpected cached graph result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_warm_cached_graph() {
+        use chrono::TimeZone;
+
+        let dir = std::env::temp_dir().join(format!("gb-scraper-warm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let warm_cache = WarmCache::new(&dir);
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let created = chrono::Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        warm_cache
+            .store(&scope, &graph_with_version("1"), created)
+            .unwrap();
+
+        // Warm cached graphs are served right away, as of when first cached.
+        let mut scraper = test_scraper_with(
+            &settings::UpstreamSettings::default(),
+            Some(warm_cache.clone()),
+        );
+        let served = served_graph(&scraper.graph_store(), &scope, GraphVariant::default());
+        assert_eq!(served.last_modified, Some(created));
+
+        // Refreshing to the same graph keeps that time.
+        scraper
+            .update_cached_graph("x86_64".to_string(), false, graph_with_version("1"))
+            .unwrap();
+        let served = served_graph(&scraper.graph_store(), &scope, GraphVariant::default());
+        assert_eq!(served.last_modified, Some(created));
+        assert_eq!(warm_cache.load(&scope).unwrap().1, created);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_swap() {
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        // Nothing is served until a graph is published.
+        let served = scraper
+            .graph_store()
+            .cached_graph(&scope, GraphVariant::default())
+            .unwrap();
+        assert!(matches!(served, CachedGraphResult::NotReady));
+
+        scraper
+            .cache_graph("x86_64".to_string(), false, graph_with_version("1"))
+            .unwrap();
+        let before = served_graph(&scraper.graph_store(), &scope, GraphVariant::default());
+        let mut two = graph_with_version("1");
+        two.nodes.push(graph_with_version("2").nodes.remove(0));
+        two.edges.push((0, 1));
+        scraper
+            .cache_graph("x86_64".to_string(), false, two)
+            .unwrap();
+        let after = served_graph(&scraper.graph_store(), &scope, GraphVariant::default());
+
+        // Earlier readers keep their snapshot, later ones see the new one whole.
+        let before: graph::Graph = serde_json::from_slice(&before.data).unwrap();
+        assert_eq!(before.nodes.len(), 1);
+        assert!(before.edges.is_empty());
+        let after: graph::Graph = serde_json::from_slice(&after.data).unwrap();
+        assert_eq!(after.nodes.len(), 2);
+        assert_eq!(after.edges, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_graph_history() {
+        use chrono::TimeZone;
+
+        let at = |secs| chrono::Utc.timestamp_opt(secs, 0).unwrap();
+        let snapshot = |version, secs| {
+            let s = GraphSnapshot::new(graph_with_version(version), None, None, None, at(secs));
+            Arc::new(s.unwrap())
+        };
+
+        let mut history = GraphHistory::default();
+        for (version, secs) in &[("1", 100), ("2", 200), ("3", 300)] {
+            history.push(snapshot(version, *secs), 1);
+        }
+        // An unchanged graph does not rotate history.
+        history.push(snapshot("3", 400), 1);
+        assert_eq!(history.current.as_ref().unwrap().created, at(300));
+        assert_eq!(history.past.len(), 1);
+
+        let digest = |s: &Arc<GraphSnapshot>| s.graph.nodes[0].version.clone();
+        assert_eq!(digest(history.find_time(at(250)).unwrap()), "2");
+        assert_eq!(digest(history.find_time(at(300)).unwrap()), "3");
+        assert_eq!(history.find_time(at(150)).unwrap_err(), at(200));
+
+        let previous = history.past[0].digest.clone();
+        assert_eq!(digest(history.find_digest(&previous).unwrap()), "2");
+        let removed = snapshot("1", 100).digest.clone();
+        assert!(history.find_digest(&removed).is_none());
+
+        assert_eq!(
+            "2022-05-05T10:00:00Z".parse::<DiffBaseline>().unwrap(),
+            DiffBaseline::Time(
+                chrono::DateTime::parse_from_rfc3339("2022-05-05T10:00:00Z")
+                    .unwrap()
+                    .with_timezone(&chrono::Utc)
+            )
+        );
+        assert_eq!(
+            "sha256:abc".parse::<DiffBaseline>().unwrap(),
+            DiffBaseline::Digest("sha256:abc".to_string())
+        );
+    }
+
+    #[test]
+    fn test_graph_signature() {
+        use openssl::pkey::PKey;
+        use openssl::sign::Verifier;
+
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let private = PKey::generate_ed25519().unwrap();
+        let pem = private.private_key_to_pem_pkcs8().unwrap();
+        scraper.signing_key = Some(SigningKey::from_pem(&pem).unwrap());
+        scraper
+            .cache_graph("x86_64".to_string(), false, graph_with_version("1"))
+            .unwrap();
+
+        // Clients verify the canonical form of the (pretty) served graph.
+        let served = served_graph(&scraper.graph_store(), &scope, GraphVariant::default());
+        let signature = base64::decode(served.signature.unwrap()).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&served.data).unwrap();
+        let canonical = serde_json::to_vec(&parsed).unwrap();
+        let public = PKey::public_key_from_raw_bytes(
+            &private.raw_public_key().unwrap(),
+            openssl::pkey::Id::ED25519,
+        )
+        .unwrap();
+        let mut verifier = Verifier::new_without_digest(&public).unwrap();
+        assert!(verifier.verify_oneshot(&signature, &canonical).unwrap());
+
+        // Partial views of the graph are not covered.
+        let versions = GraphVariant {
+            format: GraphFormat::Versions,
+            ..GraphVariant::default()
+        };
+        let served = served_graph(&scraper.graph_store(), &scope, versions);
+        assert!(served.signature.is_none());
+    }
+
+    #[test]
+    fn test_weighted_format() {
+        let mut graph = graph_with_version("1");
+        graph.nodes.push(graph_with_version("2").nodes.remove(0));
+        graph.nodes.push(graph_with_version("3").nodes.remove(0));
+        graph.nodes[2]
+            .metadata
+            .insert(metadata::DEADEND.to_string(), "true".to_string());
+        graph.edges = vec![(0, 1), (0, 2)];
+
+        let variant = GraphVariant {
+            format: "weighted".parse().unwrap(),
+            ..GraphVariant::default()
+        };
+        // Dead-ends are still detected with a custom key namespace.
+        let data = variant
+            .render(&graph, Some("com.example"), &Provenance::default())
+            .unwrap();
+        let weighted: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(weighted["edges"], serde_json::json!([[0, 1], [0, 2]]));
+        assert_eq!(weighted["edge_priorities"], serde_json::json!([0, 1]));
+        assert_eq!(
+            weighted["nodes"][2]["metadata"]["com.example.updates.deadend"],
+            "true"
+        );
+        assert!(!variant.is_whole_graph());
+    }
+
+    #[test]
+    fn test_node_fields() {
+        let variant = GraphVariant {
+            format: GraphFormat::Compact,
+            fields: Some("payload, version".parse().unwrap()),
+            ..GraphVariant::default()
+        };
+        let data = variant
+            .render(&graph_with_version("1"), None, &Provenance::default())
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&data).unwrap(),
+            r#"{"nodes":[{"version":"1","payload":"payload-1"}],"edges":[]}"#
+        );
+        assert!(!variant.is_whole_graph());
+
+        assert!("metadata".parse::<NodeFields>().is_ok());
+        assert!("".parse::<NodeFields>().is_err());
+        assert!("version,".parse::<NodeFields>().is_err());
+        assert!("version,channels".parse::<NodeFields>().is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        use std::io::Read;
+
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        scraper
+            .cache_graph("x86_64".to_string(), false, graph_with_version("1"))
+            .unwrap();
+        let plain = served_graph(&scraper.graph_store(), &scope, GraphVariant::default());
+        for level in 1..=9 {
+            let variant = GraphVariant {
+                gzip_level: Some(level),
+                ..GraphVariant::default()
+            };
+            assert!(variant.is_whole_graph());
+            let served = served_graph(&scraper.graph_store(), &scope, variant);
+            assert_eq!(served.signature, plain.signature);
+
+            let mut decoded = vec![];
+            flate2::read::GzDecoder::new(served.data.as_ref())
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, plain.data.as_ref());
+        }
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        scraper
+            .cache_graph("x86_64".to_string(), false, graph_with_version("1"))
+            .unwrap();
+        let variant = GraphVariant {
+            format: GraphFormat::Compact,
+            provenance: true,
+            ..GraphVariant::default()
+        };
+        let served = served_graph(&scraper.graph_store(), &scope, variant);
+        let graph: serde_json::Value = serde_json::from_slice(&served.data).unwrap();
+        let generated_at = graph["generated_at"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(generated_at).is_ok());
+        assert_eq!(graph["nodes"][0]["version"], "1");
+        // No upstream metadata in this scraper.
+        assert!(graph.get("upstream_schema_version").is_none());
+        assert!(served.signature.is_none());
+
+        // Strict clients see only nodes and edges by default.
+        let data = GraphVariant::default()
+            .render(&graph_with_version("1"), None, &Provenance::default())
+            .unwrap();
+        let graph: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        let mut keys: Vec<_> = graph.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["edges", "nodes"]);
+    }
+
+    #[test]
+    fn test_scrape_success_ratio() {
+        let mut window = ScrapeWindow::default();
+        assert_eq!(window.record(false), 0.0);
+        assert_eq!(window.record(true), 0.5);
+        for _ in 0..SCRAPE_WINDOW_LEN - 1 {
+            window.record(true);
+        }
+        // The first failure slid out of the window.
+        assert_eq!(window.record(false), 0.95);
+
+        let mut scraper = test_scraper();
+        scraper.mark_refreshed("x86_64", false);
+        scraper.record_scrape_outcomes();
+        scraper.record_scrape_outcomes();
+        let ratio = |graph_type| {
+            crate::SCRAPE_SUCCESS_RATIO
+                .with_label_values(&["x86_64", "stable", graph_type])
+                .get()
+        };
+        assert_eq!(ratio("checksum"), 0.5);
+        assert_eq!(ratio("oci"), 0.0);
+    }
+
+    #[test]
+    fn test_unchanged_after_rejected() {
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let releases: Vec<metadata::Release> = serde_json::from_str(
+            r#"[{"version": "1", "commits": [{"architecture": "x86_64", "checksum": "a"}]}]"#,
+        )
+        .unwrap();
+        let updates: metadata::UpdatesJSON =
+            serde_json::from_str(r#"{"stream": "stable", "releases": []}"#).unwrap();
+        let upstream = || {
+            (
+                Some(CachedUpstream {
+                    etag: None,
+                    last_modified: None,
+                    content: releases.clone(),
+                    raw: Bytes::new(),
+                }),
+                Some(CachedUpstream {
+                    etag: None,
+                    last_modified: None,
+                    content: updates.clone(),
+                    raw: Bytes::new(),
+                }),
+            )
+        };
+
+        // Rejected, then unchanged upstream: still not refreshed.
+        scraper.required_metadata_keys = vec!["com.example.missing".to_string()];
+        scraper.refresh_graphs(upstream()).unwrap_err();
+        scraper.record_scrape_outcomes();
+        scraper.refresh_graphs((None, None)).unwrap();
+        assert!(!scraper.refreshed.contains(&scope));
+
+        // Accepted, then unchanged upstream: refreshed.
+        scraper.required_metadata_keys = vec![];
+        scraper.refresh_graphs(upstream()).unwrap();
+        scraper.record_scrape_outcomes();
+        scraper.refresh_graphs((None, None)).unwrap();
+        assert!(scraper.refreshed.contains(&scope));
+    }
+
+    #[test]
+    fn test_unchanged_after_failed_assembly() {
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let releases = |json: &str| {
+            Some(CachedUpstream {
+                etag: Some(HeaderValue::from_static("\"releases\"")),
+                last_modified: None,
+                content: serde_json::from_str::<Vec<metadata::Release>>(json).unwrap(),
+                raw: Bytes::new(),
+            })
+        };
+        let updates = Some(CachedUpstream {
+            etag: None,
+            last_modified: None,
+            content: serde_json::from_str(r#"{"stream": "stable", "releases": []}"#).unwrap(),
+            raw: Bytes::new(),
+        });
+        let good =
+            r#"[{"version": "1", "commits": [{"architecture": "x86_64", "checksum": "a"}]}]"#;
+        scraper.refresh_graphs((releases(good), updates)).unwrap();
+        scraper.record_scrape_outcomes();
+
+        // Failed, then unchanged upstream: not refreshed.
+        scraper.refresh_graphs((releases("[]"), None)).unwrap_err();
+        scraper.record_scrape_outcomes();
+        scraper.refresh_graphs((None, None)).unwrap();
+        assert!(!scraper.refreshed.contains(&scope));
+
+        // The last good metadata is kept, so that the failed one is fetched again.
+        let kept = scraper.releases.as_ref().unwrap();
+        assert_eq!(kept.content[0].version, "1");
+    }
+
+    #[test]
+    fn test_empty_graph() {
+        let mut scraper = test_scraper();
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let counter = crate::EMPTY_GRAPHS.with_label_values(&["x86_64", "stable", "checksum"]);
+        let before = counter.get();
+
+        // Only released for another basearch, so the graph is empty.
+        let releases: Vec<metadata::Release> = serde_json::from_str(
+            r#"[{"version": "2", "commits": [{"architecture": "aarch64", "checksum": "c"}]}]"#,
+        )
+        .unwrap();
+        let updates: metadata::UpdatesJSON =
+            serde_json::from_str(r#"{"stream": "stable", "releases": []}"#).unwrap();
+        scraper.releases = Some(CachedUpstream {
+            etag: None,
+            last_modified: None,
+            content: releases,
+            raw: Bytes::new(),
+        });
+        scraper.updates = Some(CachedUpstream {
+            etag: None,
+            last_modified: None,
+            content: updates,
+            raw: Bytes::new(),
+        });
+
+        // Cached when there is nothing better to serve.
+        scraper.rebuild_graphs().unwrap();
+        assert_eq!(counter.get(), before + 1);
+        let current = scraper.history[&scope].current.clone().unwrap();
+        assert!(current.graph.nodes.is_empty());
+
+        // Never replacing a non-empty graph.
+        scraper
+            .cache_graph("x86_64".to_string(), false, graph_with_version("1"))
+            .unwrap();
+        scraper.rebuild_graphs().unwrap();
+        assert_eq!(counter.get(), before + 2);
+        let current = scraper.history[&scope].current.clone().unwrap();
+        assert_eq!(current.graph.nodes[0].version, "1");
+    }
+
+    #[test]
+    fn test_full_graph() {
+        let mut scraper = test_scraper().with_full_graphs(true);
+        let scope = graph::GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        let releases: Vec<metadata::Release> = serde_json::from_str(
+            r#"[{"version": "1", "commits": [{"architecture": "x86_64", "checksum": "a"}]},
+                {"version": "2", "commits": [{"architecture": "x86_64", "checksum": "b"}]}]"#,
+        )
+        .unwrap();
+        let updates: metadata::UpdatesJSON = serde_json::from_str(
+            r#"{"stream": "stable", "releases": [
+                {"version": "1", "metadata": {"deadend": {"reason": "broken"}}},
+                {"version": "2", "metadata": {"rollout": {"start_percentage": 1.0}}}]}"#,
+        )
+        .unwrap();
+        scraper.releases = Some(CachedUpstream {
+            etag: None,
+            last_modified: None,
+            content: releases,
+            raw: Bytes::new(),
+        });
+        scraper.updates = Some(CachedUpstream {
+            etag: None,
+            last_modified: None,
+            content: updates,
+            raw: Bytes::new(),
+        });
+        scraper.rebuild_graphs().unwrap();
+
+        // Clients never see edges out of dead-ends.
+        let current = scraper.history[&scope].current.clone().unwrap();
+        assert!(current.graph.edges.is_empty());
+        let full = &scraper.full_graphs.as_ref().unwrap()[&scope];
+        assert_eq!(full.edges, vec![(0, 1)]);
+        assert_eq!(full.nodes[0].metadata[metadata::DEADEND_REASON], "broken");
+
+        // Not retained by default.
+        assert!(test_scraper().full_graphs.is_none());
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let mut headers = header::HeaderMap::new();
+        assert!(last_modified(&headers).is_none());
+
+        let date = "Thu, 05 May 2022 12:00:00 GMT";
+        headers.insert(header::DATE, HeaderValue::from_static(date));
+        assert_eq!(last_modified(&headers).unwrap().timestamp(), 1_651_752_000);
+
+        // `Last-Modified` wins over `Date`, if valid.
+        let modified = "Thu, 05 May 2022 10:00:00 GMT";
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_static(modified));
+        assert_eq!(last_modified(&headers).unwrap().timestamp(), 1_651_744_800);
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_static("yesterday"));
+        assert_eq!(last_modified(&headers).unwrap().timestamp(), 1_651_752_000);
+    }
+
+    #[test]
+    fn test_next_page_url() {
+        let first = reqwest::Url::parse("https://mirror.example.com/fcos/releases.json").unwrap();
+        let mut pages = vec![first];
+        let second = next_page_url(&pages, "releases.json?page=2").unwrap();
+        assert_eq!(
+            second.as_str(),
+            "https://mirror.example.com/fcos/releases.json?page=2"
+        );
+        pages.push(second);
+        let third = next_page_url(&pages, "https://mirror.example.com/fcos/page3.json").unwrap();
+        assert_eq!(third.path(), "/fcos/page3.json");
+
+        assert!(next_page_url(&pages, "releases.json").is_err());
+        assert!(next_page_url(&pages, "https://other.example.com/page3.json").is_err());
+        assert!(next_page_url(&pages, "http://mirror.example.com/fcos/page3.json").is_err());
+    }
+
+    /// Serve documents (path --> ETag and body) over HTTP, honoring `If-None-Match`.
+    ///
+    /// Documents can be changed while being served.
+    fn serve_documents(docs: Arc<Mutex<HashMap<String, (String, String)>>>) -> reqwest::Url {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut reader = BufReader::new(conn.try_clone().unwrap());
+                let (mut path, mut if_none_match) = (None, None);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    if path.is_none() {
+                        path = line.split(' ').nth(1).map(String::from);
+                    } else if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("if-none-match") {
+                            if_none_match = Some(value.trim().to_string());
+                        }
+                    }
+                    line.clear();
+                }
+                let doc = docs.lock().unwrap().get(&path.unwrap_or_default()).cloned();
+                let resp = match doc {
+                    Some((etag, _)) if if_none_match.as_ref() == Some(&etag) => {
+                        "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+                    }
+                    Some((etag, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        etag,
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string(),
+                };
+                conn.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+        reqwest::Url::parse(&base).unwrap()
+    }
+
+    #[test]
+    fn test_fetch_paginated_releases() {
+        let release = |version: &str| {
+            format!(
+                r#"{{"version": "{}", "commits": [{{"architecture": "x86_64", "checksum": "{}"}}]}}"#,
+                version, version
+            )
+        };
+        let first_page = format!(
+            r#"{{"releases": [{}], "next": "releases-2.json"}}"#,
+            release("1")
+        );
+        let docs = Arc::new(Mutex::new(maplit::hashmap! {
+            "/prod/streams/stable/releases.json".to_string() =>
+                ("\"page-1\"".to_string(), first_page),
+            "/prod/streams/stable/releases-2.json".to_string() =>
+                ("\"page-2\"".to_string(), format!(r#"{{"releases": [{}]}}"#, release("2"))),
+        }));
+        let upstream = settings::UpstreamSettings {
+            mirrors: vec![serve_documents(docs.clone())],
+            ..settings::UpstreamSettings::default()
+        };
+        let mut scraper = test_scraper_with(&upstream, None);
+        let mirror = scraper.mirrors[0].clone();
+        let versions = |releases: &CachedUpstream<Vec<metadata::Release>>| {
+            let versions = releases.content.iter().map(|r| r.version.as_str());
+            versions.collect::<Vec<_>>().join(",")
+        };
+
+        let mut sys = actix::System::new("test");
+        let releases = sys
+            .block_on(scraper.fetch_releases(&mirror))
+            .unwrap()
+            .unwrap();
+        assert_eq!(versions(&releases), "1,2");
+        scraper.releases = Some(releases);
+
+        // The first page is unchanged, while a release is appended to the last one.
+        docs.lock().unwrap().insert(
+            "/prod/streams/stable/releases-2.json".to_string(),
+            (
+                "\"page-2b\"".to_string(),
+                format!(r#"{{"releases": [{}, {}]}}"#, release("2"), release("3")),
+            ),
+        );
+        let releases = sys
+            .block_on(scraper.fetch_releases(&mirror))
+            .unwrap()
+            .unwrap();
+        assert_eq!(versions(&releases), "1,2,3");
+    }
+
+    #[test]
+    fn test_check_scopes() {
+        let mut scraper = test_scraper();
+        let arch = "x86_64".to_string();
+        let mut checksum = graph_with_version("1");
+        checksum.nodes.push(graph_with_version("2").nodes.remove(0));
+        scraper.cache_graph(arch.clone(), false, checksum).unwrap();
+        scraper
+            .cache_graph(arch, true, graph_with_version("1"))
+            .unwrap();
+
+        scraper.check_scopes();
+        let missing = |graph_type| {
+            crate::SCOPE_MISSING_VERSIONS
+                .with_label_values(&["x86_64", "stable", graph_type])
+                .get()
+        };
+        assert_eq!(missing("oci"), 1);
+        assert_eq!(missing("checksum"), 0);
+    }
+
+    #[test]
+    fn test_refresh_now() {
+        let mut sys = actix::System::new("test");
+
+        let addr = sys.block_on(async { test_scraper().start() });
+        let res = sys.block_on(addr.send(RefreshNow {})).unwrap().unwrap();
+        assert_eq!(res, RefreshNowResult::Frozen);
+
+        // Never concurrently with an in-flight refresh.
+        let mut scraper = test_scraper();
+        scraper.frozen = false;
+        scraper.refreshing = true;
+        let addr = sys.block_on(async { scraper.start() });
+        let res = sys.block_on(addr.send(RefreshNow {})).unwrap().unwrap();
+        assert_eq!(res, RefreshNowResult::InProgress);
+    }
+
+    #[test]
+    fn test_graph_diff_unavailable() {
+        let mut sys = actix::System::new("test");
+        let addr = sys.block_on(async { test_scraper().start() });
+        let mut diff = |basearch: &str| {
+            let scope = graph::GraphScope {
+                basearch: basearch.to_string(),
+                stream: "stable".to_string(),
+                oci: false,
+            };
+            let msg = GetGraphDiff { scope, since: None };
+            sys.block_on(addr.send(msg)).unwrap().unwrap()
+        };
+        assert!(matches!(diff("riscv64"), GraphDiffResult::UnknownScope));
+        assert!(matches!(diff("x86_64"), GraphDiffResult::NotReady));
+    }
+
+    #[test]
+    fn test_oci_scope_routing() {
+        let mut scraper = test_scraper();
+        let arch = "x86_64".to_string();
+        scraper
+            .cache_graph(arch.clone(), false, graph_with_version("checksum-1"))
+            .unwrap();
+        scraper
+            .cache_graph(arch, true, graph_with_version("oci-1"))
+            .unwrap();
+
+        // Graphs are still served while the actor runs, without going through it.
+        let store = scraper.graph_store();
+        let mut sys = actix::System::new("test");
+        sys.block_on(async move {
+            let _addr = scraper.start();
+            for (oci, expected) in &[(false, "checksum-1"), (true, "oci-1")] {
+                let query = commons::web::validate_scope(
+                    Some("x86_64".to_string()),
+                    Some("stable".to_string()),
+                    Some(*oci),
+                    &None,
+                )
+                .unwrap();
+                let data = served_graph(&store, &query, GraphVariant::default());
+                let graph: graph::Graph = serde_json::from_slice(&data.data).unwrap();
+                assert!(data.last_modified.is_some());
+                assert_eq!(graph.nodes[0].version, *expected);
+            }
+        });
+    }
+}
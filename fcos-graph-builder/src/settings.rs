@@ -1,7 +1,17 @@
 use crate::config::FileConfig;
-use failure::Fallible;
-use std::collections::BTreeMap;
+use crate::listen::ListenAddr;
+use crate::signing::SigningKey;
+use crate::transforms::Transform;
+use commons::graph::GraphScope;
+use commons::metadata;
+use commons::policy::{DeadendOverrides, ReasonRedactions};
+use commons::version::ClientVersion;
+use commons::web::IpNetwork;
+use failure::{ensure, format_err, Fallible, ResultExt};
+use std::collections::{BTreeMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Runtime settings for the graph-builder.
 #[derive(Clone, Debug, Default)]
@@ -11,9 +21,414 @@ pub struct GraphBuilderSettings {
 }
 
 impl GraphBuilderSettings {
-    pub fn validate_config(_cfg: FileConfig) -> Fallible<Self> {
-        // TODO(lucab): translate config entries.
-        let settings = GraphBuilderSettings::default();
+    pub fn validate_config(cfg: FileConfig) -> Fallible<Self> {
+        let mut settings = GraphBuilderSettings::default();
+
+        if let Some(service) = cfg.service {
+            if let Some(listen) = service.listen {
+                settings.service.ip_addr = listen.ip();
+                settings.service.port = listen.port();
+            }
+            settings.service.listen_uds =
+                validate_listen_uds("service", service.listen.is_some(), service.listen_uds)?;
+            settings.service.connections = ConnectionSettings::validate(
+                "service",
+                service.keep_alive_secs,
+                service.client_timeout_ms,
+                service.max_connections,
+            )?;
+            if let Some(workers) = service.workers {
+                ensure!(workers > 0, "invalid service.workers: must be positive");
+                settings.service.workers = Some(workers);
+            }
+            if let Some(canaries) = service.canary_streams {
+                for (stream, canary) in canaries {
+                    let canary = CanaryStream {
+                        base: canary.base,
+                        overlay: canary.overlay,
+                    };
+                    settings.service.canary_streams.insert(stream, canary);
+                }
+            }
+            if let Some(keys) = service.required_metadata_keys {
+                ensure!(
+                    keys.iter().all(|k| !k.is_empty()),
+                    "invalid service.required_metadata_keys: empty key"
+                );
+                settings.service.required_metadata_keys = keys;
+            }
+            if let Some(frozen) = service.frozen {
+                settings.service.frozen = frozen;
+            }
+            if let Some(maintenance) = service.maintenance {
+                settings.service.maintenance = maintenance;
+            }
+            if let Some(transforms) = service.transforms {
+                for (index, transform) in transforms.into_iter().enumerate() {
+                    let transform =
+                        Transform::new(&transform.name, transform.keys, transform.metadata)
+                            .map_err(|e| {
+                                format_err!("invalid service.transforms[{}]: {}", index, e)
+                            })?;
+                    settings.service.transforms.push(transform);
+                }
+            }
+            if let Some(namespace) = service.metadata_key_namespace {
+                let valid_label = |label: &str| {
+                    !label.is_empty()
+                        && label
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                };
+                ensure!(
+                    namespace.split('.').all(valid_label),
+                    "invalid service.metadata_key_namespace: '{}'",
+                    namespace
+                );
+                // The default namespace is a no-op.
+                if namespace != metadata::KEY_NAMESPACE {
+                    settings.service.metadata_key_namespace = Some(namespace);
+                }
+            }
+            if let Some(path) = service.signing_key_path {
+                let key = SigningKey::read(&path)
+                    .map_err(|e| format_err!("invalid service.signing_key_path: {}", e))?;
+                settings.service.signing_key = Some(key);
+            }
+            if let Some(path) = service.extra_metadata_path {
+                settings.service.extra_metadata = ExtraMetadata::read(&path)
+                    .map_err(|e| format_err!("invalid service.extra_metadata_path: {}", e))?;
+            }
+            if let Some(overwrite) = service.extra_metadata_override {
+                settings.service.extra_metadata.overwrite = overwrite;
+            }
+            if let Some(redact) = service.redact_reasons {
+                settings.service.reason_redactions.redact_all = redact;
+            }
+            if let Some(replacements) = service.reason_replacements {
+                for (reason, replacement) in replacements {
+                    ensure!(
+                        !reason.is_empty() && !replacement.is_empty(),
+                        "invalid service.reason_replacements: empty reason or replacement"
+                    );
+                    settings
+                        .service
+                        .reason_redactions
+                        .replacements
+                        .insert(reason, replacement);
+                }
+            }
+            if let Some(span) = service.max_age_span {
+                ensure!(span > 0, "invalid service.max_age_span: must be positive");
+                settings.service.max_age_span = Some(span);
+            }
+            if let Some(check) = service.check_scope_consistency {
+                settings.service.check_scope_consistency = check;
+            }
+            if let Some(rate_limit) = service.rate_limit {
+                ensure!(
+                    rate_limit.requests_per_second > 0.0
+                        && rate_limit.requests_per_second.is_finite(),
+                    "invalid service.rate_limit.requests_per_second: must be positive"
+                );
+                ensure!(
+                    rate_limit.burst > 0,
+                    "invalid service.rate_limit.burst: must be positive"
+                );
+                if rate_limit.trust_forwarded_for {
+                    log::warn!(
+                        "service.rate_limit.trust_forwarded_for is deprecated, \
+                         use service.trusted_proxies instead"
+                    );
+                    for any in &["0.0.0.0/0", "::/0"] {
+                        let net = any.parse().expect("valid network");
+                        settings.service.trusted_proxies.push(net);
+                    }
+                }
+                settings.service.rate_limit = Some(RateLimitSettings {
+                    requests_per_second: rate_limit.requests_per_second,
+                    burst: rate_limit.burst,
+                });
+            }
+            if let Some(scope) = service.default_scope {
+                let valid = settings
+                    .service
+                    .stream_arches(&scope.stream)
+                    .map(|arches| arches.contains(&scope.basearch.as_str()))
+                    .unwrap_or(false);
+                ensure!(
+                    valid,
+                    "invalid service.default_scope: unknown scope basearch='{}', stream='{}'",
+                    scope.basearch,
+                    scope.stream
+                );
+                settings.service.default_scope = Some(GraphScope {
+                    basearch: scope.basearch,
+                    stream: scope.stream,
+                    oci: scope.oci,
+                });
+            }
+            if let Some(origins) = service.origin_allowlist {
+                for origin in &origins {
+                    validate_origin("service.origin_allowlist", origin)?;
+                }
+                settings.service.origin_allowlist = Some(origins);
+            }
+            if let Some(allowlists) = service.stream_origin_allowlist {
+                for (stream, origins) in allowlists {
+                    ensure!(
+                        settings.service.has_stream(&stream),
+                        "invalid service.stream_origin_allowlist: unknown stream '{}'",
+                        stream
+                    );
+                    let field = format!("service.stream_origin_allowlist.{}", stream);
+                    for origin in &origins {
+                        validate_origin(&field, origin)?;
+                    }
+                    settings
+                        .service
+                        .stream_origin_allowlist
+                        .insert(stream, origins);
+                }
+            }
+            if let Some(versions) = service.min_client_versions {
+                for (stream, version) in versions {
+                    ensure!(
+                        settings.service.has_stream(&stream),
+                        "invalid service.min_client_versions: unknown stream '{}'",
+                        stream
+                    );
+                    let version = version.parse().map_err(|e| {
+                        format_err!("invalid service.min_client_versions.{}: {}", stream, e)
+                    })?;
+                    settings.service.min_client_versions.insert(stream, version);
+                }
+            }
+            if let Some(fallbacks) = service.fallback_basearch {
+                for (basearch, fallback) in fallbacks {
+                    ensure!(
+                        basearch != fallback,
+                        "invalid service.fallback_basearch.{}: cannot fall back to itself",
+                        basearch
+                    );
+                    let known = settings
+                        .service
+                        .streams
+                        .values()
+                        .any(|arches| arches.contains(&fallback.as_str()));
+                    ensure!(
+                        known,
+                        "invalid service.fallback_basearch.{}: unknown basearch '{}'",
+                        basearch,
+                        fallback
+                    );
+                    settings
+                        .service
+                        .fallback_basearch
+                        .insert(basearch, fallback);
+                }
+            }
+            if let Some(mirrors) = service.upstream_mirrors {
+                ensure!(
+                    !mirrors.is_empty(),
+                    "invalid service.upstream_mirrors: must not be empty"
+                );
+                settings.service.upstream.mirrors = mirrors
+                    .iter()
+                    .map(|m| UpstreamSettings::parse_mirror(m))
+                    .collect::<Fallible<_>>()?;
+            }
+            if let Some(secs) = service.upstream_timeout_secs {
+                ensure!(
+                    secs > 0,
+                    "invalid service.upstream_timeout_secs: must be positive"
+                );
+                settings.service.upstream.timeout = Some(Duration::from_secs(secs));
+            }
+            if let Some(secs) = service.initial_scrape_delay_secs {
+                ensure!(
+                    secs > 0,
+                    "invalid service.initial_scrape_delay_secs: must be positive"
+                );
+                settings.service.upstream.initial_delay = Some(Duration::from_secs(secs));
+            }
+            if let Some(concurrency) = service.initial_scrape_concurrency {
+                ensure!(
+                    concurrency > 0,
+                    "invalid service.initial_scrape_concurrency: must be positive"
+                );
+                settings.service.upstream.initial_concurrency = Some(concurrency);
+            }
+            if let Some(require) = service.require_all_scopes {
+                settings.service.require_all_scopes = require;
+            }
+            if let Some(user_agent) = service.user_agent {
+                ensure!(
+                    !user_agent.trim().is_empty()
+                        && user_agent.chars().all(|c| c == ' ' || c.is_ascii_graphic()),
+                    "invalid service.user_agent: must be non-empty printable ASCII"
+                );
+                settings.service.user_agent = user_agent;
+            }
+            if let Some(proxies) = service.trusted_proxies {
+                for proxy in proxies {
+                    let net = proxy
+                        .parse()
+                        .map_err(|e| format_err!("invalid service.trusted_proxies: {}", e))?;
+                    settings.service.trusted_proxies.push(net);
+                }
+            }
+            if let Some(dir) = service.warm_cache_dir {
+                ensure!(
+                    dir.is_dir(),
+                    "invalid service.warm_cache_dir: '{}' is not a directory",
+                    dir.display()
+                );
+                settings.service.warm_cache_dir = Some(dir);
+            }
+            if let Some(serve_metrics) = service.serve_metrics {
+                settings.service.serve_metrics = serve_metrics;
+            }
+            if let Some(blocked) = service.blocked_versions {
+                for (stream, versions) in blocked {
+                    ensure!(
+                        settings.service.has_stream(&stream),
+                        "invalid service.blocked_versions: unknown stream '{}'",
+                        stream
+                    );
+                    settings
+                        .service
+                        .blocked_versions
+                        .insert(stream, versions.into_iter().collect());
+                }
+            }
+            if let Some(overrides) = service.deadend_overrides {
+                for (stream, cfg) in overrides {
+                    ensure!(
+                        settings.service.has_stream(&stream),
+                        "invalid service.deadend_overrides: unknown stream '{}'",
+                        stream
+                    );
+                    let overrides = DeadendOverrides {
+                        ignore: cfg.ignore.unwrap_or_default().into_iter().collect(),
+                        force: cfg.force.unwrap_or_default().into_iter().collect(),
+                    };
+                    ensure!(
+                        overrides
+                            .ignore
+                            .iter()
+                            .chain(&overrides.force)
+                            .all(|v| !v.is_empty()),
+                        "invalid service.deadend_overrides.{}: empty version",
+                        stream
+                    );
+                    if let Some(version) = overrides.ignore.intersection(&overrides.force).next() {
+                        failure::bail!(
+                            "invalid service.deadend_overrides.{}: version '{}' both ignored and forced",
+                            stream,
+                            version
+                        );
+                    }
+                    settings.service.deadend_overrides.insert(stream, overrides);
+                }
+            }
+            if let Some(delay_ms) = service.unknown_scope_delay_ms {
+                let delay = Duration::from_millis(delay_ms);
+                ensure!(
+                    delay <= ServiceSettings::MAX_UNKNOWN_SCOPE_DELAY,
+                    "invalid service.unknown_scope_delay_ms: must be at most {}",
+                    ServiceSettings::MAX_UNKNOWN_SCOPE_DELAY.as_millis()
+                );
+                settings.service.unknown_scope_delay = Some(delay);
+            }
+            if let Some(threshold_ms) = service.slow_request_threshold_ms {
+                ensure!(
+                    threshold_ms > 0,
+                    "invalid service.slow_request_threshold_ms: must be positive"
+                );
+                settings.service.slow_request_threshold = Some(Duration::from_millis(threshold_ms));
+            }
+            if let Some(level) = service.gzip_level {
+                ensure!(
+                    (1..=9).contains(&level),
+                    "invalid service.gzip_level: must be between 1 and 9"
+                );
+                settings.service.gzip_level = level;
+            }
+            if let Some(len) = service.diff_history_len {
+                ensure!(
+                    len > 0,
+                    "invalid service.diff_history_len: must be positive"
+                );
+                settings.service.diff_history_len = len;
+            }
+        }
+
+        if let Some(status) = cfg.status {
+            if let Some(enabled) = status.enabled {
+                settings.status.enabled = enabled;
+            }
+            if let Some(listen) = status.listen {
+                settings.status.ip_addr = listen.ip();
+                settings.status.port = listen.port();
+            }
+            settings.status.listen_uds =
+                validate_listen_uds("status", status.listen.is_some(), status.listen_uds)?;
+            settings.status.connections = ConnectionSettings::validate(
+                "status",
+                status.keep_alive_secs,
+                status.client_timeout_ms,
+                status.max_connections,
+            )?;
+            if let Some(workers) = status.workers {
+                ensure!(workers > 0, "invalid status.workers: must be positive");
+                settings.status.workers = Some(workers);
+            }
+            if let Some(token) = status.admin_token {
+                ensure!(
+                    !token.trim().is_empty(),
+                    "invalid status.admin_token: must not be empty"
+                );
+                settings.status.admin_token = Some(token);
+            }
+            if let Some(debug_upstream) = status.debug_upstream {
+                settings.status.debug_upstream = debug_upstream;
+            }
+            if let Some(debug_full_graph) = status.debug_full_graph {
+                settings.status.debug_full_graph = debug_full_graph;
+            }
+            if let Some(pushgateway) = status.pushgateway {
+                let url = reqwest::Url::parse(&pushgateway.url).map_err(|e| {
+                    format_err!(
+                        "invalid status.pushgateway.url '{}': {}",
+                        pushgateway.url,
+                        e
+                    )
+                })?;
+                ensure!(
+                    (url.scheme() == "http" || url.scheme() == "https") && !url.cannot_be_a_base(),
+                    "invalid status.pushgateway.url '{}': unsupported scheme",
+                    pushgateway.url
+                );
+                ensure!(
+                    url.query().is_none() && url.fragment().is_none(),
+                    "invalid status.pushgateway.url '{}': unexpected query or fragment",
+                    pushgateway.url
+                );
+                let job = pushgateway
+                    .job
+                    .unwrap_or_else(|| PushgatewaySettings::DEFAULT_JOB.to_string());
+                ensure!(
+                    !job.is_empty() && !job.contains('/'),
+                    "invalid status.pushgateway.job '{}': must be non-empty, without slashes",
+                    job
+                );
+                settings.status.pushgateway = Some(PushgatewaySettings { url, job });
+            }
+        }
+
+        settings.service.validate_streams()?;
+
         Ok(settings)
     }
 }
@@ -21,11 +436,242 @@ impl GraphBuilderSettings {
 /// Runtime settings for the main service (graph endpoint) server.
 #[derive(Clone, Debug)]
 pub struct ServiceSettings {
+    /// Origins allowed for CORS requests, or all of them if unset.
     pub(crate) origin_allowlist: Option<Vec<String>>,
+    /// stream --> origins allowed for CORS graph requests, instead of the global ones
+    pub(crate) stream_origin_allowlist: BTreeMap<String, Vec<String>>,
     pub(crate) ip_addr: IpAddr,
     pub(crate) port: u16,
+    /// Unix-domain socket to listen on instead of TCP, if any.
+    pub(crate) listen_uds: Option<PathBuf>,
     // stream --> set of valid arches for it
     pub(crate) streams: BTreeMap<&'static str, &'static [&'static str]>,
+    /// Number of HTTP workers, or one per CPU if unset.
+    pub(crate) workers: Option<usize>,
+    /// HTTP connection tuning.
+    pub(crate) connections: ConnectionSettings,
+    /// stream --> versions to remove from its graphs
+    pub(crate) blocked_versions: BTreeMap<String, HashSet<String>>,
+    /// stream --> overrides of upstream dead-end markers in its graphs
+    pub(crate) deadend_overrides: BTreeMap<String, DeadendOverrides>,
+    /// Delay before answering requests for unknown scopes, if any.
+    pub(crate) unknown_scope_delay: Option<Duration>,
+    /// Duration above which graph requests are logged as slow, if any.
+    pub(crate) slow_request_threshold: Option<Duration>,
+    /// Compression level for gzip-encoded graphs.
+    pub(crate) gzip_level: u32,
+    /// Number of past graphs retained per scope, for diffing.
+    pub(crate) diff_history_len: usize,
+    /// canary stream --> its base stream and overlay releases
+    pub(crate) canary_streams: BTreeMap<String, CanaryStream>,
+    /// Per-client rate limiting, disabled if unset.
+    pub(crate) rate_limit: Option<RateLimitSettings>,
+    /// Whether graph refreshes are paused.
+    pub(crate) frozen: bool,
+    /// Whether to serve empty graphs, during maintenance.
+    pub(crate) maintenance: bool,
+    /// Metadata keys that every node in a graph must have.
+    pub(crate) required_metadata_keys: Vec<String>,
+    /// Whether to check that OCI and checksum graphs have the same versions.
+    pub(crate) check_scope_consistency: bool,
+    /// Transforms to apply to assembled graphs, in order.
+    pub(crate) transforms: Vec<Transform>,
+    /// Maximum age-index span of graph nodes behind the newest one, if limited.
+    pub(crate) max_age_span: Option<u64>,
+    /// Namespace for metadata keys in served graphs, if not the default one.
+    pub(crate) metadata_key_namespace: Option<String>,
+    /// Key for signing served graphs, if any.
+    pub(crate) signing_key: Option<SigningKey>,
+    /// Extra metadata for graph nodes.
+    pub(crate) extra_metadata: ExtraMetadata,
+    /// Rewrites of barrier and dead-end reasons in served graphs.
+    pub(crate) reason_redactions: ReasonRedactions,
+    /// Scope for graph requests without any scope parameters, if any.
+    pub(crate) default_scope: Option<GraphScope>,
+    /// stream --> minimum client version required to fetch its graphs
+    pub(crate) min_client_versions: BTreeMap<String, ClientVersion>,
+    /// Whether to also serve `/metrics` on the main service.
+    pub(crate) serve_metrics: bool,
+    /// `User-Agent` for upstream requests.
+    pub(crate) user_agent: String,
+    /// Directory for persisting the last graphs, disabled if unset.
+    pub(crate) warm_cache_dir: Option<PathBuf>,
+    /// Networks of proxies trusted for `X-Forwarded-*` headers.
+    pub(crate) trusted_proxies: Vec<IpNetwork>,
+    /// basearch --> basearch to serve instead, if it has no graph
+    pub(crate) fallback_basearch: BTreeMap<String, String>,
+    /// Upstream metadata sources.
+    pub(crate) upstream: UpstreamSettings,
+    /// Whether to fail startup unless the first scrape succeeds for all scopes.
+    pub(crate) require_all_scopes: bool,
+}
+
+/// Validate a CORS origin, which must be in its serialized form.
+fn validate_origin(field: &str, origin: &str) -> Fallible<()> {
+    let serialized = reqwest::Url::parse(origin)
+        .ok()
+        .map(|url| url.origin().ascii_serialization());
+    ensure!(
+        serialized.as_deref() == Some(origin),
+        "invalid {}: '{}' is not an origin",
+        field,
+        origin
+    );
+    Ok(())
+}
+
+/// Validate the unix-domain socket of a server, which excludes a TCP listener.
+fn validate_listen_uds(
+    section: &str,
+    has_listen: bool,
+    listen_uds: Option<PathBuf>,
+) -> Fallible<Option<PathBuf>> {
+    let path = match listen_uds {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    ensure!(
+        !has_listen,
+        "invalid {0}.listen_uds: conflicts with {0}.listen",
+        section
+    );
+    ensure!(
+        !path.as_os_str().is_empty(),
+        "invalid {}.listen_uds: empty path",
+        section
+    );
+    Ok(Some(path))
+}
+
+/// Upstream metadata sources.
+#[derive(Clone, Debug)]
+pub struct UpstreamSettings {
+    /// Base URLs for upstream metadata, tried in order.
+    pub(crate) mirrors: Vec<reqwest::Url>,
+    /// Timeout for each upstream request, or the client default if unset.
+    pub(crate) timeout: Option<Duration>,
+    /// Delay before the first scrape, if any.
+    pub(crate) initial_delay: Option<Duration>,
+    /// Maximum number of streams doing their first scrape concurrently, if limited.
+    pub(crate) initial_concurrency: Option<usize>,
+}
+
+impl UpstreamSettings {
+    /// Parse a mirror base URL.
+    fn parse_mirror(input: &str) -> Fallible<reqwest::Url> {
+        let mut url = reqwest::Url::parse(input)
+            .map_err(|e| format_err!("invalid service.upstream_mirrors: '{}': {}", input, e))?;
+        ensure!(
+            url.scheme() == "http" || url.scheme() == "https",
+            "invalid service.upstream_mirrors: '{}': unsupported scheme",
+            input
+        );
+        ensure!(
+            url.query().is_none() && url.fragment().is_none(),
+            "invalid service.upstream_mirrors: '{}': unexpected query or fragment",
+            input
+        );
+        // Metadata paths are relative to the base URL.
+        if !url.path().ends_with('/') {
+            let path = format!("{}/", url.path());
+            url.set_path(&path);
+        }
+        Ok(url)
+    }
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        let base = reqwest::Url::parse(metadata::UPSTREAM_BASE_URL).expect("valid upstream URL");
+        Self {
+            mirrors: vec![base],
+            timeout: None,
+            initial_delay: None,
+            initial_concurrency: None,
+        }
+    }
+}
+
+/// HTTP connection tuning for a server, with actix defaults for unset values.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionSettings {
+    /// Keep-alive timeout, in seconds (0 disables keep-alive).
+    pub(crate) keep_alive_secs: Option<usize>,
+    /// Timeout for clients to send the first request, in milliseconds.
+    pub(crate) client_timeout_ms: Option<u64>,
+    /// Maximum number of concurrent connections per worker.
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl ConnectionSettings {
+    fn validate(
+        section: &str,
+        keep_alive_secs: Option<usize>,
+        client_timeout_ms: Option<u64>,
+        max_connections: Option<usize>,
+    ) -> Fallible<Self> {
+        if let Some(max) = max_connections {
+            ensure!(
+                max > 0,
+                "invalid {}.max_connections: must be positive",
+                section
+            );
+        }
+        Ok(Self {
+            keep_alive_secs,
+            client_timeout_ms,
+            max_connections,
+        })
+    }
+}
+
+/// Per-client rate limiting for graph requests.
+#[derive(Clone, Debug)]
+pub struct RateLimitSettings {
+    pub(crate) requests_per_second: f64,
+    pub(crate) burst: u32,
+}
+
+/// Extra metadata for graph nodes, from a local file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtraMetadata {
+    /// version --> key --> value
+    pub(crate) versions: BTreeMap<String, BTreeMap<String, String>>,
+    /// Whether to replace keys already set on graph nodes.
+    pub(crate) overwrite: bool,
+}
+
+impl ExtraMetadata {
+    /// Parse a TOML table of versions, each with a table of metadata keys.
+    pub(crate) fn parse(content: &str) -> Fallible<Self> {
+        let versions: BTreeMap<String, BTreeMap<String, String>> = toml::from_str(content)?;
+        for (version, entries) in &versions {
+            ensure!(!version.is_empty(), "empty version");
+            ensure!(
+                entries.keys().all(|key| !key.is_empty()),
+                "empty metadata key for version '{}'",
+                version
+            );
+        }
+        Ok(Self {
+            versions,
+            overwrite: false,
+        })
+    }
+
+    /// Read extra metadata from a TOML file.
+    pub(crate) fn read(path: &Path) -> Fallible<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|_| format!("failed to read '{}'", path.display()))?;
+        Self::parse(&content)
+    }
+}
+
+/// A stream composed from a base stream plus overlay releases.
+#[derive(Clone, Debug)]
+pub struct CanaryStream {
+    pub(crate) base: String,
+    pub(crate) overlay: Vec<metadata::Release>,
 }
 
 impl ServiceSettings {
@@ -39,19 +685,120 @@ impl ServiceSettings {
         ("testing", &["x86_64", "aarch64", "s390x", "ppc64le"]),
         ("next", &["x86_64", "aarch64", "s390x", "ppc64le"]),
     ];
+    /// Default `User-Agent` for upstream requests.
+    const DEFAULT_USER_AGENT: &'static str =
+        concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+    /// Default compression level for gzip-encoded graphs, favoring speed.
+    ///
+    /// Graphs are highly redundant, so they compress well even at low levels.
+    const DEFAULT_GZIP_LEVEL: u32 = 1;
+    /// Default number of past graphs retained per scope, i.e. only the previous one.
+    pub(crate) const DEFAULT_DIFF_HISTORY_LEN: usize = 1;
+    /// Maximum delay before answering requests for unknown scopes.
+    const MAX_UNKNOWN_SCOPE_DELAY: Duration = Duration::from_secs(5);
 
     pub fn socket_addr(&self) -> SocketAddr {
         SocketAddr::new(self.ip_addr, self.port)
     }
+
+    /// Return the address to listen on, either TCP or unix-domain.
+    pub(crate) fn listen_addr(&self) -> ListenAddr {
+        match &self.listen_uds {
+            Some(path) => ListenAddr::Unix(path.clone()),
+            None => ListenAddr::Tcp(self.socket_addr()),
+        }
+    }
+
+    /// Return the basearches of a stream (either regular or canary), if configured.
+    pub fn stream_arches(&self, stream: &str) -> Option<&'static [&'static str]> {
+        let base = match self.canary_streams.get(stream) {
+            Some(canary) => canary.base.as_str(),
+            None => stream,
+        };
+        self.streams.get(base).copied()
+    }
+
+    /// Return the origins to let through the CORS middleware, or `None` for all.
+    ///
+    /// Per-stream origins are also let through, and then checked against the
+    /// requested stream when serving graphs.
+    pub fn cors_origins(&self) -> Option<Vec<String>> {
+        let mut origins = self.origin_allowlist.clone()?;
+        for stream_origins in self.stream_origin_allowlist.values() {
+            origins.extend(stream_origins.iter().cloned());
+        }
+        origins.sort();
+        origins.dedup();
+        Some(origins)
+    }
+
+    /// Check that canary streams are named, do not shadow regular streams,
+    /// and are based on a regular stream.
+    fn validate_streams(&self) -> Fallible<()> {
+        for (stream, canary) in &self.canary_streams {
+            ensure!(
+                !stream.is_empty(),
+                "invalid service.canary_streams: empty stream name"
+            );
+            ensure!(
+                !self.streams.contains_key(stream.as_str()),
+                "invalid service.canary_streams: stream '{}' already exists",
+                stream
+            );
+            ensure!(
+                self.streams.contains_key(canary.base.as_str()),
+                "invalid service.canary_streams.{}: unknown base stream '{}'",
+                stream,
+                canary.base
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether a stream (either regular or canary) is configured.
+    pub fn has_stream(&self, stream: &str) -> bool {
+        self.streams.contains_key(stream) || self.canary_streams.contains_key(stream)
+    }
 }
 
 impl Default for ServiceSettings {
     fn default() -> Self {
         Self {
             origin_allowlist: None,
+            stream_origin_allowlist: BTreeMap::new(),
             ip_addr: Self::DEFAULT_GB_SERVICE_ADDR.into(),
             port: Self::DEFAULT_GB_SERVICE_PORT,
+            listen_uds: None,
             streams: Self::DEFAULT_STREAMS.iter().copied().collect(),
+            workers: None,
+            connections: ConnectionSettings::default(),
+            blocked_versions: BTreeMap::new(),
+            deadend_overrides: BTreeMap::new(),
+            unknown_scope_delay: None,
+            slow_request_threshold: None,
+            gzip_level: Self::DEFAULT_GZIP_LEVEL,
+            diff_history_len: Self::DEFAULT_DIFF_HISTORY_LEN,
+            canary_streams: BTreeMap::new(),
+            rate_limit: None,
+            frozen: false,
+            maintenance: false,
+            required_metadata_keys: vec![],
+            check_scope_consistency: false,
+            transforms: vec![],
+            max_age_span: None,
+            metadata_key_namespace: None,
+            signing_key: None,
+            extra_metadata: ExtraMetadata::default(),
+            reason_redactions: ReasonRedactions::default(),
+            default_scope: None,
+            min_client_versions: BTreeMap::new(),
+            serve_metrics: false,
+            user_agent: Self::DEFAULT_USER_AGENT.to_string(),
+            warm_cache_dir: None,
+            trusted_proxies: vec![],
+            fallback_basearch: BTreeMap::new(),
+            upstream: UpstreamSettings::default(),
+            require_all_scopes: false,
         }
     }
 }
@@ -59,8 +806,24 @@ impl Default for ServiceSettings {
 /// Runtime settings for the status server.
 #[derive(Clone, Debug)]
 pub struct StatusSettings {
+    /// Whether to run the status server at all.
+    pub(crate) enabled: bool,
     pub(crate) ip_addr: IpAddr,
     pub(crate) port: u16,
+    /// Unix-domain socket to listen on instead of TCP, if any.
+    pub(crate) listen_uds: Option<PathBuf>,
+    /// Number of HTTP workers, or one per CPU if unset.
+    pub(crate) workers: Option<usize>,
+    /// HTTP connection tuning.
+    pub(crate) connections: ConnectionSettings,
+    /// Bearer token for admin endpoints, which are disabled if unset.
+    pub(crate) admin_token: Option<String>,
+    /// Whether to serve the last fetched upstream metadata, for debugging.
+    pub(crate) debug_upstream: bool,
+    /// Whether to retain and serve graphs including edges out of dead-ends, for auditing.
+    pub(crate) debug_full_graph: bool,
+    /// Pushgateway to push final metrics to on shutdown, if any.
+    pub(crate) pushgateway: Option<PushgatewaySettings>,
 }
 
 impl StatusSettings {
@@ -72,13 +835,505 @@ impl StatusSettings {
     pub fn socket_addr(&self) -> SocketAddr {
         SocketAddr::new(self.ip_addr, self.port)
     }
+
+    /// Return the address to listen on, either TCP or unix-domain.
+    pub(crate) fn listen_addr(&self) -> ListenAddr {
+        match &self.listen_uds {
+            Some(path) => ListenAddr::Unix(path.clone()),
+            None => ListenAddr::Tcp(self.socket_addr()),
+        }
+    }
 }
 
 impl Default for StatusSettings {
     fn default() -> Self {
         Self {
+            enabled: true,
             ip_addr: Self::DEFAULT_GB_SERVICE_ADDR.into(),
             port: Self::DEFAULT_GB_STATUS_PORT,
+            listen_uds: None,
+            workers: None,
+            connections: ConnectionSettings::default(),
+            admin_token: None,
+            debug_upstream: false,
+            debug_full_graph: false,
+            pushgateway: None,
+        }
+    }
+}
+
+/// Pushing final metrics to a Prometheus Pushgateway.
+#[derive(Clone, Debug)]
+pub struct PushgatewaySettings {
+    pub(crate) url: reqwest::Url,
+    pub(crate) job: String,
+}
+
+impl PushgatewaySettings {
+    /// Default job name for pushed metrics.
+    const DEFAULT_JOB: &'static str = env!("CARGO_PKG_NAME");
+
+    /// URL for pushing metrics of this job.
+    pub(crate) fn push_url(&self) -> reqwest::Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("base URL")
+            .pop_if_empty()
+            .extend(&["metrics", "job", &self.job]);
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn parse(input: &str) -> GraphBuilderSettings {
+        let cfg: FileConfig = toml::from_str(input).unwrap();
+        GraphBuilderSettings::validate_config(cfg).unwrap()
+    }
+
+    #[test]
+    fn test_validate_streams() {
+        assert!(ServiceSettings::default().validate_streams().is_ok());
+
+        let error =
+            |settings: ServiceSettings| settings.validate_streams().unwrap_err().to_string();
+        let mut duplicate = ServiceSettings::default();
+        let canary = CanaryStream {
+            base: "stable".to_string(),
+            overlay: vec![],
+        };
+        duplicate
+            .canary_streams
+            .insert("stable".to_string(), canary.clone());
+        assert_eq!(
+            error(duplicate),
+            "invalid service.canary_streams: stream 'stable' already exists"
+        );
+        let mut empty = ServiceSettings::default();
+        empty.canary_streams.insert(String::new(), canary.clone());
+        assert_eq!(
+            error(empty),
+            "invalid service.canary_streams: empty stream name"
+        );
+        let mut unknown = ServiceSettings::default();
+        let orphan = CanaryStream {
+            base: "nonexistent".to_string(),
+            ..canary
+        };
+        unknown.canary_streams.insert("canary".to_string(), orphan);
+        assert_eq!(
+            error(unknown),
+            "invalid service.canary_streams.canary: unknown base stream 'nonexistent'"
+        );
+
+        // Canary stream names are also checked when parsing configuration.
+        for (invalid, message) in &[
+            (
+                "[service.canary_streams.\"\"]\nbase = \"stable\"",
+                "empty stream name",
+            ),
+            (
+                "[service.canary_streams.stable]\nbase = \"stable\"",
+                "already exists",
+            ),
+        ] {
+            let cfg: FileConfig = toml::from_str(invalid).unwrap();
+            let error = GraphBuilderSettings::validate_config(cfg).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_extra_metadata() {
+        let extra = ExtraMetadata::parse(
+            r#"
+            ["36.20220505.3.2"]
+            "com.example.advisory" = "https://example.com/FEDORA-2022-1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            extra.versions["36.20220505.3.2"]["com.example.advisory"],
+            "https://example.com/FEDORA-2022-1"
+        );
+        assert!(!extra.overwrite);
+
+        let invalid = [
+            "[\"\"]\nkey = \"value\"\n",
+            "[\"36.20220505.3.2\"]\n\"\" = \"value\"\n",
+            "[\"36.20220505.3.2\"]\nkey = 1\n",
+        ];
+        for content in &invalid {
+            ExtraMetadata::parse(content).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_reason_redactions() {
+        let settings = parse(
+            r#"
+            [service]
+            redact_reasons = true
+            [service.reason_replacements]
+            "CVE-2022-0001 regression" = "security issue"
+            "#,
+        );
+        let redactions = &settings.service.reason_redactions;
+        assert!(redactions.redact_all);
+        assert_eq!(
+            redactions.replacements["CVE-2022-0001 regression"],
+            "security issue"
+        );
+        assert!(parse("").service.reason_redactions.is_empty());
+
+        for invalid in &[
+            "[service.reason_replacements]\n\"\" = \"generic\"\n",
+            "[service.reason_replacements]\nbug = \"\"\n",
+        ] {
+            let cfg: FileConfig = toml::from_str(invalid).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_pushgateway() {
+        let cfg: FileConfig = toml::from_str(
+            r#"
+            [status.pushgateway]
+            url = "http://pushgateway.example.com:9091/"
+            "#,
+        )
+        .unwrap();
+        let settings = GraphBuilderSettings::validate_config(cfg).unwrap();
+        let pushgateway = settings.status.pushgateway.unwrap();
+        assert_eq!(
+            pushgateway.push_url().as_str(),
+            "http://pushgateway.example.com:9091/metrics/job/fcos-graph-builder"
+        );
+
+        let pushgateway = PushgatewaySettings {
+            url: "https://example.com/prefix".parse().unwrap(),
+            job: "gb batch".to_string(),
+        };
+        assert_eq!(
+            pushgateway.push_url().as_str(),
+            "https://example.com/prefix/metrics/job/gb%20batch"
+        );
+
+        for invalid in &[
+            "[status.pushgateway]\nurl = \"pushgateway:9091\"",
+            "[status.pushgateway]\nurl = \"ftp://example.com/\"",
+            "[status.pushgateway]\nurl = \"http://example.com/?job=gb\"",
+            "[status.pushgateway]\nurl = \"http://example.com/\"\njob = \"\"",
+            "[status.pushgateway]\nurl = \"http://example.com/\"\njob = \"a/b\"",
+        ] {
+            let cfg: FileConfig = toml::from_str(invalid).unwrap();
+            assert!(GraphBuilderSettings::validate_config(cfg).is_err());
+        }
+        assert!(StatusSettings::default().pushgateway.is_none());
+    }
+
+    #[test]
+    fn test_socket_addr_default() {
+        let settings = parse("");
+        assert_eq!(settings.service.socket_addr().to_string(), "0.0.0.0:8080");
+        assert_eq!(settings.status.socket_addr().to_string(), "0.0.0.0:9080");
+    }
+
+    #[test]
+    fn test_socket_addr_ipv4() {
+        let settings = parse(
+            r#"
+            [service]
+            listen = "127.0.0.1:8081"
+            [status]
+            listen = "127.0.0.1:9081"
+            "#,
+        );
+        let service = settings.service.socket_addr();
+        assert_eq!(service.ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(service.port(), 8081);
+        assert_eq!(settings.status.socket_addr().to_string(), "127.0.0.1:9081");
+    }
+
+    #[test]
+    fn test_socket_addr_ipv6() {
+        let settings = parse(
+            r#"
+            [service]
+            listen = "[::1]:8080"
+            [status]
+            listen = "[::1]:9080"
+            "#,
+        );
+        let service = settings.service.socket_addr();
+        assert!(service.is_ipv6());
+        assert_eq!(service.ip(), Ipv6Addr::LOCALHOST);
+        assert_eq!(service.to_string(), "[::1]:8080");
+        assert_eq!(settings.status.socket_addr().to_string(), "[::1]:9080");
+    }
+
+    #[test]
+    fn test_socket_addr_dual_stack() {
+        // `[::]` binds all IPv6 addresses, and on dual-stack hosts
+        // (i.e. without `IPV6_V6ONLY`) all IPv4 ones as well.
+        let settings = parse(
+            r#"
+            [service]
+            listen = "[::]:8080"
+            [status]
+            listen = "[::]:9080"
+            "#,
+        );
+        let service = settings.service.socket_addr();
+        assert!(service.is_ipv6());
+        assert!(service.ip().is_unspecified());
+        assert_eq!(service.to_string(), "[::]:8080");
+        let status = settings.status.socket_addr();
+        assert!(status.ip().is_unspecified());
+        assert_eq!(status.port(), 9080);
+    }
+
+    #[test]
+    fn test_listen_uds() {
+        let settings = parse(
+            r#"
+            [service]
+            listen_uds = "/run/fcos-graph-builder/service.sock"
+            [status]
+            listen = "127.0.0.1:9081"
+            "#,
+        );
+        assert_eq!(
+            settings.service.listen_addr().to_string(),
+            "unix:/run/fcos-graph-builder/service.sock"
+        );
+        assert_eq!(
+            settings.status.listen_addr(),
+            ListenAddr::Tcp("127.0.0.1:9081".parse().unwrap())
+        );
+
+        for invalid in &[
+            "[service]\nlisten = \"127.0.0.1:8080\"\nlisten_uds = \"/run/gb.sock\"\n",
+            "[status]\nlisten = \"127.0.0.1:9080\"\nlisten_uds = \"/run/gb.sock\"\n",
+            "[service]\nlisten_uds = \"\"\n",
+        ] {
+            let cfg: FileConfig = toml::from_str(invalid).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_min_client_versions() {
+        let settings = parse(
+            r#"
+            [service.min_client_versions]
+            testing = "0.0.24"
+            "#,
+        );
+        let min = &settings.service.min_client_versions["testing"];
+        assert_eq!(min.to_string(), "0.0.24");
+
+        for invalid in &["unknown = \"0.1\"", "stable = \"latest\""] {
+            let input = format!("[service.min_client_versions]\n{}\n", invalid);
+            let cfg: FileConfig = toml::from_str(&input).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_fallback_basearch() {
+        let settings = parse(
+            r#"
+            [service.fallback_basearch]
+            riscv64 = "x86_64"
+            "#,
+        );
+        let fallbacks = &settings.service.fallback_basearch;
+        assert_eq!(fallbacks["riscv64"], "x86_64");
+        assert!(parse("").service.fallback_basearch.is_empty());
+
+        for invalid in &["riscv64 = \"riscv64\"", "riscv64 = \"mips\""] {
+            let input = format!("[service.fallback_basearch]\n{}\n", invalid);
+            let cfg: FileConfig = toml::from_str(&input).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_blocked_versions() {
+        let settings = parse(
+            r#"
+            [service.blocked_versions]
+            stable = ["1", "2"]
+            "#,
+        );
+        let blocked = &settings.service.blocked_versions["stable"];
+        assert!(blocked.contains("1") && blocked.contains("2"));
+        assert!(parse("").service.blocked_versions.is_empty());
+
+        let cfg: FileConfig = toml::from_str(
+            "[service.blocked_versions]
+foo = [\"1\"]
+",
+        )
+        .unwrap();
+        let error = GraphBuilderSettings::validate_config(cfg).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid service.blocked_versions: unknown stream 'foo'"
+        );
+    }
+
+    #[test]
+    fn test_deadend_overrides() {
+        let settings = parse(
+            r#"
+            [service.deadend_overrides.stable]
+            ignore = ["1"]
+            force = ["2"]
+            "#,
+        );
+        let overrides = &settings.service.deadend_overrides["stable"];
+        assert!(overrides.ignore.contains("1"));
+        assert!(overrides.force.contains("2"));
+        assert!(parse("").service.deadend_overrides.is_empty());
+
+        for invalid in &[
+            "[service.deadend_overrides.foo]\nignore = [\"1\"]\n",
+            "[service.deadend_overrides.stable]\nignore = [\"\"]\n",
+            "[service.deadend_overrides.stable]\nignore = [\"1\"]\nforce = [\"1\"]\n",
+        ] {
+            let cfg: FileConfig = toml::from_str(invalid).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_origin_allowlists() {
+        let settings = parse(
+            r#"
+            [service]
+            origin_allowlist = ["https://example.com"]
+            [service.stream_origin_allowlist]
+            testing = ["https://partner.example.com", "https://example.com"]
+            "#,
+        );
+        assert_eq!(
+            settings.service.cors_origins().unwrap(),
+            vec!["https://example.com", "https://partner.example.com"]
+        );
+        assert_eq!(
+            settings.service.stream_origin_allowlist["testing"][0],
+            "https://partner.example.com"
+        );
+        assert!(parse("").service.cors_origins().is_none());
+
+        for invalid in &[
+            "origin_allowlist = [\"https://example.com/\"]",
+            "origin_allowlist = [\"example.com\"]",
+            "[service.stream_origin_allowlist]\nmystery = [\"https://example.com\"]",
+            "[service.stream_origin_allowlist]\nstable = [\"https://example.com/ui\"]",
+        ] {
+            let input = format!("[service]\n{}\n", invalid);
+            let cfg: FileConfig = toml::from_str(&input).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_upstream_mirrors() {
+        let settings = parse(
+            r#"
+            [service]
+            upstream_mirrors = ["https://mirror.example.com/fcos", "http://10.0.0.1:8000/"]
+            upstream_timeout_secs = 20
+            initial_scrape_delay_secs = 5
+            initial_scrape_concurrency = 2
+            "#,
+        );
+        let upstream = &settings.service.upstream;
+        let mirrors: Vec<&str> = upstream.mirrors.iter().map(|m| m.as_str()).collect();
+        assert_eq!(
+            mirrors,
+            vec!["https://mirror.example.com/fcos/", "http://10.0.0.1:8000/"]
+        );
+        assert_eq!(upstream.timeout, Some(Duration::from_secs(20)));
+        assert_eq!(upstream.initial_delay, Some(Duration::from_secs(5)));
+        assert_eq!(upstream.initial_concurrency, Some(2));
+        let default = parse("").service.upstream;
+        assert_eq!(default.mirrors[0].as_str(), metadata::UPSTREAM_BASE_URL);
+        assert!(default.initial_delay.is_none());
+
+        for invalid in &[
+            "upstream_mirrors = []",
+            "upstream_mirrors = [\"mirror\"]",
+            "upstream_mirrors = [\"ftp://mirror/\"]",
+            "upstream_mirrors = [\"https://mirror/?a=b\"]",
+            "upstream_timeout_secs = 0",
+            "initial_scrape_delay_secs = 0",
+            "initial_scrape_concurrency = 0",
+        ] {
+            let input = format!("[service]\n{}\n", invalid);
+            let cfg: FileConfig = toml::from_str(&input).unwrap();
+            assert!(
+                GraphBuilderSettings::validate_config(cfg).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let settings = parse(
+            r#"
+            [service]
+            trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+            "#,
+        );
+        let proxies = &settings.service.trusted_proxies;
+        assert_eq!(proxies.len(), 2);
+        assert!(proxies[0].contains("10.1.1.1".parse().unwrap()));
+        assert!(parse("").service.trusted_proxies.is_empty());
+
+        let cfg: FileConfig = toml::from_str("[service]\ntrusted_proxies = [\"proxy\"]\n").unwrap();
+        assert!(GraphBuilderSettings::validate_config(cfg).is_err());
+    }
+
+    #[test]
+    fn test_socket_addr_invalid() {
+        let invalid = ["::1:8080", "[::1]", "localhost:8080"];
+        for listen in &invalid {
+            let input = format!("[service]\nlisten = \"{}\"\n", listen);
+            assert!(toml::from_str::<FileConfig>(&input).is_err(), "{}", listen);
         }
     }
 }
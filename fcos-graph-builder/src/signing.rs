@@ -0,0 +1,87 @@
+//! Detached signatures for served graphs.
+//!
+//! Graphs are signed over their canonical serialization (compact JSON with
+//! sorted keys), so that clients can verify them independently of the
+//! format they were served in.
+
+use failure::{ensure, Fallible, ResultExt};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+use std::fmt;
+use std::path::Path;
+
+/// An Ed25519 private key for signing graphs.
+#[derive(Clone)]
+pub(crate) struct SigningKey {
+    key: PKey<Private>,
+    /// Raw public key, identifying this key.
+    public: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Parse a PEM-encoded (PKCS#8) Ed25519 private key.
+    pub(crate) fn from_pem(pem: &[u8]) -> Fallible<Self> {
+        let key = PKey::private_key_from_pem(pem).context("failed to parse private key")?;
+        ensure!(key.id() == Id::ED25519, "not an Ed25519 key");
+        let public = key.raw_public_key()?;
+        Ok(Self { key, public })
+    }
+
+    /// Read a PEM-encoded Ed25519 private key from a file.
+    pub(crate) fn read(path: &Path) -> Fallible<Self> {
+        let pem =
+            std::fs::read(path).with_context(|_| format!("failed to read '{}'", path.display()))?;
+        Self::from_pem(&pem)
+    }
+
+    /// Sign some data, returning the base64-encoded signature.
+    pub(crate) fn sign(&self, data: &[u8]) -> Fallible<String> {
+        let mut signer = Signer::new_without_digest(&self.key)?;
+        let signature = signer.sign_oneshot_to_vec(data)?;
+        Ok(base64::encode(signature))
+    }
+}
+
+impl PartialEq for SigningKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.public == other.public
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never log private key material.
+        f.debug_struct("SigningKey")
+            .field("public", &base64::encode(&self.public))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Public;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn test_sign() {
+        let generated = PKey::generate_ed25519().unwrap();
+        let pem = generated.private_key_to_pem_pkcs8().unwrap();
+        let key = SigningKey::from_pem(&pem).unwrap();
+        assert_eq!(key, key.clone());
+
+        let signature = base64::decode(key.sign(b"graph").unwrap()).unwrap();
+        let public: PKey<Public> =
+            PKey::public_key_from_raw_bytes(&key.public, Id::ED25519).unwrap();
+        let mut verifier = Verifier::new_without_digest(&public).unwrap();
+        assert!(verifier.verify_oneshot(&signature, b"graph").unwrap());
+        let mut verifier = Verifier::new_without_digest(&public).unwrap();
+        assert!(!verifier.verify_oneshot(&signature, b"other").unwrap());
+
+        let rsa = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let pem = rsa.private_key_to_pem_pkcs8().unwrap();
+        assert!(SigningKey::from_pem(&pem).is_err());
+        assert!(SigningKey::from_pem(b"garbage").is_err());
+        assert!(!format!("{:?}", key).contains("PRIVATE"));
+    }
+}
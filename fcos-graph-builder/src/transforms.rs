@@ -0,0 +1,122 @@
+//! Post-processing steps for assembled graphs.
+//!
+//! Transforms are applied in order to every assembled graph, before it is
+//! validated and cached.
+
+use commons::graph::Graph;
+use failure::{bail, ensure, Fallible};
+use std::collections::BTreeMap;
+
+/// A named transformation step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Transform {
+    /// Remove metadata keys from all nodes.
+    StripMetadata(Vec<String>),
+    /// Add metadata to all nodes, replacing existing values.
+    AddStaticMetadata(BTreeMap<String, String>),
+}
+
+impl Transform {
+    /// Build a transform from its name and parameters.
+    pub(crate) fn new(
+        name: &str,
+        keys: Option<Vec<String>>,
+        metadata: Option<BTreeMap<String, String>>,
+    ) -> Fallible<Self> {
+        let transform = match (name, keys, metadata) {
+            ("strip-metadata", Some(keys), None) => {
+                ensure!(!keys.is_empty(), "no keys to strip");
+                ensure!(keys.iter().all(|k| !k.is_empty()), "empty key");
+                Transform::StripMetadata(keys)
+            }
+            ("add-static-metadata", None, Some(metadata)) => {
+                ensure!(!metadata.is_empty(), "no metadata to add");
+                ensure!(metadata.keys().all(|k| !k.is_empty()), "empty key");
+                Transform::AddStaticMetadata(metadata)
+            }
+            ("strip-metadata", _, _) => bail!("'strip-metadata' takes only 'keys'"),
+            ("add-static-metadata", _, _) => bail!("'add-static-metadata' takes only 'metadata'"),
+            (other, _, _) => bail!("unknown transform '{}'", other),
+        };
+        Ok(transform)
+    }
+
+    /// Name of this transform, as used in configuration.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Transform::StripMetadata(_) => "strip-metadata",
+            Transform::AddStaticMetadata(_) => "add-static-metadata",
+        }
+    }
+
+    /// Apply this transform to a graph.
+    pub(crate) fn apply(&self, mut graph: Graph) -> Graph {
+        match self {
+            Transform::StripMetadata(keys) => {
+                for node in &mut graph.nodes {
+                    for key in keys {
+                        node.metadata.remove(key);
+                    }
+                }
+            }
+            Transform::AddStaticMetadata(metadata) => {
+                for node in &mut graph.nodes {
+                    for (key, value) in metadata {
+                        node.metadata.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        graph
+    }
+}
+
+/// Apply a pipeline of transforms to a graph, in order.
+pub(crate) fn apply_all(transforms: &[Transform], graph: Graph) -> Graph {
+    transforms.iter().fold(graph, |graph, transform| {
+        log::trace!("applying graph transform '{}'", transform.name());
+        transform.apply(graph)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commons::graph::CincinnatiPayload;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_pipeline() {
+        let mut metadata = HashMap::new();
+        metadata.insert("a".to_string(), "1".to_string());
+        metadata.insert("b".to_string(), "2".to_string());
+        let graph = Graph {
+            nodes: vec![CincinnatiPayload {
+                version: "1".to_string(),
+                metadata,
+                payload: "payload".to_string(),
+            }],
+            edges: vec![],
+        };
+
+        let add = maplit::btreemap! {
+            "b".to_string() => "3".to_string(),
+            "c".to_string() => "4".to_string(),
+        };
+        let transforms = vec![
+            Transform::new("strip-metadata", Some(vec!["a".to_string()]), None).unwrap(),
+            Transform::new("add-static-metadata", None, Some(add)).unwrap(),
+        ];
+        let graph = apply_all(&transforms, graph);
+        let mut metadata: Vec<_> = graph.nodes[0].metadata.iter().collect();
+        metadata.sort();
+        let b = ("b".to_string(), "3".to_string());
+        let c = ("c".to_string(), "4".to_string());
+        assert_eq!(metadata, vec![(&b.0, &b.1), (&c.0, &c.1)]);
+
+        assert!(Transform::new("relabel", None, None).is_err());
+        assert!(Transform::new("strip-metadata", None, None).is_err());
+        assert!(Transform::new("strip-metadata", Some(vec![]), None).is_err());
+        assert!(Transform::new("add-static-metadata", Some(vec![]), None).is_err());
+    }
+}
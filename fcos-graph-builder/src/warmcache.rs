@@ -0,0 +1,147 @@
+//! On-disk cache of the last graphs, to serve them right after a restart.
+
+use chrono::{DateTime, Utc};
+use commons::graph::{Graph, GraphScope};
+use failure::{Fallible, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Directory holding the last cached graph for each scope.
+#[derive(Clone, Debug)]
+pub(crate) struct WarmCache {
+    dir: PathBuf,
+}
+
+/// Content of a cache file.
+#[derive(Deserialize, Serialize)]
+struct CacheFile<G> {
+    /// When the graph was first cached, as an RFC 3339 time.
+    created: String,
+    graph: G,
+}
+
+impl WarmCache {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Path of the cache file for a scope.
+    fn path(&self, scope: &GraphScope) -> PathBuf {
+        let graph_type = if scope.oci { "oci" } else { "checksum" };
+        self.dir.join(format!(
+            "{}-{}-{}.json",
+            scope.stream, scope.basearch, graph_type
+        ))
+    }
+
+    /// Load the cached graph for a scope, if any, along with when it was
+    /// first cached.
+    ///
+    /// Corrupt or invalid cache files are discarded.
+    pub(crate) fn load(&self, scope: &GraphScope) -> Option<(Graph, DateTime<Utc>)> {
+        let path = self.path(scope);
+        if !path.exists() {
+            return None;
+        }
+        match Self::read_graph(&path) {
+            Ok(cached) => Some(cached),
+            Err(e) => {
+                log::warn!("discarding warm cache file '{}': {}", path.display(), e);
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::warn!("failed to remove '{}': {}", path.display(), e);
+                }
+                None
+            }
+        }
+    }
+
+    fn read_graph(path: &Path) -> Fallible<(Graph, DateTime<Utc>)> {
+        let content = std::fs::read(path)?;
+        let file: CacheFile<Graph> = serde_json::from_slice(&content)?;
+        let created = DateTime::parse_from_rfc3339(&file.created)?;
+        file.graph.check_edges()?;
+        file.graph.check_acyclic()?;
+        Ok((file.graph, created.with_timezone(&Utc)))
+    }
+
+    /// Store the graph for a scope, first cached at `created`, atomically
+    /// replacing any previous one.
+    pub(crate) fn store(
+        &self,
+        scope: &GraphScope,
+        graph: &Graph,
+        created: DateTime<Utc>,
+    ) -> Fallible<()> {
+        let path = self.path(scope);
+        let tmp = path.with_extension("json.tmp");
+        let file = CacheFile {
+            created: created.to_rfc3339(),
+            graph,
+        };
+        let data = serde_json::to_vec(&file)?;
+        std::fs::write(&tmp, data)
+            .with_context(|_| format!("failed to write '{}'", tmp.display()))?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|_| format!("failed to rename to '{}'", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commons::graph::CincinnatiPayload;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_warm_cache() {
+        use chrono::TimeZone;
+
+        let dir = std::env::temp_dir().join(format!("gb-warmcache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = WarmCache::new(&dir);
+        let scope = GraphScope {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            oci: false,
+        };
+        assert!(cache.load(&scope).is_none());
+
+        let node = |version: &str| CincinnatiPayload {
+            version: version.to_string(),
+            metadata: HashMap::new(),
+            payload: format!("payload-{}", version),
+        };
+        let graph = Graph {
+            nodes: vec![node("1"), node("2")],
+            edges: vec![(0, 1)],
+        };
+        let created = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        cache.store(&scope, &graph, created).unwrap();
+        let (loaded, loaded_created) = cache.load(&scope).unwrap();
+        assert_eq!(loaded.nodes.len(), 2);
+        assert_eq!(loaded.edges, vec![(0, 1)]);
+        assert_eq!(loaded_created, created);
+
+        // Corrupt files are discarded.
+        std::fs::write(cache.path(&scope), "{\"nodes\": [").unwrap();
+        assert!(cache.load(&scope).is_none());
+        assert!(!cache.path(&scope).exists());
+        let data = serde_json::to_vec(&CacheFile {
+            created: "yesterday".to_string(),
+            graph: &graph,
+        })
+        .unwrap();
+        std::fs::write(cache.path(&scope), data).unwrap();
+        assert!(cache.load(&scope).is_none());
+
+        let bogus = Graph {
+            nodes: vec![node("1")],
+            edges: vec![(0, 1)],
+        };
+        cache.store(&scope, &bogus, created).unwrap();
+        assert!(cache.load(&scope).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}